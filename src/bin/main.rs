@@ -1,45 +1,64 @@
-use std::{ffi::OsString, fs, path::Path, sync::Arc};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::Context as _;
-use clap::{arg, command, ArgAction, ArgMatches, Command};
+use clap::{arg, command, parser::ValueSource, ArgAction, ArgMatches, Command};
 use config::{Config, File, FileFormat};
 use http::Method;
+use inquire::MultiSelect;
 use kla::{
     clap::DefaultValueIfSome,
     config::{ConfigCommand, MergeChildren},
-    Endpoint, Environment, Expand, FromEnvironment, KlaClientBuilder, KlaRequestBuilder,
-    OutputBuilder, Sigv4Request, TemplateBuilder, URLBuilder, When, WithEnvironment,
+    to_curl, CookieJar, CurlRequest, DigestAuth, Endpoint, Environment, Expand, FromEnvironment,
+    Har, History, HistoryEntry, Import, KeyValue, KlaClientBuilder, KlaRequestBuilder,
+    MultipartField, OutputBuilder, RetryPolicy, Sigv4Request, State, TemplateBuilder, URLBuilder,
+    When, WithEnvironment, OAUTH2_STATE_KEY,
 };
-use log::error;
+use log::{error, warn};
 use regex::Regex;
 use reqwest::{ClientBuilder, Response};
 use skim::{prelude::SkimOptionsBuilder, Skim, SkimItem};
+use tera::Tera;
 use tokio::sync::OnceCell;
 
 static DEFAULT_ENV: OnceCell<OsString> = OnceCell::const_new();
 
-static ROOT_ABOUT: &'static str = include_str!("txt/root_about.txt");
-static RUN_ABOUT: &'static str = include_str!("txt/run_about.txt");
+static ROOT_ABOUT: &str = include_str!("txt/root_about.txt");
+static RUN_ABOUT: &str = include_str!("txt/run_about.txt");
 
 fn command() -> Command {
     command!()
         .arg_required_else_help(true)
         .long_about(ROOT_ABOUT)
         .subcommand_required(false)
-        .arg(arg!(--agent <AGENT> "The header agent string").default_value("kla"))
+        .arg(arg!(--agent <AGENT> "The header agent string, defaults to the environment's user_agent or kla"))
         .arg(arg!(-e --env <ENVIRONMENT> "The environment we will run the request against").required(false).default_value_if_some(DEFAULT_ENV.get().map(|v| v.as_os_str())))
         .arg(arg!(-t --template <TEMPLATE> "The template to use when formating the output. prepending with @ will read a file."))
         .arg(arg!(--"failure-template" <TEMPLATE> "The template to use when formating the failure output. prepending with @ will read a file."))
-        .arg(arg!(-o --output <FILE> "The file to write the output into"))
+        .arg(arg!(-o --output <FILE> "The file to write the output into, replacing it when it exists. The file is only readable by you"))
         .arg(arg!(--"output-failure" <FILE> "Where any failure will be written out to"))
         .arg(arg!(--timeout <SECONDS> "The amount of time allotted for the request to finish"))
-        .arg(arg!(--"basic-auth" <BASIC_AUTH> "The username and password seperated by :, a preceding @ denotes a file path."))
-        .arg(arg!(--"bearer-token" <BEARER_TOKEN> "The bearer token to use in requests. A preceding @ denotes a file path."))
+        .arg(arg!(--"basic-auth" <BASIC_AUTH> "The username and password seperated by :, a preceding @ denotes a file path, cmd: a command to run and - reads standard in."))
+        .arg(arg!(--"digest-auth" <DIGEST_AUTH> "The username and password seperated by :, used to answer a digest challenge. A preceding @ denotes a file path, cmd: a command to run and - reads standard in."))
+        .arg(arg!(--"bearer-token" <BEARER_TOKEN> "The bearer token to use in requests. A preceding @ denotes a file path, cmd: a command to run and - reads standard in."))
         .arg(arg!(-H --header <HEADER> "Specify a header The key and value should be seperated by a : (eg --header \"Content-Type: application/json\")").action(ArgAction::Append))
         .arg(arg!(-Q --query <QUERY> "Specify a query parameter The key and value should be seperated by a = (eg --query \"username=Jed\")").action(ArgAction::Append))
         .arg(arg!(-F --form <FORM> "Specify a form key=value to be passed in the form body").action(ArgAction::Append))
+        .arg(arg!(--multipart <FIELD> "Specify a multipart/form-data field as name=value, or upload a file with name=@path;type=mime;filename=name").action(ArgAction::Append).conflicts_with("form"))
+        .arg(arg!(--cookie <COOKIE> "Send a cookie name=value with the request, it isn't stored in the cookie jar").action(ArgAction::Append))
+        .arg(arg!(--"cookie-jar" <FILE> "Send the cookies in the Netscape cookie file, and store the cookies set by the response into it"))
         .arg(arg!(-v --verbose "make it loud and proud").action(ArgAction::SetTrue))
         .arg(arg!(--dry "don't actually do anything, will automatically enable verbose").action(ArgAction::SetTrue))
+        .arg(arg!(--"as-curl" "Print the request as a curl command instead of sending it").action(ArgAction::SetTrue))
+        .arg(arg!(--har <FILE> "Record the request and response into a HAR file, appending to the file when it already exists"))
+        .arg(arg!(--"show-secrets" "Show the value of authorization headers, cookies and other secrets in the verbose output instead of redacting them").action(ArgAction::SetTrue))
         .arg(arg!(--"http-version" <HTTP_VERSION> "The version of http to send the request as").value_parser(["0.9", "1.0", "1.1", "2.0", "3.0"]))
         .arg(arg!(--"no-gzip" "Do not automatically uncompress gzip responses").action(ArgAction::SetTrue))
         .arg(arg!(--"no-brotli" "Do not automatically uncompress brotli responses").action(ArgAction::SetTrue))
@@ -49,12 +68,22 @@ fn command() -> Command {
         .arg(arg!(--proxy <PROXY> "The proxy to use for all requests."))
         .arg(arg!(--"proxy-http" <PROXY_HTTP> "The proxy to use for http requests."))
         .arg(arg!(--"proxy-https" <PROXY_HTTPS> "The proxy to use for https requests."))
-        .arg(arg!(--"proxy-auth" <PROXY_AUTH> "The username and password seperated by :."))
+        .arg(arg!(--"proxy-auth" <PROXY_AUTH> "The username and password seperated by :, a preceding @ denotes a file path, cmd: a command to run and - reads standard in."))
         .arg(arg!(--"connect-timeout" <DURATION> "The amount of time to allow for connection"))
+        .arg(arg!(--retry <NUMBER> "The number of times a failed request is retried, with an exponential backoff between attempts").value_parser(clap::value_parser!(u32)))
+        .arg(arg!(--"retry-on" <STATUS_CODES> "The response status codes to retry, seperated by a , (defaults to 429,502,503,504)").value_delimiter(',').value_parser(clap::value_parser!(u16)))
+        .arg(arg!(--"retry-connection-errors" "Also retry requests that failed to connect or timed out").action(ArgAction::SetTrue))
+        .arg(arg!(--"retry-all-methods" "Retry requests with methods that aren't idempotent, like POST and PATCH").action(ArgAction::SetTrue))
+        .arg(arg!(--"retry-delay" <DURATION> "The delay before the first retry, doubled for each attempt (defaults to 1s)"))
         .arg(arg!(--"sigv4" "Sign the request with AWS v4 Signature").action(ArgAction::SetTrue))
         .arg(arg!(--"sigv4-aws-profile" <AWS_PROFILE> "The AWS profile to use when signing a request"))
         .arg(arg!(--"sigv4-service" <SERVICE> "The AWS Service to use when signing the request"))
-        .arg(arg!(--certificate <CERTIFICATE_FILE> "The path to the certificate to use for requests. Accepts PEM and DER, expects files to end in .der or .pem. defaults to pem").action(ArgAction::Append))
+        .arg(arg!(--certificate <CERTIFICATE_FILE> "The path to a root certificate to trust for requests. Accepts DER, PEM and PEM bundles with many certificates, or a directory of certificate files").action(ArgAction::Append))
+        .arg(arg!(--"no-system-roots" "Only trust the certificates given by --certificate or the environment, not the system root certificates").action(ArgAction::SetTrue))
+        .arg(arg!(--"client-cert" <CLIENT_CERT_FILE> "The PEM certificate (chain) to present to the server for mutual TLS. May also contain the private key"))
+        .arg(arg!(--"client-key" <CLIENT_KEY_FILE> "The PEM private key (PKCS#8, RSA or EC) for --client-cert"))
+        .arg(arg!(--"client-pkcs12" <PKCS12_FILE> "A PKCS#12 file holding the private key and certificate to present to the server for mutual TLS. You will be prompted for the password").conflicts_with("client-cert"))
+        .arg(arg!(--"client-pkcs12-password" <PASSWORD> "The password of the --client-pkcs12 file").env("KLA_CLIENT_PKCS12_PASSWORD").hide_env_values(true))
         .arg(arg!("method-or-url": [METHOD_OR_URL] "The URL path (with an assumed GET method) OR the method if another argument is supplied"))
         .arg(arg!(url: [URL] "The URL path when a method is supplied"))
         .arg(arg!(body: [BODY] "The body of the HTTP request, if prefixed with a `@` it is treated as a file path"))
@@ -70,39 +99,115 @@ fn command() -> Command {
             .alias("context")
             .arg(arg!(matcher: [Matcher] "A regex statement to filter down matches, (if we only match one value it's selected)").required(false).default_value(".*"))
         )
+        .subcommand(
+            Command::new("login")
+            .about("Log in to the environment with its oauth2 settings, the token is refreshed on later requests")
+        )
+        .subcommand(
+            Command::new("state")
+            .about("Manage the values stored for the environment, available to templates as {{ state.<key> }}")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("get")
+                .about("Print the value of a key")
+                .arg(arg!(key: <KEY> "The key to print"))
+            )
+            .subcommand(
+                Command::new("set")
+                .about("Store a value for a key")
+                .arg(arg!(key: <KEY> "The key to store"))
+                .arg(arg!(value: <VALUE> "The value to store"))
+            )
+            .subcommand(
+                Command::new("unset")
+                .about("Remove a key")
+                .arg(arg!(key: <KEY> "The key to remove"))
+            )
+            .subcommand(
+                Command::new("list")
+                .about("Print every key and value")
+                .alias("ls")
+            )
+        )
+        .subcommand(
+            Command::new("history")
+            .about("Show the requests that were sent, newest first. Selecting one sends it again")
+            .arg(arg!(--grep <REGEX> "Only show the requests matching the regex"))
+            .arg(arg!(--list "Print the requests instead of picking one").action(ArgAction::SetTrue))
+        )
+        .subcommand(
+            Command::new("replay")
+            .about("Send a request from the history again, with the same arguments")
+            .arg(arg!(id: <ID> "The id of the request, as shown by kla history").value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            Command::new("import")
+            .about("Create templates for the environment from other descriptions of requests")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("curl")
+                .about("Create a template from a curl command, like the ones copied from a browser's developer tools")
+                .arg(arg!(command: [COMMAND] "The curl command, read from standard input when it's missing or -").allow_hyphen_values(true))
+                .arg(arg!(--name <NAME> "The name of the template, defaults to the method and path"))
+                .arg(arg!(--force "Overwrite the template when it already exists").action(ArgAction::SetTrue))
+                .arg(arg!(--"keep-headers" "Keep the headers every browser sends, like User-Agent and Accept-Language").action(ArgAction::SetTrue))
+                .arg(arg!(--"no-prompt" "Don't offer to turn values of the request into arguments").action(ArgAction::SetTrue))
+            )
+            .subcommand(
+                Command::new("openapi")
+                .about("Create a template for every operation of an OpenAPI 3 spec")
+                .arg(arg!(spec: <SPEC> "The JSON or YAML spec, read from standard input when it's -"))
+                .arg(arg!(--force "Overwrite templates that already exist").action(ArgAction::SetTrue))
+            )
+        )
 }
 
-fn args_client(args: &ArgMatches) -> Result<ClientBuilder, anyhow::Error> {
+// args_client creates the client from the environment, and then applies the
+// arguments so any flags take precedence over the environment.
+async fn args_client(args: &ArgMatches, env: &Environment) -> Result<ClientBuilder, anyhow::Error> {
+    let has_proxy = ["proxy", "proxy-http", "proxy-https"]
+        .into_iter()
+        .any(|name| args.contains_id(name));
+
     let client_builder = ClientBuilder::new()
+        .user_agent("kla")
+        .with_environment(env)
+        .await
+        .with_context(|| {
+            format!(
+                "could not configure client for environment {:?}",
+                env.name()
+            )
+        })?
+        // proxies given as flags replace the proxies of the environment
+        .when(has_proxy, ClientBuilder::no_proxy)
         .opt_header_agent(args.get_one("agent"))
         .with_context(|| format!("could not add agent: {:?}", args.get_one::<String>("agent")))?
-        .gzip(
-            !args
-                .get_one::<bool>("no-gzip")
-                .map(|v| *v)
-                .unwrap_or_default(),
-        )
+        .gzip(!args.get_one::<bool>("no-gzip").copied().unwrap_or_default())
         .brotli(
             !args
                 .get_one::<bool>("no-brotli")
-                .map(|v| *v)
+                .copied()
                 .unwrap_or_default(),
         )
         .deflate(
             !args
                 .get_one::<bool>("no-deflate")
-                .map(|v| *v)
-                .unwrap_or_default(),
-        )
-        .connection_verbose(
-            args.get_one::<bool>("verbose")
-                .map(|v| *v)
+                .copied()
                 .unwrap_or_default(),
         )
+        .connection_verbose(args.get_one::<bool>("verbose").copied().unwrap_or_default())
+        .opt_connect_timeout(args.get_one("connect-timeout"))
+        .with_context(|| {
+            format!(
+                "{:?} is not a valid format",
+                args.get_one::<String>("connect-timeout")
+            )
+        })?
         .opt_max_redirects(args.get_one("max-redirects"))
         .no_redirects(
             args.get_one::<bool>("no-redirects")
-                .map(|v| *v)
+                .copied()
                 .unwrap_or_default(),
         )
         .opt_proxy(args.get_one("proxy"), args.get_one("proxy-auth"))
@@ -133,7 +238,30 @@ fn args_client(args: &ArgMatches) -> Result<ClientBuilder, anyhow::Error> {
             )
         })?
         .opt_certificate(args.get_many("certificate"))
-        .with_context(|| format!("could not add certificate"))?;
+        .with_context(|| "could not add certificate".to_string())?
+        .no_system_roots(
+            args.get_one::<bool>("no-system-roots")
+                .copied()
+                .unwrap_or_default(),
+        )
+        .opt_client_certificate(args.get_one("client-cert"), args.get_one("client-key"))
+        .with_context(|| {
+            format!(
+                "could not add client certificate: --client-cert={:?} --client-key={:?}",
+                args.get_one::<String>("client-cert"),
+                args.get_one::<String>("client-key"),
+            )
+        })?
+        .opt_client_pkcs12(
+            args.get_one("client-pkcs12"),
+            args.get_one("client-pkcs12-password"),
+        )
+        .with_context(|| {
+            format!(
+                "could not add client certificate: --client-pkcs12={:?}",
+                args.get_one::<String>("client-pkcs12"),
+            )
+        })?;
     Ok(client_builder)
 }
 
@@ -145,13 +273,20 @@ async fn main() {
             "{}",
             err.chain().fold(String::new(), |mut f, err| {
                 f.push_str(err.to_string().as_str());
-                f.push_str("\n");
+                f.push('\n');
                 f
             })
         ),
     }
 }
 
+// state_home returns the directory files written for the user are kept in. The
+// state holds tokens, so it isn't kept beside the default environment file, which
+// lives in /etc/kla by default and is shared by every user.
+fn state_home() -> String {
+    std::env::var("XDG_STATE_HOME").unwrap_or_else(|_| "~/.local/state".into())
+}
+
 async fn run() -> Result<(), anyhow::Error> {
     colog::init();
 
@@ -162,15 +297,19 @@ async fn run() -> Result<(), anyhow::Error> {
         "/etc/kla/config.toml".into(),
     ]
     .into_iter()
-    .filter(|f| Path::new(f).exists())
-    .next()
+    .find(|f| Path::new(f).exists())
     .ok_or(anyhow::Error::msg("No valid config file found"))?;
 
     let conf = Config::builder()
         .add_source(File::new(&config_file, FileFormat::Toml))
         .set_default("default.environment", "/etc/kla/.default-environment")?
+        .set_default("default.state", format!("{}/kla/state.json", state_home()))?
+        .set_default(
+            "default.history",
+            format!("{}/kla/history.jsonl", state_home()),
+        )?
         .build()
-        .with_context(|| format!("could not load configuration"))?
+        .with_context(|| "could not load configuration".to_string())?
         .merge_children("config")
         .context("could not load [[config]] files")?;
 
@@ -207,6 +346,11 @@ async fn run() -> Result<(), anyhow::Error> {
     match m.subcommand() {
         Some(("environments", envs)) => run_environments(envs, &conf),
         Some(("switch", envs)) => run_switch(envs, &conf),
+        Some(("state", state)) => run_state(state, &conf),
+        Some(("login", login)) => run_login(login, &conf).await,
+        Some(("import", import)) => run_import(import, &conf),
+        Some(("history", history)) => run_history(history, &conf),
+        Some(("replay", replay)) => run_replay(replay, &conf),
         Some(("run", envs)) => run_run(envs.get_one::<String>("template"), &m, &conf).await,
         _ => run_root(&m, &conf).await,
     }
@@ -252,25 +396,26 @@ async fn run_run<S: Into<String>>(
 
     // Run the command parsing for the template again, this will make actually
     // parse things with the configured arguments etc
-    let m = command()
-        .subcommand(
-            Command::new("run")
-                .about("run templates defined for the environment")
-                .long_about(RUN_ABOUT)
-                .alias("template")
-                .subcommand(Command::try_from(tmpl_config.clone())?),
-        )
-        .get_matches();
+    let m = template_command(tmpl_config.clone())?.get_matches();
 
+    let jar = load_cookie_jar(&m, &env)?;
     TemplateBuilder::new()
-        .client(args_client(&m)?.with_environment(&env).await?.build()?)
+        .client(
+            args_client(&m, &env)
+                .await?
+                .opt_cookie_jar(jar.clone())
+                .build()?,
+        )
         // TODO: This should be changed to try_config, and we shouldn't turn it into
         // a ConfigCommand here, all that should be done inside the builder
         // We will need to get the name in the config somehow
         .config(tmpl_config.clone())
+        .state(load_state(conf)?)
+        .history(load_history(args, conf)?)
         .build()?
         .run(
             &env,
+            &m,
             m.subcommand()
                 .expect("only run in run")
                 .1
@@ -279,9 +424,80 @@ async fn run_run<S: Into<String>>(
                 .1,
         )
         .await?;
+
+    if let Some(jar) = jar {
+        jar.save().context("could not save the cookie jar")?;
+    }
     Ok(())
 }
 
+/// template_command returns the command with the template as a subcommand of `run`.
+/// The flags of kla are given before `run` and the template only takes its own
+/// args, so they can share names.
+fn template_command(tmpl_config: ConfigCommand) -> Result<Command, anyhow::Error> {
+    Ok(command().subcommand(
+        Command::new("run")
+            .about("run templates defined for the environment")
+            .long_about(RUN_ABOUT)
+            .alias("template")
+            .subcommand(Command::try_from(tmpl_config)?),
+    ))
+}
+
+/// load_cookie_jar loads the jar given by --cookie-jar, or the jar of the environment
+/// when it keeps one, along with the --cookie values. Without any of them there is
+/// no jar.
+fn load_cookie_jar(
+    args: &ArgMatches,
+    env: &Environment,
+) -> Result<Option<Arc<CookieJar>>, anyhow::Error> {
+    let path = args
+        .get_one::<String>("cookie-jar")
+        .map(|path| PathBuf::from(path.shell_expansion()))
+        .or_else(|| env.cookie_jar());
+
+    let jar = match path {
+        Some(path) => CookieJar::load(&path)
+            .with_context(|| format!("could not load the cookie jar {}", path.display()))?,
+        None if args.contains_id("cookie") => CookieJar::default(),
+        None => return Ok(None),
+    };
+
+    let jar = jar
+        .one_off(args.get_many::<String>("cookie").into_iter().flatten())
+        .context("could not add --cookie")?;
+    Ok(Some(Arc::new(jar)))
+}
+
+/// load_state loads the state of the user
+fn load_state(conf: &Config) -> Result<State, anyhow::Error> {
+    let state_file = conf
+        .get_string("default.state")
+        .map(String::shell_expansion)
+        .expect("default value");
+
+    State::load(&state_file).with_context(|| format!("could not load the state {}", &state_file))
+}
+
+/// load_history loads the history executed requests are recorded into, along with
+/// the arguments kla was called with. The default environment is added to the
+/// arguments so replaying doesn't depend on the environment selected at the time.
+fn load_history(args: &ArgMatches, conf: &Config) -> Result<History, anyhow::Error> {
+    let mut argv: Vec<String> = vec![];
+    if let (Some(ValueSource::DefaultValue), Some(env)) =
+        (args.value_source("env"), args.get_one::<String>("env"))
+    {
+        argv.extend(["--env".into(), env.clone()]);
+    }
+    argv.extend(
+        std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().to_string()),
+    );
+
+    Ok(History::new(&conf.get_string("default.history")?).args(argv))
+}
+
 fn run_run_empty(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
     let env = Environment::new(args.get_one("env"), conf).with_context(|| {
         format!(
@@ -327,15 +543,12 @@ fn run_environments(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Erro
 
     let environments = conf
         .get_table("environment")
-        .with_context(|| format!("Could not load environments from config"))?
+        .with_context(|| "Could not load environments from config".to_string())?
         .into_iter()
         .filter_map(|(k, v)| if r.is_match(&k) { Some((k, v)) } else { None });
 
-    for (k, v) in environments {
-        let mut env: Endpoint = v
-            .try_deserialize()
-            .with_context(|| format!("invalid endpoint {}", k))?;
-        env.name = k;
+    for (k, _) in environments {
+        let env = Endpoint::new(&k, conf).with_context(|| format!("invalid endpoint {}", k))?;
         println!("{}", env);
     }
 
@@ -353,16 +566,14 @@ fn run_switch(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
 
     let environments = conf
         .get_table("environment")
-        .with_context(|| format!("Could not load environments from config"))?
+        .with_context(|| "Could not load environments from config".to_string())?
         .into_iter()
         .filter_map(|(k, v)| if r.is_match(&k) { Some((k, v)) } else { None });
 
     let mut num_entries = 0;
-    for (name, val) in environments {
-        let mut endpoint: Endpoint = val
-            .try_deserialize()
-            .with_context(|| format!("invalid endpoint {}", name))?;
-        endpoint.name = name;
+    for (name, _) in environments {
+        let endpoint =
+            Endpoint::new(&name, conf).with_context(|| format!("invalid endpoint {}", name))?;
         let endpoint: Arc<dyn SkimItem> = Arc::new(endpoint);
         send.send(endpoint).unwrap();
 
@@ -413,6 +624,291 @@ fn run_switch(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+// run_history shows the requests in the history, picking one replays it
+fn run_history(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
+    let history = History::new(&conf.get_string("default.history")?);
+    let r = args
+        .get_one::<String>("grep")
+        .map(|grep| Regex::new(grep).with_context(|| format!("invalid regex supplied {:?}", grep)))
+        .transpose()?;
+    // only filter by the environment when it was asked for, not when it's the default
+    let env = args
+        .get_one::<String>("env")
+        .filter(|_| args.value_source("env") != Some(ValueSource::DefaultValue));
+
+    let entries: Vec<HistoryEntry> = history
+        .entries()
+        .context("could not read the history")?
+        .into_iter()
+        .rev()
+        .filter(|entry| env.is_none() || entry.environment.as_ref() == env)
+        .filter(|entry| {
+            r.as_ref()
+                .is_none_or(|r| r.is_match(&entry.to_string()) || r.is_match(&entry.args.join(" ")))
+        })
+        .collect();
+
+    if args.get_flag("list") || !io::stdout().is_terminal() {
+        for entry in entries {
+            println!("{}", entry);
+        }
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        return Err(anyhow::Error::msg("no requests in the history match"));
+    }
+
+    let (send, recv) = crossbeam_channel::unbounded();
+    for entry in entries.iter().cloned() {
+        let entry: Arc<dyn SkimItem> = Arc::new(entry);
+        send.send(entry).unwrap();
+    }
+    drop(send);
+
+    let options = SkimOptionsBuilder::default()
+        .preview(Some(String::from("right")))
+        .build()?;
+
+    let selected = Skim::run_with(&options, Some(recv))
+        .filter(|f| !f.is_abort)
+        .map(|v| v.selected_items)
+        .into_iter()
+        .flatten()
+        .next()
+        .map(|v| v.text().to_string());
+
+    match selected.and_then(|text| entries.into_iter().find(|entry| entry.to_string() == text)) {
+        Some(entry) => replay(&entry),
+        None => Ok(()),
+    }
+}
+
+// run_replay sends a request from the history again
+fn run_replay(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
+    let id = *args.get_one::<u64>("id").expect("required");
+    let entry = History::new(&conf.get_string("default.history")?)
+        .get(id)
+        .context("could not read the history")?;
+
+    replay(&entry)
+}
+
+// replay runs kla again with the arguments of the entry
+fn replay(entry: &HistoryEntry) -> Result<(), anyhow::Error> {
+    let args = entry.replayable()?;
+    let status = process::Command::new(std::env::current_exe()?)
+        .args(args)
+        .status()
+        .with_context(|| format!("could not replay history entry {}", entry.id))?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow::Error::msg(format!(
+            "replaying history entry {} failed with {}",
+            entry.id, status
+        ))),
+    }
+}
+
+// run_login logs in to the environment
+async fn run_login(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
+    let env = Environment::new(args.get_one("env"), conf).with_context(|| {
+        format!(
+            "could not load environment: {:?}",
+            args.get_one::<String>("env")
+        )
+    })?;
+    let endpoint = match &env {
+        Environment::Endpoint(endpoint) => endpoint,
+        Environment::Empty => {
+            return Err(anyhow::Error::msg(
+                "log in needs an environment, select one with -e",
+            ))
+        }
+    };
+
+    let client = args_client(args, &env).await?.build()?;
+    endpoint
+        .login(&client)
+        .await
+        .with_context(|| format!("could not log in to {}", endpoint.name))?;
+    println!("Logged in to {}", endpoint.name);
+
+    Ok(())
+}
+
+// run_state manages the state of the environment
+fn run_state(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
+    let env = Environment::new(args.get_one("env"), conf).with_context(|| {
+        format!(
+            "could not load environment: {:?}",
+            args.get_one::<String>("env")
+        )
+    })?;
+    let name = env
+        .name()
+        .ok_or_else(|| anyhow::Error::msg("state is kept per environment, select one with -e"))?;
+
+    let mut state = load_state(conf)?;
+
+    // strings are printed as they are, so they are easy to use in scripts. The
+    // oauth2 tokens are never printed.
+    let text = |key: &str, value: &serde_json::Value| match value {
+        _ if key == OAUTH2_STATE_KEY => "[REDACTED]".to_string(),
+        serde_json::Value::String(v) => v.clone(),
+        v => v.to_string(),
+    };
+
+    match args.subcommand() {
+        Some(("get", args)) => {
+            let key = args.get_one::<String>("key").expect("required");
+            let value = state
+                .get(name, key)
+                .with_context(|| format!("{} is not set in {}", key, name))?;
+            println!("{}", text(key, value));
+        }
+        Some(("set", args)) => {
+            state.set(
+                name,
+                args.get_one::<String>("key").expect("required"),
+                args.get_one::<String>("value")
+                    .expect("required")
+                    .as_str()
+                    .into(),
+            );
+            state.save().context("could not save state")?;
+        }
+        Some(("unset", args)) => {
+            let key = args.get_one::<String>("key").expect("required");
+            if state.unset(name, key).is_some() {
+                state.save().context("could not save state")?;
+            }
+        }
+        Some(("list", _)) => {
+            for (key, value) in state.list(name) {
+                println!("{}={}", key, text(key, value));
+            }
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
+
+// run_import creates a template from another description of a request
+fn run_import(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
+    let env = Environment::new(args.get_one("env"), conf).with_context(|| {
+        format!(
+            "could not load environment: {:?}",
+            args.get_one::<String>("env")
+        )
+    })?;
+    let template_dir = env.template_dir().ok_or_else(|| {
+        anyhow::Error::msg(
+            "templates are written into the template_dir of the environment, select one with -e",
+        )
+    })?;
+
+    match args.subcommand() {
+        Some(("curl", args)) => import_curl(args, &env, template_dir),
+        Some(("openapi", args)) => import_openapi(args, template_dir),
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+// import_curl creates a template from a curl command
+fn import_curl(
+    args: &ArgMatches,
+    env: &Environment,
+    template_dir: &str,
+) -> Result<(), anyhow::Error> {
+    let command = match args.get_one::<String>("command").map(String::as_str) {
+        Some("-") | None => io::read_to_string(io::stdin())
+            .context("could not read the curl command from standard input")?,
+        Some(command) => command.to_string(),
+    };
+    let request = CurlRequest::parse(&command).context("could not parse the curl command")?;
+    let mut import = Import::from_curl(
+        &request,
+        env.prefix().map(String::as_str),
+        args.get_flag("keep-headers"),
+    )
+    .context("could not import the curl command")?;
+
+    // values are only offered as arguments when someone is there to answer
+    let candidates = import.candidates();
+    if !args.get_flag("no-prompt") && io::stdin().is_terminal() && !candidates.is_empty() {
+        let chosen = MultiSelect::new("Turn these values into arguments:", candidates)
+            .prompt()
+            .context("could not select the arguments")?;
+        import.apply(
+            &chosen
+                .into_iter()
+                .map(|c| c.placeholder)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    if let Some(name) = args.get_one::<String>("name") {
+        import.name = name.clone();
+    }
+
+    let path = import
+        .write(template_dir, args.get_flag("force"))
+        .context("could not write the template")?;
+    println!("Wrote template to {}", path.display());
+
+    Ok(())
+}
+
+// import_openapi creates a template for every operation of an OpenAPI spec
+fn import_openapi(args: &ArgMatches, template_dir: &str) -> Result<(), anyhow::Error> {
+    let spec = args.get_one::<String>("spec").expect("required");
+    let text = match spec.as_str() {
+        "-" => io::read_to_string(io::stdin())
+            .context("could not read the spec from standard input")?,
+        spec => fs::read_to_string(spec.shell_expansion())
+            .with_context(|| format!("could not read {}", spec))?,
+    };
+
+    // YAML is a superset of JSON, so both are read the same way
+    let spec: serde_json::Value = serde_json::to_value(
+        serde_yaml::from_str::<serde_yaml::Value>(&text).context("could not parse the spec")?,
+    )
+    .context("could not parse the spec")?;
+    let imports = Import::from_openapi(&spec).context("could not import the spec")?;
+
+    let mut written = 0;
+    for import in imports {
+        match import.write(template_dir, args.get_flag("force")) {
+            Ok(_) => written += 1,
+            Err(err) => warn!("skipped {}: {}", import.name, err),
+        }
+    }
+    println!("Wrote {} templates to {}", written, template_dir);
+
+    Ok(())
+}
+
+// render_url renders the url with the vars and state of the environment. Urls are
+// only rendered when they hold tags, urls which aren't templates, like a query with
+// a literal {%25}, are used as they are
+fn render_url(uri: &String, env: &Environment, conf: &Config) -> Result<String, anyhow::Error> {
+    let mut tmpl = Tera::default();
+    if !(uri.contains("{{") || uri.contains("{%")) || tmpl.add_raw_template("url", uri).is_err() {
+        return Ok(uri.clone());
+    }
+
+    let mut context = env.context();
+    if let Some(name) = env.name() {
+        context.extend(load_state(conf)?.context(name));
+    }
+
+    tmpl.render("url", &context)
+        .with_context(|| format!("could not render url: {}", uri))
+}
+
 // run_root will run the command with no arguments
 async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error> {
     let env = Environment::new(args.get_one("env"), conf).with_context(|| {
@@ -422,10 +918,7 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
         )
     })?;
 
-    let verbose = args
-        .get_one::<bool>("verbose")
-        .map(|v| *v)
-        .unwrap_or_default();
+    let verbose = args.get_one::<bool>("verbose").copied().unwrap_or_default();
 
     let (uri, method) = if let Some(uri) = args.get_one::<String>("url") {
         (
@@ -450,16 +943,27 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
         )
     };
 
-    let url = env.url_builder().build(uri)?;
-    let client = args_client(args)?.with_environment(&env).await?.build()?;
+    let uri = render_url(uri, &env, conf)?;
+    let url = env.url_builder().build(&uri)?;
+    let jar = load_cookie_jar(args, &env)?;
+    let client = args_client(args, &env)
+        .await?
+        .opt_cookie_jar(jar.clone())
+        .build()?;
 
     let request = client
         .request(method, url)
-        .with_environment(&env)
-        .await?
         .opt_body(args.get_one("body"))
         .with_context(|| format!("could not set body: {:?}", args.get_one::<String>("body")))?
-        .opt_headers(args.get_many("header"))
+        .opt_headers(Some(
+            args.get_many::<String>("header")
+                .into_iter()
+                .flatten()
+                .map(KeyValue::header)
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid --header")?
+                .into_iter(),
+        ))
         .with_context(|| {
             format!(
                 "could not set header: {:?}",
@@ -467,7 +971,9 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
             )
         })?
         .opt_bearer_auth(args.get_one("bearer-token"))
+        .context("could not read --bearer-token")?
         .opt_basic_auth(args.get_one("basic-auth"))
+        .context("could not read --basic-auth")?
         .opt_query(args.get_many("query"))
         .with_context(|| {
             format!(
@@ -482,6 +988,13 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
                 args.get_many::<String>("form")
             )
         })?
+        .opt_multipart(args.get_many("multipart"))
+        .with_context(|| {
+            format!(
+                "could not set multipart field: {:?}",
+                args.get_many::<String>("multipart")
+            )
+        })?
         .opt_timeout(args.get_one("timeout"))
         .with_context(|| {
             format!(
@@ -496,30 +1009,77 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
                 args.get_one::<String>("http-version")
             )
         })?
-        .build()
-        .context("Could not build http request")?
+        // the environment only adds what the flags and template didn't set
         .with_environment(&env)
-        .await?;
+        .await
+        .context("Could not build http request")?
+        .build()
+        .context("Could not build http request")?;
+    let dry = args.get_flag("dry");
+    let request = env.prepare((!dry).then_some(&client), request).await?;
 
-    let request = if args.get_one("sigv4").map(|v| *v).unwrap_or(false) {
+    let request = if args.get_one("sigv4").copied().unwrap_or(false) {
         request
             .sign_request(
                 args.get_one::<String>("sigv4-aws-profile"),
-                args.get_one::<String>("sigv4-aws-service"),
+                args.get_one::<String>("sigv4-service"),
             )
             .await?
     } else {
         request
     };
 
-    let output = OutputBuilder::new().when(verbose, |builder| builder.request_prelude(&request));
+    let multipart = MultipartField::parse(args.get_many::<String>("multipart"))
+        .context("could not read the multipart fields")?;
+    if args.get_flag("as-curl") {
+        println!("{}", to_curl(&request, args.get_one("body"), &multipart));
+        return Ok(());
+    }
+
+    let output = OutputBuilder::new()
+        .show_secrets(args.get_flag("show-secrets"))
+        .secret_headers(env.secret_headers())
+        .secret_query(env.secret_query())
+        .when(verbose, |builder| builder.request_prelude(&request));
 
-    let response = match args.get_one("dry").map(|b| *b).unwrap_or_default() {
+    let response = match dry {
         true => Response::from(http::Response::<Vec<u8>>::default()),
-        false => client
-            .execute(request)
-            .await
-            .with_context(|| format!("request failed!"))?,
+        false => {
+            let policy = RetryPolicy::default()
+                .with_args(args)
+                .context("invalid retry settings")?;
+            let har =
+                Har::opt_new(args.get_one("har")).map(|har| har.secrets(output.secrets().clone()));
+            let exchange = har
+                .as_ref()
+                .map(|har| har.start(&request, args.get_one("body"), &multipart));
+            let history = load_history(args, conf)?;
+            let entry = history
+                .entry(&request, output.secrets())
+                .environment(env.name());
+            let start = Instant::now();
+            let response =
+                match DigestAuth::opt_new(args.get_one("digest-auth").or(env.digest_auth()))
+                    .context("invalid digest auth")?
+                {
+                    Some(digest) => digest.execute(&policy, &client, request).await,
+                    None => policy.execute(&client, request).await,
+                }
+                .with_context(|| "request failed!".to_string())?;
+            if let Err(err) = history.record(entry.finish(response.status(), start.elapsed())) {
+                warn!("could not record the request in the history: {}", err);
+            }
+            if let Some(jar) = jar {
+                jar.save().context("could not save the cookie jar")?;
+            }
+            match exchange {
+                Some(exchange) => exchange
+                    .finish(response)
+                    .await
+                    .context("could not record the request in the HAR file")?,
+                None => response,
+            }
+        }
     };
 
     let succeed = response.status().is_success();
@@ -529,13 +1089,153 @@ async fn run_root(args: &ArgMatches, conf: &Config) -> Result<(), anyhow::Error>
         } else {
             args.get_one("failure-template")
         })
-        .with_context(|| format!("Your request was sent but the --template or --failure-template could not be parsed, run with -v to see if your request was successful"))?
+        .with_context(|| "Your request was sent but the --template or --failure-template could not be parsed, run with -v to see if your request was successful".to_string())?
         .when(verbose, |builder| builder.response_prelude(&response))
         .opt_output(args.get_one("output"))
         .await
-        .with_context(|| format!("could not set --output"))?
+        .with_context(|| "could not set --output".to_string())?
         .render(response)
-        .await.with_context(|| format!("could not write output to specified location!"))?;
+        .await.with_context(|| "could not write output to specified location!".to_string())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use kla::KLA_FLAGS;
+
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        Config::builder()
+            .set_default(
+                "default.state",
+                std::env::temp_dir()
+                    .join(format!("kla-main-{}.json", process::id()))
+                    .to_str()
+                    .unwrap(),
+            )
+            .unwrap()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_urls_with_vars() {
+        let conf = config(
+            "[environment.prod]\nurl = \"http://prod\"\n[environment.prod.vars]\ntenant = \"acme\"\n",
+        );
+        let env = Environment::new(Some(&"prod".into()), &conf).unwrap();
+
+        let render = |uri: &str| render_url(&uri.to_string(), &env, &conf).unwrap();
+        assert_eq!(render("/{{ tenant }}/_search"), "/acme/_search");
+        // urls without tags are left alone, even when they aren't valid templates
+        assert_eq!(render("/logs/_search?q=a%20b"), "/logs/_search?q=a%20b");
+        assert_eq!(render("/search?q={%25}"), "/search?q={%25}");
+        assert!(render_url(&"/{{ missing }}".to_string(), &env, &conf).is_err());
+    }
+
+    #[test]
+    fn templates_share_names_with_flags() {
+        let tmpl = Config::builder()
+            .add_source(File::from_str(
+                "uri = \"/search\"\n[[arg]]\nname = \"query\"\nlong = \"query\"\n[[arg]]\nname = \"timeout\"\nlong = \"timeout\"\n[[arg]]\nname = \"vertical\"\nshort = 'v'\ntype = \"bool\"\naction = \"set_true\"\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let tmpl_config = ConfigCommand::with_name("search", tmpl).unwrap();
+
+        let m = template_command(tmpl_config.clone())
+            .unwrap()
+            .try_get_matches_from([
+                "kla",
+                "--timeout",
+                "5s",
+                "-Q",
+                "page=2",
+                "run",
+                "search",
+                "--query",
+                "foo",
+                "--timeout",
+                "3",
+                "-v",
+            ])
+            .unwrap();
+        assert_eq!(m.get_one::<String>("timeout").unwrap(), "5s");
+        assert_eq!(
+            m.get_many::<String>("query").unwrap().collect::<Vec<_>>(),
+            ["page=2"]
+        );
+        assert!(!m.get_flag("verbose"));
+
+        let (_, run) = m.subcommand().unwrap();
+        let (_, args) = run.subcommand().unwrap();
+        let context = tmpl_config.args_context(args).unwrap();
+        assert_eq!(context.get("query").unwrap(), "foo");
+        assert_eq!(context.get("timeout").unwrap(), "3");
+        assert_eq!(context.get("vertical").unwrap(), true);
+    }
+
+    #[test]
+    fn imports_know_every_flag() {
+        for flag in command().get_arguments().filter_map(|arg| arg.get_long()) {
+            assert!(
+                KLA_FLAGS.contains(&flag),
+                "--{} is missing from KLA_FLAGS",
+                flag
+            );
+        }
+    }
+
+    /// proxy starts a proxy answering a single request, it returns the url of the
+    /// proxy and the head of the request it received
+    async fn proxy() -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let head = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = vec![];
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&head).contains("\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        (url, head)
+    }
+
+    #[tokio::test]
+    async fn flags_override_the_environment_client() {
+        let (flag_proxy, head) = proxy().await;
+        // nothing listens on the proxy of the environment
+        let conf = config(
+            "[environment.prod]\nurl = \"http://prod\"\nproxy = \"http://127.0.0.1:1\"\nuser_agent = \"from-env\"\n",
+        );
+        let env = Environment::new(Some(&"prod".into()), &conf).unwrap();
+        let args = command()
+            .try_get_matches_from(["kla", "--proxy", &flag_proxy, "--agent", "from-flag", "/"])
+            .unwrap();
+
+        let client = args_client(&args, &env).await.unwrap().build().unwrap();
+        let response = client.get("http://kla.invalid/").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let head = head.await.unwrap();
+        assert!(
+            head.starts_with("GET http://kla.invalid/ HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("user-agent: from-flag\r\n"), "{}", head);
+    }
+}
@@ -1,11 +1,11 @@
 use anyhow::Context as _;
-use clap::{command, Arg, ArgAction, ArgMatches, Command};
+use clap::{builder::PossibleValuesParser, command, Arg, ArgAction, ArgMatches, Command};
 use config::Config;
 use inquire::Password;
 use serde::{de::Visitor, Deserialize, Deserializer};
 use tera::{Context, Number, Tera};
 
-use crate::{Ok, Opt, RenderGroup};
+use crate::{Ok, Opt, PasswordArg, RenderGroup, RetryPolicy};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ConfigCommand {
@@ -33,15 +33,21 @@ pub struct ConfigCommand {
     pub(crate) query: Vec<ConfigKV>,
     #[serde(rename = "form", default)]
     pub(crate) form: Vec<ConfigKV>,
+    #[serde(rename = "multipart", default)]
+    pub(crate) multipart: Vec<ConfigKV>,
+    #[serde(rename = "retry", default)]
+    pub(crate) retry: RetryPolicy,
+    #[serde(rename = "extract", default)]
+    pub(crate) extract: Vec<ConfigExtract>,
 
     // these are utilized by OutputBuilder
-    #[serde(rename = "template", skip)]
+    #[serde(rename = "template")]
     pub template: Option<String>,
-    #[serde(rename = "template_failure", skip)]
+    #[serde(rename = "template_failure")]
     pub template_failure: Option<String>,
-    #[serde(rename = "output", skip)]
+    #[serde(rename = "output")]
     pub output: Option<String>,
-    #[serde(rename = "output_failure", skip)]
+    #[serde(rename = "output_failure", alias = "failure_output")]
     pub output_failure: Option<String>,
 }
 
@@ -64,6 +70,123 @@ pub struct ConfigKV {
     pub value: String,
     #[serde(rename = "when")]
     pub when: Option<String>,
+    /// secret redacts the value from the verbose output
+    #[serde(rename = "secret", default)]
+    pub secret: bool,
+}
+
+// ConfigExtract defines a value pulled out of a successful response, and where
+// the value should be sent
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigExtract {
+    #[serde(rename = "name")]
+    pub name: String,
+    /// value is a Tera expression rendered against the response context
+    #[serde(rename = "value")]
+    pub value: Option<String>,
+    /// pointer is a JSON pointer into the response body, e.g. `/data/0/id`
+    #[serde(rename = "pointer")]
+    pub pointer: Option<String>,
+    /// file writes the value to the file at this path
+    #[serde(rename = "file")]
+    pub file: Option<String>,
+    /// export prints the value to stdout as `export NAME=value`
+    #[serde(rename = "export", default)]
+    pub export: bool,
+    /// state stores the value in the state of the environment
+    #[serde(rename = "state", default)]
+    pub state: bool,
+}
+
+impl ConfigExtract {
+    /// validate returns an error when the extraction has nowhere to go, when it's
+    /// exported under a name which isn't a valid shell variable or when it doesn't
+    /// say how the value is found
+    pub fn validate(&self) -> crate::Result<()> {
+        match (self.value.as_ref(), self.pointer.as_ref()) {
+            (Some(value), None) => {
+                Tera::default()
+                    .add_raw_template(&self.name, value)
+                    .map_err(|err| {
+                        crate::Error::from(format!(
+                            "extract `{}` has an invalid value: {}",
+                            self.name, err
+                        ))
+                    })?;
+            }
+            (None, Some(pointer)) if !pointer.is_empty() && !pointer.starts_with('/') => {
+                return Err(crate::Error::from(format!(
+                    "extract `{}` has an invalid pointer {}, pointers start with /",
+                    self.name, pointer
+                )));
+            }
+            (None, Some(_)) => {}
+            _ => {
+                return Err(crate::Error::from(format!(
+                    "extract `{}` needs exactly one of `value` or `pointer`",
+                    self.name
+                )))
+            }
+        }
+
+        if self.file.is_none() && !self.export && !self.state {
+            return Err(crate::Error::from(format!(
+                "extract `{}` has nowhere to go, set `file`, `export` or `state`",
+                self.name
+            )));
+        }
+
+        let mut chars = self.name.chars();
+        let shell_name = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if self.export && !shell_name {
+            return Err(crate::Error::from(format!(
+                "extract `{}` can't be exported, names must be letters, digits and _",
+                self.name
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// extract returns the value out of the response context. JSON pointers are
+    /// resolved against `resp_body`, strings are returned without quotes and
+    /// everything else is returned as JSON.
+    pub fn extract(&self, ctx: &Context) -> crate::Result<serde_json::Value> {
+        match (self.value.as_ref(), self.pointer.as_ref()) {
+            (Some(value), None) => Ok(Tera::one_off(value, ctx, false)?.into()),
+            (None, Some(pointer)) => {
+                let body = ctx
+                    .get("resp_body")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default();
+                let body: serde_json::Value = serde_json::from_str(body)?;
+                body.pointer(pointer).cloned().ok_or_else(|| {
+                    crate::Error::from(format!(
+                        "extract `{}`: {} did not match anything in the response",
+                        self.name, pointer
+                    ))
+                })
+            }
+            _ => Err(crate::Error::from(format!(
+                "extract `{}` needs exactly one of `value` or `pointer`",
+                self.name
+            ))),
+        }
+    }
+}
+
+impl ConfigKV {
+    /// included returns true when the entry should be added, which is when it has
+    /// no `when` clause or the clause renders to a non empty string
+    pub fn included(&self, context: &Context) -> crate::Result<bool> {
+        match self.when.as_ref() {
+            Some(when) => Ok(!Tera::one_off(when, context, true)?.is_empty()),
+            None => Ok(true),
+        }
+    }
 }
 
 pub trait FilterWhen {
@@ -71,16 +194,18 @@ pub trait FilterWhen {
 }
 
 impl FilterWhen for Vec<ConfigKV> {
-    /// filter_when filters the when clause in the ConfigKV
+    /// filter_when returns true when the ConfigKV the group renders is included.
+    /// Entries are found by the position ending the template name, as names may
+    /// repeat, falling back to the first entry with the group's name.
     fn filter_when(&self, tmpl: &RenderGroup<'_>) -> crate::Result<bool> {
-        self.iter()
-            .filter(|v| v.name == tmpl.name)
+        tmpl.tmpl_name
+            .rsplit('.')
             .next()
-            .map(|v| v.when.as_ref())
-            .flatten()
-            .map(|v| Tera::one_off(v, tmpl.context, true).map(|v| v != ""))
+            .and_then(|i| i.parse::<usize>().ok())
+            .and_then(|i| self.get(i))
+            .or_else(|| self.iter().find(|v| v.name == tmpl.name))
+            .map(|v| v.included(tmpl.context))
             .unwrap_or(Ok(true))
-            .map_err(crate::Error::from)
     }
 }
 
@@ -98,27 +223,54 @@ impl ConfigCommand {
         let mut templates: Vec<(String, &'a String)> = vec![];
 
         if let Some(body) = self.body.as_ref() {
-            templates.push(("body".into(), &body));
+            templates.push(("body".into(), body));
         }
 
         templates.push(("uri".into(), &self.uri));
         templates.push(("method".into(), &self.method));
 
-        for header in &self.header {
-            templates.push((format!("header.{}", header.name), &header.value));
+        // entries are numbered, as names may repeat
+        for (i, header) in self.header.iter().enumerate() {
+            templates.push((format!("header.{}", i), &header.value));
         }
 
-        for query in &self.query {
-            templates.push((format!("query.{}", query.name), &query.value));
+        for (i, query) in self.query.iter().enumerate() {
+            templates.push((format!("query.{}", i), &query.value));
         }
 
-        for form in &self.form {
-            templates.push((format!("form.{}", form.name), &form.value));
+        for (i, form) in self.form.iter().enumerate() {
+            templates.push((format!("form.{}", i), &form.value));
+        }
+
+        for (i, multipart) in self.multipart.iter().enumerate() {
+            templates.push((format!("multipart.{}", i), &multipart.value));
         }
 
         Ok(templates)
     }
 
+    /// passwords returns the values given to the args marked as a password, along
+    /// with the flags they're given with
+    pub fn passwords(&self, args: &ArgMatches) -> Vec<PasswordArg> {
+        self.args
+            .iter()
+            .filter(|arg| arg.password)
+            .filter_map(|arg| {
+                let value = args.try_get_one::<String>(&arg.name).ok().flatten()?;
+                Some(PasswordArg {
+                    value: value.clone(),
+                    longs: arg.long.iter().chain(&arg.aliases).cloned().collect(),
+                    shorts: arg
+                        .short
+                        .iter()
+                        .chain(&arg.short_aliases)
+                        .copied()
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
     // args_context returns a Tera Context object from the arguments specifified
     pub fn args_context(&self, args: &ArgMatches) -> crate::Result<Context> {
         macro_rules! get_one {
@@ -224,7 +376,7 @@ impl TryFrom<ConfigCommand> for Command {
                 value
                     .args
                     .into_iter()
-                    .map(|v| ConfigArg::try_into(v))
+                    .map(ConfigArg::try_into)
                     .collect::<Result<Vec<Arg>, Self::Error>>(),
                 Command::args,
             )
@@ -234,9 +386,10 @@ impl TryFrom<ConfigCommand> for Command {
     }
 }
 
-#[derive(Deserialize, Copy, Clone, Debug)]
+#[derive(Deserialize, Copy, Clone, Debug, Default)]
 pub enum ConfigArgType {
     #[serde(rename = "string")]
+    #[default]
     String,
     #[serde(rename = "number")]
     Number,
@@ -244,12 +397,6 @@ pub enum ConfigArgType {
     Bool,
 }
 
-impl Default for ConfigArgType {
-    fn default() -> Self {
-        Self::String
-    }
-}
-
 #[derive(Deserialize, Clone, Debug)]
 pub struct ConfigArg {
     #[serde(rename = "name")]
@@ -302,6 +449,8 @@ pub struct ConfigArg {
     default_missing_value: Option<String>,
     #[serde(rename = "default_missing_values", default)]
     default_missing_values: Option<Vec<String>>,
+    #[serde(rename = "possible_values")]
+    possible_values: Option<Vec<String>>,
     #[serde(rename = "env")]
     env: Option<String>,
     #[serde(rename = "hide")]
@@ -416,6 +565,9 @@ impl TryFrom<ConfigArg> for Arg {
             .with_some(value.default_values, Arg::default_values)
             .with_some(value.default_missing_value, Arg::default_missing_value)
             .with_some(value.default_missing_values, Arg::default_missing_values)
+            .with_some(value.possible_values, |arg, values| {
+                arg.value_parser(PossibleValuesParser::new(values))
+            })
             .with_some(value.env, Arg::env)
             .with_some(value.hide, Arg::hide)
             .with_some(value.hide_possible_values, Arg::hide_possible_values)
@@ -431,3 +583,201 @@ impl TryFrom<ConfigArg> for Arg {
         Ok(arg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+
+    fn parse(toml: &str) -> ConfigCommand {
+        let conf = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .expect("valid toml");
+        ConfigCommand::with_name("test", conf).expect("valid template")
+    }
+
+    #[test]
+    fn deserializes_all_documented_keys() {
+        let cmd = parse(
+            r#"
+short_description = "short"
+description = "long"
+body = "{{ message }}"
+uri = "/admin/message"
+method = "POST"
+template = "success {{ resp_status }}"
+template_failure = "failure {{ resp_status }}"
+output = "~/.cache/my_token"
+output_failure = "-"
+
+[[arg]]
+  name = "message"
+  required = true
+
+[[header]]
+  name = "x-my-message"
+  value = "{{ message }}"
+  when = "{{ message }}"
+
+[[query]]
+  name = "message"
+  value = "{{ message }}"
+
+[[form]]
+  name = "message"
+  value = "{{ message }}"
+"#,
+        );
+
+        assert_eq!(cmd.name, "test");
+        assert_eq!(cmd.short_description.as_deref(), Some("short"));
+        assert_eq!(cmd.description.as_deref(), Some("long"));
+        assert_eq!(cmd.body.as_deref(), Some("{{ message }}"));
+        assert_eq!(cmd.uri, "/admin/message");
+        assert_eq!(cmd.method, "POST");
+        assert_eq!(cmd.args.len(), 1);
+        assert_eq!(cmd.header.len(), 1);
+        assert_eq!(cmd.header[0].when.as_deref(), Some("{{ message }}"));
+        assert_eq!(cmd.query.len(), 1);
+        assert_eq!(cmd.form.len(), 1);
+        assert_eq!(cmd.template.as_deref(), Some("success {{ resp_status }}"));
+        assert_eq!(
+            cmd.template_failure.as_deref(),
+            Some("failure {{ resp_status }}")
+        );
+        assert_eq!(cmd.output.as_deref(), Some("~/.cache/my_token"));
+        assert_eq!(cmd.output_failure.as_deref(), Some("-"));
+    }
+
+    #[test]
+    fn accepts_failure_output_alias() {
+        let cmd = parse(r#"failure_output = "/tmp/failure""#);
+        assert_eq!(cmd.output_failure.as_deref(), Some("/tmp/failure"));
+    }
+
+    #[test]
+    fn defaults_when_keys_are_missing() {
+        let cmd = parse("");
+        assert_eq!(cmd.uri, "/");
+        assert_eq!(cmd.method, "GET");
+        assert!(cmd.template.is_none());
+        assert!(cmd.output.is_none());
+    }
+
+    #[test]
+    fn extracts_values_from_the_response() {
+        let cmd = parse(
+            r#"
+[[extract]]
+  name = "token"
+  pointer = "/auth/token"
+  state = true
+
+[[extract]]
+  name = "greeting"
+  value = "hello {{ name }}"
+  export = true
+"#,
+        );
+
+        let mut ctx = Context::new();
+        ctx.insert("name", "terry");
+        ctx.insert("resp_body", r#"{"auth": {"token": "abc"}}"#);
+
+        assert_eq!(cmd.extract.len(), 2);
+        assert!(cmd.extract[0].state);
+        assert_eq!(cmd.extract[0].extract(&ctx).unwrap(), "abc");
+        assert_eq!(cmd.extract[1].extract(&ctx).unwrap(), "hello terry");
+    }
+
+    #[test]
+    fn extracts_are_validated() {
+        let cmd = parse(
+            r#"
+[[extract]]
+  name = "_access_token2"
+  value = "a"
+  export = true
+
+[[extract]]
+  name = "access-token"
+  value = "a"
+  file = "/tmp/token"
+
+[[extract]]
+  name = "access-token"
+  value = "a"
+  export = true
+
+[[extract]]
+  name = "2fa; rm -rf ~"
+  value = "a"
+  export = true
+
+[[extract]]
+  name = "token"
+  value = "a"
+
+[[extract]]
+  name = "token"
+  value = "a"
+  pointer = "/a"
+  state = true
+
+[[extract]]
+  name = "token"
+  state = true
+
+[[extract]]
+  name = "token"
+  pointer = "a/b"
+  state = true
+
+[[extract]]
+  name = "token"
+  value = "{{ resp_body"
+  state = true
+"#,
+        );
+
+        let valid: Vec<bool> = cmd.extract.iter().map(|e| e.validate().is_ok()).collect();
+        assert_eq!(
+            valid,
+            [true, true, false, false, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn filter_when_uses_the_entry_position() {
+        let cmd = parse(
+            r#"
+[[header]]
+  name = "x-id"
+  value = "a"
+  when = "{{ a }}"
+
+[[header]]
+  name = "x-id"
+  value = "b"
+  when = "{{ b }}"
+"#,
+        );
+        let tmpl = Tera::default();
+        let mut context = Context::new();
+        context.insert("a", "");
+        context.insert("b", "yes");
+
+        let group = |tmpl_name: &str, name: &str| RenderGroup {
+            name: name.into(),
+            tmpl_name: tmpl_name.into(),
+            tmpl: &tmpl,
+            context: &context,
+        };
+        assert!(!cmd.header.filter_when(&group("header.0", "x-id")).unwrap());
+        assert!(cmd.header.filter_when(&group("header.1", "x-id")).unwrap());
+        assert!(!cmd.header.filter_when(&group("header", "x-id")).unwrap());
+        assert!(cmd.header.filter_when(&group("header", "x-other")).unwrap());
+    }
+}
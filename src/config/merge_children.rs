@@ -49,9 +49,9 @@ impl MergeChildren for Config {
         for c in path {
             let c = c.into_table()?;
             if let (Some(_), Some(_)) = (c.get("path"), c.get("dir")) {
-                return Err(ConfigError::Message(format!(
-                    "config must have only `path` or `dir` property set! You set both"
-                )));
+                return Err(ConfigError::Message(
+                    "config must have only `path` or `dir` property set! You set both".to_string(),
+                ));
             } else if let Some(path) = c.get("path") {
                 // for some reason it takes ownership :(
                 let path = path
@@ -79,9 +79,9 @@ impl MergeChildren for Config {
                     builder = builder.add_source(File::new(path.as_ref(), FileFormat::Toml));
                 }
             } else {
-                return Err(ConfigError::Message(format!(
-                    "config must have a `path` or `dir` property set!"
-                )));
+                return Err(ConfigError::Message(
+                    "config must have a `path` or `dir` property set!".to_string(),
+                ));
             }
         }
 
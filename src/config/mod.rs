@@ -3,4 +3,6 @@ pub use merge_children::*;
 
 mod command;
 pub use command::ConfigCommand;
+pub use command::ConfigExtract;
+pub use command::ConfigKV;
 pub use command::FilterWhen;
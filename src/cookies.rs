@@ -0,0 +1,265 @@
+use std::{fs, io::ErrorKind, path::PathBuf, sync::RwLock};
+
+use cookie::{time::OffsetDateTime, Cookie as RawCookie};
+use cookie_store::{CookieDomain, CookieExpiration, CookieStore};
+use http::HeaderValue;
+use url::Url;
+
+use crate::{write_private, Error, Result};
+
+/// HTTP_ONLY prefixes the domain of HttpOnly cookies in a Netscape cookie file
+const HTTP_ONLY: &str = "#HttpOnly_";
+
+#[derive(Debug, Default)]
+/// CookieJar stores the cookies set by responses and sends them with later
+/// requests. Cookies are loaded from and saved to a Netscape cookie file, the
+/// format curl uses, so the same file works with both. A jar without a path only
+/// keeps cookies for the requests of a single invocation.
+pub struct CookieJar {
+    path: Option<PathBuf>,
+    store: RwLock<CookieStore>,
+    /// one_off are sent with every request but are never stored
+    one_off: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    /// load reads the cookies from the Netscape cookie file at path. A missing file
+    /// is an empty jar, the file will be created when the jar is saved.
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+
+        let mut store = CookieStore::default();
+        match fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(parse_line)
+                .for_each(|(cookie, url)| {
+                    // cookies the store refuses, such as expired ones, are left out
+                    let _ = store.insert_raw(&cookie, &url);
+                }),
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            store: RwLock::new(store),
+            one_off: vec![],
+        })
+    }
+
+    /// one_off adds cookies sent with every request without storing them. Each
+    /// value is a name=value, many can be given seperated by ;
+    pub fn one_off<'a, I: IntoIterator<Item = &'a String>>(mut self, cookies: I) -> Result<Self> {
+        for cookie in cookies.into_iter().flat_map(|c| c.split(';')) {
+            let (name, value) = cookie
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| Error::from(format!("{} is not a name=value cookie", cookie)))?;
+            self.one_off.push((name.trim().into(), value.trim().into()));
+        }
+        Ok(self)
+    }
+
+    /// save writes the stored cookies into the file the jar was loaded from,
+    /// including the session cookies. The file is only readable by the user. A jar
+    /// without a path isn't saved.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut text = String::from("# Netscape HTTP Cookie File\n# Written by kla\n\n");
+        let store = self.store.read().expect("not poisoned");
+        for cookie in store.iter_unexpired() {
+            let (domain, subdomains) = match &cookie.domain {
+                CookieDomain::HostOnly(domain) => (domain.clone(), false),
+                CookieDomain::Suffix(domain) => (format!(".{}", domain), true),
+                CookieDomain::NotPresent | CookieDomain::Empty => continue,
+            };
+            let expires = match &cookie.expires {
+                CookieExpiration::AtUtc(time) => time.unix_timestamp(),
+                CookieExpiration::SessionEnd => 0,
+            };
+
+            text.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only().unwrap_or_default() {
+                    HTTP_ONLY
+                } else {
+                    ""
+                },
+                domain,
+                flag(subdomains),
+                String::from(&cookie.path),
+                flag(cookie.secure().unwrap_or_default()),
+                expires,
+                cookie.name(),
+                cookie.value(),
+            ));
+        }
+
+        write_private(path, text)
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| RawCookie::parse(value.to_string()).ok());
+
+        self.store
+            .write()
+            .expect("not poisoned")
+            .store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let store = self.store.read().expect("not poisoned");
+
+        // one off cookies take the place of stored cookies with the same name
+        let cookies = self
+            .one_off
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(
+                store
+                    .get_request_values(url)
+                    .filter(|(name, _)| !self.one_off.iter().any(|(n, _)| n == name)),
+            )
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        match cookies.is_empty() {
+            true => None,
+            false => HeaderValue::from_str(&cookies).ok(),
+        }
+    }
+}
+
+/// parse_line reads a cookie from a line of a Netscape cookie file, along with
+/// the url it would have been set by. Comments and malformed lines are skipped.
+fn parse_line(line: &str) -> Option<(RawCookie<'static>, Url)> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+        return None;
+    };
+    let host = domain.trim_start_matches('.');
+    let secure = secure.eq_ignore_ascii_case("TRUE");
+
+    let mut cookie = RawCookie::new(name.to_string(), value.to_string());
+    cookie.set_path(path.to_string());
+    cookie.set_secure(secure);
+    cookie.set_http_only(http_only);
+    if subdomains.eq_ignore_ascii_case("TRUE") {
+        cookie.set_domain(host.to_string());
+    }
+    // an expiry of 0 is a session cookie
+    match expires.parse::<i64>().ok()? {
+        0 => (),
+        expires => cookie.set_expires(OffsetDateTime::from_unix_timestamp(expires).ok()?),
+    }
+
+    let scheme = if secure { "https" } else { "http" };
+    let url = Url::parse(&format!("{}://{}{}", scheme, host, path)).ok()?;
+    Some((cookie, url))
+}
+
+/// flag returns a boolean the way Netscape cookie files write it
+fn flag(v: bool) -> &'static str {
+    match v {
+        true => "TRUE",
+        false => "FALSE",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::cookie::CookieStore as _;
+
+    use super::*;
+
+    const COOKIES: &str = "# Netscape HTTP Cookie File
+#HttpOnly_x.io\tFALSE\t/\tFALSE\t0\tsession\ts1
+.x.io\tTRUE\t/api\tTRUE\t4102444800\ttheme\tdark
+x.io\tFALSE\t/\tFALSE\t946684800\texpired\tgone
+not a cookie
+";
+
+    fn cookies(jar: &CookieJar, url: &str) -> Option<String> {
+        jar.cookies(&Url::parse(url).unwrap())
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn load_and_save() {
+        let dir = std::env::temp_dir().join(format!("kla-cookies-{}", std::process::id()));
+        let path = dir.join("cookies.txt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, COOKIES).unwrap();
+
+        let jar = CookieJar::load(&path).unwrap();
+        assert_eq!(cookies(&jar, "http://x.io/").as_deref(), Some("session=s1"));
+        assert_eq!(cookies(&jar, "http://api.x.io/api"), None);
+        assert_eq!(
+            cookies(&jar, "https://api.x.io/api/users").as_deref(),
+            Some("theme=dark")
+        );
+        jar.save().unwrap();
+
+        let mut lines = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("# "))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "#HttpOnly_x.io\tFALSE\t/\tFALSE\t0\tsession\ts1",
+                ".x.io\tTRUE\t/api\tTRUE\t4102444800\ttheme\tdark",
+            ]
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn one_off_cookies_replace_stored() {
+        let dir = std::env::temp_dir().join(format!("kla-cookies-one-off-{}", std::process::id()));
+        let path = dir.join("cookies.txt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, COOKIES).unwrap();
+
+        let jar = CookieJar::load(&path)
+            .unwrap()
+            .one_off([&"session=s2; lang=en".to_string()])
+            .unwrap();
+        assert_eq!(
+            cookies(&jar, "http://x.io/").as_deref(),
+            Some("session=s2; lang=en")
+        );
+        assert!(CookieJar::default().one_off([&"nope".to_string()]).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,690 @@
+use base64::prelude::*;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Method, Version,
+};
+use log::warn;
+use reqwest::Request;
+
+use crate::{Error, Expand, MultipartField, Result};
+
+/// Bodies larger than this are piped into curl instead of being placed on the
+/// command line
+const INLINE_BODY_LIMIT: usize = 1024;
+
+/// The delimiter of the here document holding piped bodies
+const BODY_DELIMITER: &str = "KLA_BODY";
+
+/// to_curl returns a curl command line which sends the same request. Settings of
+/// the client, like proxies and certificates, are not part of the request and are
+/// left out. Large or binary bodies are base64 encoded and piped into curl. Bodies
+/// streamed from a file or standard in are read by curl the same way, source is
+/// the body argument the request was built with. Multipart bodies are sent with
+/// `-F`, multipart holds the fields the request was built with.
+pub fn to_curl(request: &Request, source: Option<&String>, multipart: &[MultipartField]) -> String {
+    let mut args = vec![String::from("curl")];
+
+    match *request.method() {
+        // curl sends a POST when given a body, unless told otherwise
+        Method::GET if request.body().is_some() || !multipart.is_empty() => {
+            args.push("-X GET".into())
+        }
+        Method::GET => (),
+        Method::HEAD => args.push("--head".into()),
+        ref method => args.push(format!("-X {}", shell_quote(method.as_str()))),
+    }
+
+    match request.version() {
+        Version::HTTP_10 => args.push("--http1.0".into()),
+        Version::HTTP_2 => args.push("--http2".into()),
+        Version::HTTP_3 => args.push("--http3".into()),
+        _ => (),
+    }
+
+    args.push(shell_quote(request.url().as_str()));
+
+    for (name, value) in request.headers() {
+        // curl picks its own boundary for multipart bodies
+        if !multipart.is_empty() && (name == CONTENT_TYPE || name == CONTENT_LENGTH) {
+            continue;
+        }
+        args.push(format!(
+            "-H {}",
+            shell_quote(&format!(
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            ))
+        ));
+    }
+
+    let body = request.body().map(|body| body.as_bytes());
+    let piped = match body {
+        Some(_) if !multipart.is_empty() => {
+            args.extend(multipart.iter().map(form_arg));
+            None
+        }
+        Some(Some(body)) => match std::str::from_utf8(body) {
+            Ok(text) if body.len() <= INLINE_BODY_LIMIT && !has_control(text) => {
+                args.push(format!("--data-binary {}", shell_quote(text)));
+                None
+            }
+            _ => {
+                args.push("--data-binary @-".into());
+                Some(body)
+            }
+        },
+        Some(None) => {
+            match source.and_then(|source| source.strip_prefix('@')) {
+                Some(path) => args.push(format!(
+                    "--data-binary @{}",
+                    shell_quote(&path.shell_expansion())
+                )),
+                None if source.is_some_and(|source| source == "-") => {
+                    args.push("--data-binary @-".into())
+                }
+                None => warn!("the body is streamed and is not part of the curl command"),
+            }
+            None
+        }
+        None => None,
+    };
+
+    let command = args.join(" \\\n  ");
+    match piped {
+        Some(body) => {
+            let encoded = BASE64_STANDARD.encode(body);
+            let mut lines = vec![format!("base64 -d <<'{}' | {}", BODY_DELIMITER, command)];
+            lines.extend(
+                encoded
+                    .as_bytes()
+                    .chunks(76)
+                    .map(|line| String::from_utf8_lossy(line).to_string()),
+            );
+            lines.push(BODY_DELIMITER.into());
+            lines.join("\n")
+        }
+        None => command,
+    }
+}
+
+/// form_arg returns the field as a curl flag. Text is sent with --form-string so
+/// curl doesn't treat a leading @ or < as a file, files as `-F name=@path` with
+/// their content type and file name.
+fn form_arg(field: &MultipartField) -> String {
+    match field.file() {
+        None => format!(
+            "--form-string {}",
+            shell_quote(&format!(
+                "{}={}",
+                field.name(),
+                field.text().unwrap_or_default()
+            ))
+        ),
+        Some((path, mime, filename)) => format!(
+            "-F {}",
+            shell_quote(&format!(
+                "{}=@{};type={};filename={}",
+                field.name(),
+                form_quote(&path),
+                mime,
+                form_quote(&filename)
+            ))
+        ),
+    }
+}
+
+/// form_quote double quotes values of -F which hold characters curl splits on
+fn form_quote(value: &str) -> String {
+    match value.contains([';', ',', '"', '\\']) {
+        true => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        false => value.to_string(),
+    }
+}
+
+/// has_control returns true when the text holds control characters that shouldn't
+/// be placed on a command line
+fn has_control(text: &str) -> bool {
+    text.chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+}
+
+/// shell_quote quotes the value so it can be safely evaluated by a posix shell.
+/// Values made of characters the shell doesn't treat specially are left as they are.
+pub(crate) fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:@%+,=".contains(c));
+
+    match plain {
+        true => value.to_string(),
+        false => format!("'{}'", value.replace('\'', "'\\''")),
+    }
+}
+
+/// Flags of curl which take a value, but don't change the request
+const IGNORED_VALUE_FLAGS: &[&str] = &[
+    "-o",
+    "--output",
+    "-m",
+    "--max-time",
+    "--connect-timeout",
+    "-x",
+    "--proxy",
+    "-U",
+    "--proxy-user",
+    "--cacert",
+    "--capath",
+    "-E",
+    "--cert",
+    "--key",
+    "--cert-type",
+    "--key-type",
+    "-w",
+    "--write-out",
+    "-c",
+    "--cookie-jar",
+    "--retry",
+    "--retry-delay",
+    "--retry-max-time",
+    "-r",
+    "--range",
+    "--resolve",
+    "--connect-to",
+    "--interface",
+    "-K",
+    "--config",
+    "--max-redirs",
+    "-D",
+    "--dump-header",
+    "--trace",
+    "--trace-ascii",
+];
+
+/// Short flags whose value may be attached, like -XPOST
+const TAKES_VALUE: &[&str] = &[
+    "-X", "-H", "-d", "-F", "-u", "-b", "-A", "-e", "-o", "-m", "-x",
+];
+
+#[derive(Clone, Debug, Default)]
+/// CurlRequest is the request described by a curl command line
+pub struct CurlRequest {
+    /// method is the method given with -X, or derived from the other flags
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// data holds the -d, --data* values, joined with & like curl does
+    pub data: Option<String>,
+    /// multipart holds the -F name=value fields
+    pub multipart: Vec<(String, String)>,
+    /// user is the -u user:password
+    pub user: Option<String>,
+}
+
+impl CurlRequest {
+    /// parse parses a curl command line, as copied from a terminal or a browser's
+    /// "Copy as cURL". Flags which don't change the request are ignored.
+    pub fn parse(command: &str) -> Result<Self> {
+        let words = shell_words(command)?;
+        let mut words = words.into_iter();
+
+        match words.next().as_deref() {
+            Some("curl") => (),
+            _ => return Err(Error::from("the command must start with curl")),
+        }
+
+        let mut request = CurlRequest::default();
+        let mut method = None;
+        let mut data: Vec<String> = vec![];
+        let mut get = false;
+        let mut head = false;
+
+        while let Some(word) = words.next() {
+            if !word.starts_with('-') || word == "-" {
+                request.url = word;
+                continue;
+            }
+
+            // long flags may be given as --flag=value
+            let (flag, inline) = match word.split_once('=') {
+                Some((flag, value)) if word.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                // short flags may have the value attached, -XPOST
+                _ if !word.starts_with("--") && word.len() > 2 => {
+                    let (flag, value) = word.split_at(2);
+                    match TAKES_VALUE.contains(&flag) {
+                        true => (flag.to_string(), Some(value.to_string())),
+                        false => (word.clone(), None),
+                    }
+                }
+                _ => (word.clone(), None),
+            };
+
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| words.next())
+                    .ok_or_else(|| Error::from(format!("{} needs a value", flag)))
+            };
+
+            match flag.as_str() {
+                "-X" | "--request" => method = Some(value()?.to_uppercase()),
+                "-H" | "--header" => {
+                    let header = value()?;
+                    if let Some((name, value)) = header.split_once(':') {
+                        request
+                            .headers
+                            .push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+                "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-ascii"
+                | "--data-urlencode" | "--json" => {
+                    let mut value = value()?;
+                    if flag == "--data-urlencode" {
+                        value = urlencode_data(&value);
+                    }
+                    if flag == "--json" {
+                        request
+                            .headers
+                            .push(("Content-Type".into(), "application/json".into()));
+                        request
+                            .headers
+                            .push(("Accept".into(), "application/json".into()));
+                    }
+                    data.push(value);
+                }
+                "-F" | "--form" | "--form-string" => {
+                    if let Some((name, value)) = value()?.split_once('=') {
+                        request.multipart.push((name.into(), value.into()));
+                    }
+                }
+                "-u" | "--user" => request.user = Some(value()?),
+                "-b" | "--cookie" => request.headers.push(("Cookie".into(), value()?)),
+                "-A" | "--user-agent" => request.headers.push(("User-Agent".into(), value()?)),
+                "-e" | "--referer" => request.headers.push(("Referer".into(), value()?)),
+                "--url" => request.url = value()?,
+                "-G" | "--get" => get = true,
+                "-I" | "--head" => head = true,
+                flag if IGNORED_VALUE_FLAGS.contains(&flag) => {
+                    value()?;
+                }
+                // everything else is a switch, like --compressed or -sSL
+                _ => (),
+            }
+        }
+
+        if request.url.is_empty() {
+            return Err(Error::from("the curl command has no url"));
+        }
+
+        let data = match data.is_empty() {
+            true => None,
+            false => Some(data.join("&")),
+        };
+
+        // -G sends the data as the query string
+        if get {
+            if let Some(data) = data {
+                let separator = if request.url.contains('?') { '&' } else { '?' };
+                request.url = format!("{}{}{}", request.url, separator, data);
+            }
+        } else {
+            request.data = data;
+        }
+
+        request.method = match (method, head) {
+            (Some(method), _) => method,
+            (None, true) => "HEAD".into(),
+            (None, false) if request.data.is_some() || !request.multipart.is_empty() => {
+                "POST".into()
+            }
+            (None, false) => "GET".into(),
+        };
+
+        Ok(request)
+    }
+}
+
+/// urlencode_data encodes a --data-urlencode value the way curl does, `content`
+/// and `=content` are encoded as a whole, `name=content` only encodes the content.
+/// Values read from files are left as they are.
+fn urlencode_data(value: &str) -> String {
+    let encode = |v: &str| url::form_urlencoded::byte_serialize(v.as_bytes()).collect::<String>();
+    match value.split_once('=') {
+        Some(("", content)) => encode(content),
+        Some((name, content)) => format!("{}={}", name, encode(content)),
+        None if value.contains('@') => value.to_string(),
+        None => encode(value),
+    }
+}
+
+/// shell_words splits the command line into words the way a posix shell would,
+/// handling single quotes, double quotes, $'' quotes, escapes and line
+/// continuations.
+fn shell_words(command: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // line continuation
+                Some('\n') => (),
+                Some('\r') if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                }
+                Some(c) => {
+                    word.push(c);
+                    in_word = true;
+                }
+                None => (),
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::from("unterminated ' in the command")),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('t') => word.push('\t'),
+                            Some('r') => word.push('\r'),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16).map_err(|_| {
+                                    Error::from(format!("invalid escape \\x{}", hex))
+                                })?;
+                                word.push(char::from(byte));
+                            }
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or_else(|| {
+                                        Error::from(format!("invalid escape \\u{}", hex))
+                                    })?;
+                                word.push(c);
+                            }
+                            Some(c) => word.push(c),
+                            None => return Err(Error::from("unterminated $' in the command")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::from("unterminated $' in the command")),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => (),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(Error::from("unterminated \" in the command")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::from("unterminated \" in the command")),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &str) -> Vec<String> {
+        shell_words(command).unwrap()
+    }
+
+    #[test]
+    fn shell_words_quotes() {
+        assert_eq!(
+            words(r#"curl 'a b' "c \"d\" \$e \n" g\ h '' x"#),
+            ["curl", "a b", r#"c "d" $e \n"#, "g h", "", "x"]
+        );
+    }
+
+    #[test]
+    fn shell_words_ansi_c_quotes() {
+        assert_eq!(words(r#"curl $'a\nb\tc\'d\x41é'"#), ["curl", "a\nb\tc'dAé"]);
+    }
+
+    #[test]
+    fn shell_words_continuations() {
+        assert_eq!(
+            words("curl \\\n  -H 'A: b' \\\r\n  https://x.io \"a\\\nb\""),
+            ["curl", "-H", "A: b", "https://x.io", "ab"]
+        );
+    }
+
+    #[test]
+    fn shell_words_unterminated() {
+        assert!(shell_words("curl 'a").is_err());
+        assert!(shell_words("curl \"a").is_err());
+        assert!(shell_words("curl $'a").is_err());
+        assert!(shell_words(r"curl $'\xZZ'").is_err());
+    }
+
+    fn request(body: Option<&str>) -> Request {
+        let client = reqwest::Client::new();
+        let request = client.post("https://x.io/a?b=c d");
+        match body {
+            Some(body) => request.body(body.to_string()),
+            None => request,
+        }
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn shell_quote_values() {
+        assert_eq!(
+            shell_quote("https://x.io/a-b_c.d/e=f%20g+h,@i"),
+            "https://x.io/a-b_c.d/e=f%20g+h,@i"
+        );
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME`x`"), "'$HOME`x`'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(
+            words(&format!("echo {}", shell_quote("it's a \"$b\""))),
+            ["echo", "it's a \"$b\""]
+        );
+    }
+
+    #[test]
+    fn to_curl_inline_body() {
+        assert_eq!(
+            to_curl(&request(Some("{\"a\": 1}")), None, &[]),
+            "curl \\\n  -X POST \\\n  'https://x.io/a?b=c%20d' \\\n  --data-binary '{\"a\": 1}'"
+        );
+
+        let get = reqwest::Client::new()
+            .get("https://x.io/_search")
+            .body("{\"query\": {}}")
+            .build()
+            .unwrap();
+        assert_eq!(
+            to_curl(&get, None, &[]),
+            "curl \\\n  -X GET \\\n  https://x.io/_search \\\n  --data-binary '{\"query\": {}}'"
+        );
+    }
+
+    #[test]
+    fn to_curl_piped_body() {
+        for body in ["a\u{1}b".to_string(), "x".repeat(INLINE_BODY_LIMIT + 1)] {
+            let command = to_curl(&request(Some(&body)), None, &[]);
+            let mut lines = command.lines();
+
+            assert_eq!(lines.next(), Some("base64 -d <<'KLA_BODY' | curl \\"));
+            assert!(command.contains("  --data-binary @-\n"));
+            assert_eq!(command.lines().last(), Some(BODY_DELIMITER));
+
+            let encoded = command
+                .lines()
+                .skip_while(|line| !line.starts_with("  --data-binary"))
+                .skip(1)
+                .take_while(|line| *line != BODY_DELIMITER)
+                .inspect(|line| assert!(line.len() <= 76))
+                .collect::<String>();
+            assert_eq!(BASE64_STANDARD.decode(encoded).unwrap(), body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn to_curl_streamed_body() {
+        let streamed = || {
+            let stream = tokio_util::io::ReaderStream::new(tokio::io::empty());
+            reqwest::Client::new()
+                .put("https://x.io")
+                .body(reqwest::Body::wrap_stream(stream))
+                .build()
+                .unwrap()
+        };
+
+        assert!(to_curl(&streamed(), Some(&"@my file.bin".into()), &[])
+            .ends_with("\n  --data-binary @'my file.bin'"));
+        assert!(to_curl(&streamed(), Some(&"-".into()), &[]).ends_with("\n  --data-binary @-"));
+        assert!(!to_curl(&streamed(), None, &[]).contains("--data-binary"));
+        assert!(!to_curl(&request(None), None, &[]).contains("--data-binary"));
+    }
+
+    #[test]
+    fn to_curl_multipart_body() {
+        use crate::KlaRequestBuilder;
+
+        let path = std::env::temp_dir().join(format!("kla-curl-{}.csv", std::process::id()));
+        std::fs::write(&path, "a,b\n").unwrap();
+        let fields = [
+            "greeting=<hello>".to_string(),
+            format!("report=@{};filename=q1,2.csv", path.display()),
+        ];
+        let request = reqwest::Client::new()
+            .post("https://x.io")
+            .opt_multipart(Some(fields.iter()))
+            .unwrap()
+            .build()
+            .unwrap();
+        let multipart = MultipartField::parse(Some(fields.iter())).unwrap();
+        let command = to_curl(&request, None, &multipart);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            command,
+            format!(
+                "curl \\\n  -X POST \\\n  https://x.io/ \\\n  --form-string 'greeting=<hello>' \\\n  -F 'report=@{};type=text/csv;filename=\"q1,2.csv\"'",
+                path.display()
+            )
+        );
+        assert_eq!(
+            words(&command)[4..],
+            [
+                "--form-string",
+                "greeting=<hello>",
+                "-F",
+                &format!(
+                    "report=@{};type=text/csv;filename=\"q1,2.csv\"",
+                    path.display()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_method() {
+        let method = |command: &str| CurlRequest::parse(command).unwrap().method;
+        assert_eq!(method("curl https://x.io"), "GET");
+        assert_eq!(method("curl -d a=1 https://x.io"), "POST");
+        assert_eq!(method("curl -F a=1 https://x.io"), "POST");
+        assert_eq!(method("curl -I https://x.io"), "HEAD");
+        assert_eq!(method("curl -XPUT -d a=1 https://x.io"), "PUT");
+        assert_eq!(method("curl --request=delete https://x.io"), "DELETE");
+    }
+
+    #[test]
+    fn parse_request() {
+        let request = CurlRequest::parse(
+            r#"curl 'https://x.io/users' -H 'Accept: application/json' --header='X-Id:  7 ' \
+              -u me:pw -b 'a=b' -A kla -o out.json --compressed -sSL \
+              -d 'name=kla' --data-urlencode 'bio=a b&c' --data-raw '@x'"#,
+        )
+        .unwrap();
+
+        assert_eq!(request.url, "https://x.io/users");
+        assert_eq!(
+            request.headers,
+            [
+                ("Accept".to_string(), "application/json".to_string()),
+                ("X-Id".into(), "7".into()),
+                ("Cookie".into(), "a=b".into()),
+                ("User-Agent".into(), "kla".into()),
+            ]
+        );
+        assert_eq!(request.user.as_deref(), Some("me:pw"));
+        assert_eq!(request.data.as_deref(), Some("name=kla&bio=a+b%26c&@x"));
+    }
+
+    #[test]
+    fn parse_get_moves_data_to_query() {
+        let request = CurlRequest::parse("curl -G https://x.io/?a=1 -d b=2 -d c=3").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.url, "https://x.io/?a=1&b=2&c=3");
+        assert_eq!(request.data, None);
+    }
+
+    #[test]
+    fn parse_json() {
+        let request = CurlRequest::parse(r#"curl --json '{"a":1}' --url https://x.io"#).unwrap();
+        assert_eq!(request.url, "https://x.io");
+        assert_eq!(request.data.as_deref(), Some(r#"{"a":1}"#));
+        assert!(request
+            .headers
+            .contains(&("Content-Type".into(), "application/json".into())));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(CurlRequest::parse("wget https://x.io").is_err());
+        assert!(CurlRequest::parse("curl -H 'A: b'").is_err());
+        assert!(CurlRequest::parse("curl https://x.io -X").is_err());
+    }
+}
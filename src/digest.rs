@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    HeaderValue, StatusCode,
+};
+use log::debug;
+use md5::Md5;
+use reqwest::{Client, Request, Response};
+use sha2::{Digest as _, Sha256};
+
+use crate::{read_secret, sigv4::buffer_body, Error, Result, RetryPolicy};
+
+#[derive(Clone, Debug)]
+/// DigestAuth answers HTTP Digest challenges (RFC 7616). The request is sent
+/// without credentials first, and replayed with an Authorization header computed
+/// from the `WWW-Authenticate` challenge when the server responds with a 401.
+pub struct DigestAuth {
+    username: String,
+    password: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Algorithm is the hash algorithm of a challenge, `sess` variants hash the
+/// nonces into the credentials as well.
+enum Algorithm {
+    Md5 { sess: bool },
+    Sha256 { sess: bool },
+}
+
+impl Algorithm {
+    fn parse(value: Option<&String>) -> Option<Self> {
+        match value.map(|v| v.to_uppercase()).as_deref() {
+            None | Some("MD5") => Some(Self::Md5 { sess: false }),
+            Some("MD5-SESS") => Some(Self::Md5 { sess: true }),
+            Some("SHA-256") => Some(Self::Sha256 { sess: false }),
+            Some("SHA-256-SESS") => Some(Self::Sha256 { sess: true }),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 { sess: false } => "MD5",
+            Self::Md5 { sess: true } => "MD5-sess",
+            Self::Sha256 { sess: false } => "SHA-256",
+            Self::Sha256 { sess: true } => "SHA-256-sess",
+        }
+    }
+
+    fn sess(&self) -> bool {
+        matches!(self, Self::Md5 { sess: true } | Self::Sha256 { sess: true })
+    }
+
+    /// hash returns the lowercase hex hash of the data
+    fn hash<D: AsRef<[u8]>>(&self, data: D) -> String {
+        match self {
+            Self::Md5 { .. } => hex(&Md5::digest(data)),
+            Self::Sha256 { .. } => hex(&Sha256::digest(data)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Challenge is a parsed `WWW-Authenticate: Digest ...` header
+struct Challenge {
+    algorithm: Algorithm,
+    params: HashMap<String, String>,
+}
+
+impl Challenge {
+    /// parse parses a single Digest challenge, returning None for other schemes
+    /// and algorithms we don't support
+    fn parse(value: &str) -> Option<Self> {
+        let (scheme, rest) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+
+        let params = parse_params(rest);
+        Some(Self {
+            algorithm: Algorithm::parse(params.get("algorithm"))?,
+            params,
+        })
+    }
+
+    fn param(&self, name: &str) -> &str {
+        self.params
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// qop returns the quality of protection to use. `auth` is preferred, `auth-int`
+    /// is used when it's the only option and the body is known.
+    fn qop(&self, body: Option<&[u8]>) -> Option<&'static str> {
+        let offered: Vec<&str> = self.params.get("qop")?.split(',').map(str::trim).collect();
+        match (
+            offered.contains(&"auth"),
+            offered.contains(&"auth-int"),
+            body,
+        ) {
+            (true, _, _) => Some("auth"),
+            (false, true, Some(_)) => Some("auth-int"),
+            _ => None,
+        }
+    }
+}
+
+impl DigestAuth {
+    /// new creates the credentials from the username and password separated by `:`,
+    /// a preceding `@` denotes a file path.
+    pub fn new(userpass: &str) -> Result<Self> {
+        let userpass = read_secret(userpass)?;
+        let (username, password) = userpass.split_once(':').ok_or_else(|| {
+            Error::from("digest auth needs a username and password separated by :")
+        })?;
+
+        Ok(Self {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    /// opt_new creates the credentials when userpass is supplied
+    pub fn opt_new(userpass: Option<&String>) -> Result<Option<Self>> {
+        userpass.map(|userpass| Self::new(userpass)).transpose()
+    }
+
+    /// execute sends the request with the retry policy. When the server challenges
+    /// the request, it's sent again with the digest Authorization header. Streamed
+    /// bodies are read into memory first, so they can be sent twice.
+    pub async fn execute(
+        &self,
+        policy: &RetryPolicy,
+        client: &Client,
+        request: Request,
+    ) -> Result<Response> {
+        let request = buffer_body(request).await?;
+        let replay = request
+            .try_clone()
+            .expect("requests with a buffered body can be cloned");
+
+        let response = policy.execute(client, request).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        // prefer the strongest algorithm the server offers
+        let challenge = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(Challenge::parse)
+            .max_by_key(|c| matches!(c.algorithm, Algorithm::Sha256 { .. }));
+
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => {
+                debug!("401 without a supported digest challenge");
+                return Ok(response);
+            }
+        };
+
+        let mut replay = replay;
+        let cnonce = format!("{:016x}", fastrand::u64(..));
+        let authorization = self.authorization(&challenge, &replay, &cnonce)?;
+        let mut value = HeaderValue::try_from(authorization)?;
+        value.set_sensitive(true);
+        replay.headers_mut().insert(AUTHORIZATION, value);
+
+        policy.execute(client, replay).await
+    }
+
+    /// authorization computes the Authorization header answering the challenge
+    fn authorization(
+        &self,
+        challenge: &Challenge,
+        request: &Request,
+        cnonce: &str,
+    ) -> Result<String> {
+        let algorithm = challenge.algorithm;
+        let realm = challenge.param("realm");
+        let nonce = challenge.param("nonce");
+        let body = request.body().and_then(|b| b.as_bytes());
+        let qop = challenge.qop(body);
+
+        let url = request.url();
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let nc = "00000001";
+
+        let mut ha1 = algorithm.hash(format!("{}:{}:{}", self.username, realm, self.password));
+        if algorithm.sess() {
+            ha1 = algorithm.hash(format!("{}:{}:{}", ha1, nonce, cnonce));
+        }
+
+        let ha2 = match qop {
+            Some("auth-int") => algorithm.hash(format!(
+                "{}:{}:{}",
+                request.method(),
+                uri,
+                algorithm.hash(body.unwrap_or_default())
+            )),
+            _ => algorithm.hash(format!("{}:{}", request.method(), uri)),
+        };
+
+        let response = match qop {
+            Some(qop) => algorithm.hash(format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, nonce, nc, cnonce, qop, ha2
+            )),
+            None => algorithm.hash(format!("{}:{}:{}", ha1, nonce, ha2)),
+        };
+
+        let mut header = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}, response="{}""#,
+            quote(&self.username),
+            quote(realm),
+            quote(nonce),
+            quote(&uri),
+            algorithm.name(),
+            response
+        );
+
+        if let Some(qop) = qop {
+            header.push_str(&format!(r#", qop={}, nc={}, cnonce="{}""#, qop, nc, cnonce));
+        }
+
+        if let Some(opaque) = challenge.params.get("opaque") {
+            header.push_str(&format!(r#", opaque="{}""#, quote(opaque)));
+        }
+
+        Ok(header)
+    }
+}
+
+/// parse_params parses the comma separated `key=value` pairs of a challenge, values
+/// may be quoted strings containing commas and escaped characters.
+fn parse_params(value: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .skip_while(|c| c.is_whitespace() || *c == ',')
+            .take_while(|c| *c != '=')
+            .collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                value.push(c);
+            }
+        }
+
+        params.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    params
+}
+
+/// quote escapes the value so it can be placed in a quoted string
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// hex returns the lowercase hex encoding of the bytes
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{Body, Method, Url};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn get(url: &str) -> Request {
+        Request::new(Method::GET, Url::parse(url).unwrap())
+    }
+
+    /// param returns the value of name in an Authorization header
+    fn param(header: &str, name: &str) -> String {
+        parse_params(header.strip_prefix("Digest ").unwrap())
+            .remove(name)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_params_quoted_and_unquoted() {
+        let params = parse_params(
+            r#"realm="a, \"b\"", qop="auth, auth-int" ,algorithm=SHA-256, Stale=false"#,
+        );
+        assert_eq!(params["realm"], r#"a, "b""#);
+        assert_eq!(params["qop"], "auth, auth-int");
+        assert_eq!(params["algorithm"], "SHA-256");
+        assert_eq!(params["stale"], "false");
+        assert_eq!(params.len(), 4);
+    }
+
+    #[test]
+    fn challenge_other_schemes() {
+        assert!(Challenge::parse(r#"Basic realm="x""#).is_none());
+        assert!(Challenge::parse(r#"Digest realm="x", algorithm=SHA-512-256"#).is_none());
+        assert_eq!(
+            Challenge::parse(r#"Digest realm="x""#).unwrap().algorithm,
+            Algorithm::Md5 { sess: false }
+        );
+    }
+
+    // RFC 7616 section 3.9.1
+    const RFC7616_CHALLENGE: &str = r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=ALGORITHM, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+    const RFC7616_CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    #[test]
+    fn rfc7616_sha256() {
+        let auth = DigestAuth::new("Mufasa:Circle of Life").unwrap();
+        let challenge =
+            Challenge::parse(&RFC7616_CHALLENGE.replace("ALGORITHM", "SHA-256")).unwrap();
+        let header = auth
+            .authorization(
+                &challenge,
+                &get("http://www.example.org/dir/index.html"),
+                RFC7616_CNONCE,
+            )
+            .unwrap();
+
+        assert_eq!(
+            param(&header, "response"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+        assert_eq!(param(&header, "uri"), "/dir/index.html");
+        assert_eq!(param(&header, "qop"), "auth");
+        assert_eq!(param(&header, "nc"), "00000001");
+        assert_eq!(param(&header, "algorithm"), "SHA-256");
+        assert_eq!(
+            param(&header, "opaque"),
+            "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"
+        );
+    }
+
+    #[test]
+    fn rfc7616_md5() {
+        let auth = DigestAuth::new("Mufasa:Circle of Life").unwrap();
+        let challenge = Challenge::parse(&RFC7616_CHALLENGE.replace("ALGORITHM", "MD5")).unwrap();
+        let header = auth
+            .authorization(
+                &challenge,
+                &get("http://www.example.org/dir/index.html"),
+                RFC7616_CNONCE,
+            )
+            .unwrap();
+
+        assert_eq!(
+            param(&header, "response"),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+    }
+
+    // RFC 2617 section 3.5
+    #[test]
+    fn rfc2617() {
+        let auth = DigestAuth::new("Mufasa:Circle Of Life").unwrap();
+        let challenge = Challenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+        let header = auth
+            .authorization(
+                &challenge,
+                &get("http://www.nowhere.org/dir/index.html"),
+                "0a4f113b",
+            )
+            .unwrap();
+
+        assert_eq!(
+            param(&header, "response"),
+            "6629fae49393a05397450978507c4ef1"
+        );
+        assert_eq!(param(&header, "realm"), "testrealm@host.com");
+        assert_eq!(param(&header, "cnonce"), "0a4f113b");
+    }
+
+    #[tokio::test]
+    async fn streamed_body_is_sent_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/upload", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for response in [
+                "HTTP/1.1 401 Unauthorized\r\nwww-authenticate: Digest realm=\"kla\", qop=\"auth\", nonce=\"n\"\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                while !String::from_utf8_lossy(&request).ends_with("\r\n\r\nhello") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let mut request = Request::new(Method::POST, Url::parse(&url).unwrap());
+        *request.body_mut() = Some(Body::wrap_stream(body_stream("hello")));
+
+        let response = DigestAuth::new("user:pass")
+            .unwrap()
+            .execute(&RetryPolicy::default(), &Client::new(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let requests = server.await.unwrap();
+        assert!(!requests[0].to_lowercase().contains("authorization:"));
+        assert!(requests[1]
+            .to_lowercase()
+            .contains("authorization: digest username=\"user\""));
+    }
+
+    /// body_stream returns a stream of the text, a body reqwest can not clone
+    fn body_stream(
+        text: &'static str,
+    ) -> tokio_util::io::ReaderStream<std::io::Cursor<&'static [u8]>> {
+        tokio_util::io::ReaderStream::new(std::io::Cursor::new(text.as_bytes()))
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
     ffi::OsString,
     fmt::{Display, Write},
     path::PathBuf,
@@ -7,28 +8,30 @@ use std::{
 
 use std::fs::{self, DirEntry};
 
-use config::{builder::DefaultState, Config, ConfigBuilder, File};
-use reqwest::{ClientBuilder, Request, RequestBuilder};
+use base64::prelude::*;
+use config::{builder::DefaultState, Config, ConfigBuilder, File, Map, Value, ValueKind};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    Client, ClientBuilder, Request, RequestBuilder,
+};
 use serde::Deserialize;
 use skim::SkimItem;
+use tera::Context;
 
 use crate::{
+    config::ConfigKV,
+    read_secret,
     url_builder::{AssumingURLBuilder, OptBaseURLBuilder},
-    Error, Expand, Result, Sigv4Request,
+    Error, Expand, KlaClientBuilder, OAuth2, Result, Sigv4Request,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub enum Environment {
-    Endpoint(Endpoint),
+    Endpoint(Box<Endpoint>),
+    #[default]
     Empty,
 }
 
-impl Default for Environment {
-    fn default() -> Self {
-        return Environment::Empty;
-    }
-}
-
 impl Display for Environment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -41,7 +44,10 @@ impl Display for Environment {
 impl Environment {
     pub fn new(env: Option<&String>, config: &Config) -> Result<Environment> {
         if let Some(env) = env {
-            Ok(Environment::Endpoint(Endpoint::new(env.clone(), config)?))
+            Ok(Environment::Endpoint(Box::new(Endpoint::new(
+                env.clone(),
+                config,
+            )?)))
         } else {
             Ok(Environment::Empty)
         }
@@ -61,6 +67,14 @@ impl Environment {
         }
     }
 
+    /// prefix returns the url every path of the environment is appended to
+    pub fn prefix(&self) -> Option<&String> {
+        match self {
+            Environment::Endpoint(endpoint) => Some(endpoint.prefix()),
+            Environment::Empty => None,
+        }
+    }
+
     pub fn templates(&self) -> Result<Box<dyn Iterator<Item = String>>> {
         match self {
             Environment::Endpoint(endpoint) => endpoint.walk_templates(),
@@ -74,6 +88,77 @@ impl Environment {
             Environment::Empty => None,
         }
     }
+
+    /// prepare adds the oauth2 access token of the environment to the request, then
+    /// applies the environment through `WithEnvironment for Request`. The token is
+    /// fetched with client, the same client the request is sent with. Without a
+    /// client, as in a dry run, no token is fetched.
+    pub async fn prepare(&self, client: Option<&Client>, request: Request) -> Result<Request> {
+        let endpoint = match self {
+            Environment::Endpoint(endpoint) => endpoint,
+            Environment::Empty => return Ok(request),
+        };
+
+        let mut request = request;
+        if let (Some(oauth2), Some(client)) = (endpoint.oauth2.as_ref(), client) {
+            if !request.headers().contains_key(AUTHORIZATION) {
+                let token = oauth2
+                    .access_token(client, &endpoint.name, endpoint.state_file.as_deref())
+                    .await?;
+
+                let mut value = HeaderValue::try_from(format!("Bearer {}", token))?;
+                value.set_sensitive(true);
+                request.headers_mut().insert(AUTHORIZATION, value);
+            }
+        }
+
+        request.with_environment(self).await
+    }
+
+    /// secret_headers returns the names of the default headers marked as secret
+    pub fn secret_headers(&self) -> Vec<&String> {
+        match self {
+            Environment::Endpoint(endpoint) => secret_names(&endpoint.header),
+            Environment::Empty => vec![],
+        }
+    }
+
+    /// secret_query returns the names of the default query parameters marked as secret
+    pub fn secret_query(&self) -> Vec<&String> {
+        match self {
+            Environment::Endpoint(endpoint) => secret_names(&endpoint.query),
+            Environment::Empty => vec![],
+        }
+    }
+
+    /// digest_auth returns the credentials used to answer digest challenges
+    pub fn digest_auth(&self) -> Option<&String> {
+        match self {
+            Environment::Endpoint(endpoint) => endpoint.digest_auth.as_ref(),
+            Environment::Empty => None,
+        }
+    }
+
+    /// cookie_jar returns the cookie file of the environment when it keeps its
+    /// cookies. Cookies are credentials, so the file lives beside the state of the
+    /// user rather than the shared default environment file.
+    pub fn cookie_jar(&self) -> Option<PathBuf> {
+        match self {
+            Environment::Endpoint(endpoint) if endpoint.cookie_jar => endpoint
+                .state_file
+                .as_ref()
+                .map(|file| file.with_file_name(format!("cookies-{}.txt", endpoint.name))),
+            _ => None,
+        }
+    }
+
+    /// context returns a Tera Context holding the `vars` of the environment
+    pub fn context(&self) -> Context {
+        match self {
+            Environment::Endpoint(endpoint) => endpoint.context(),
+            Environment::Empty => Context::new(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -99,6 +184,66 @@ pub struct Endpoint {
     sigv4_aws_profile: Option<String>,
     #[serde(rename = "sigv4_aws_service")]
     sigv4_aws_service: Option<String>,
+
+    // default values applied to every request made against the environment.
+    // Anything set by the user or the template takes precedence.
+    #[serde(rename = "header", default)]
+    header: Vec<ConfigKV>,
+    #[serde(rename = "query", default)]
+    query: Vec<ConfigKV>,
+    #[serde(rename = "basic_auth")]
+    basic_auth: Option<String>,
+    #[serde(rename = "bearer_token")]
+    bearer_token: Option<String>,
+    #[serde(rename = "digest_auth")]
+    digest_auth: Option<String>,
+    #[serde(rename = "oauth2")]
+    oauth2: Option<OAuth2>,
+
+    // state_file is the state of the user, oauth2 tokens are cached in it and
+    // cookie jars are kept beside it
+    #[serde(skip)]
+    state_file: Option<PathBuf>,
+
+    // vars are added to the context of templates and the url of requests
+    #[serde(rename = "vars", default)]
+    vars: HashMap<String, serde_json::Value>,
+
+    // client settings, flags take precedence over these values
+    #[serde(rename = "certificate", default)]
+    certificate: Vec<String>,
+    #[serde(rename = "no_system_roots", default)]
+    no_system_roots: bool,
+    #[serde(rename = "client_cert")]
+    client_cert: Option<String>,
+    #[serde(rename = "client_key")]
+    client_key: Option<String>,
+    #[serde(rename = "client_pkcs12")]
+    client_pkcs12: Option<String>,
+    #[serde(rename = "client_pkcs12_password")]
+    client_pkcs12_password: Option<String>,
+    #[serde(rename = "insecure", default)]
+    insecure: bool,
+    #[serde(rename = "proxy")]
+    proxy: Option<String>,
+    #[serde(rename = "proxy_http")]
+    proxy_http: Option<String>,
+    #[serde(rename = "proxy_https")]
+    proxy_https: Option<String>,
+    #[serde(rename = "proxy_auth")]
+    proxy_auth: Option<String>,
+    #[serde(rename = "connect_timeout")]
+    connect_timeout: Option<String>,
+    #[serde(rename = "timeout")]
+    timeout: Option<String>,
+    #[serde(rename = "max_redirects")]
+    max_redirects: Option<usize>,
+    #[serde(rename = "no_redirects", default)]
+    no_redirects: bool,
+    #[serde(rename = "user_agent")]
+    user_agent: Option<String>,
+    #[serde(rename = "cookie_jar", default)]
+    cookie_jar: bool,
 }
 
 impl Endpoint {
@@ -107,18 +252,30 @@ impl Endpoint {
         S: Into<String>,
     {
         let env: String = env.into();
-        let mut endpoint =
-            config.get::<Endpoint>(format!("environment.{}", env.as_str()).as_ref())?;
+        let mut endpoint: Endpoint =
+            Value::from(environment_table(&env, config, &mut vec![])?).try_deserialize()?;
 
         // set the name
         endpoint.name = env;
+        endpoint.state_file = config
+            .get_string("default.state")
+            .ok()
+            .map(|file| PathBuf::from(file.shell_expansion()));
 
         // normalize the prefix
         if !endpoint.prefix.ends_with("/") {
-            endpoint.prefix.push_str("/");
+            endpoint.prefix.push('/');
         };
 
         endpoint.template_dir = endpoint.template_dir.map(String::shell_expansion);
+        endpoint.certificate = endpoint
+            .certificate
+            .into_iter()
+            .map(String::shell_expansion)
+            .collect();
+        endpoint.client_cert = endpoint.client_cert.map(String::shell_expansion);
+        endpoint.client_key = endpoint.client_key.map(String::shell_expansion);
+        endpoint.client_pkcs12 = endpoint.client_pkcs12.map(String::shell_expansion);
 
         Ok(endpoint)
     }
@@ -127,11 +284,79 @@ impl Endpoint {
         AssumingURLBuilder::from(&self.prefix)
     }
 
+    /// prefix returns the url of the environment, always ending with a /
+    pub fn prefix(&self) -> &String {
+        &self.prefix
+    }
+
+    /// context returns a Tera Context holding the `vars` of the environment
+    pub fn context(&self) -> Context {
+        let mut ctx = Context::new();
+        for (name, value) in &self.vars {
+            ctx.insert(name, value);
+        }
+        ctx
+    }
+
     // template_dir returns the directory for the given environment
     pub fn template_dir(&self) -> Option<&String> {
         self.template_dir.as_ref()
     }
 
+    /// login logs in to the environment through its `oauth2` settings, storing the
+    /// token in the state so later requests can use and refresh it.
+    pub async fn login(&self, client: &Client) -> Result<()> {
+        let oauth2 = self.oauth2.as_ref().ok_or_else(|| {
+            Error::from(format!("environment {} has no oauth2 settings", self.name))
+        })?;
+
+        oauth2
+            .login(client, &self.name, self.state_file.as_deref())
+            .await
+    }
+
+    /// apply_defaults adds the environment's default headers, query parameters and
+    /// authorization to the request. Values are only added when the request does not
+    /// already have them, so flags and templates always win.
+    pub fn apply_defaults(&self, req: Request) -> Result<Request> {
+        let mut req = req;
+
+        let mut headers = HeaderMap::new();
+        for header in &self.header {
+            let name = HeaderName::try_from(header.name.as_str())?;
+            if !req.headers().contains_key(&name) {
+                headers.append(name, HeaderValue::try_from(header.value.as_str())?);
+            }
+        }
+        req.headers_mut().extend(headers);
+
+        let existing: HashSet<String> = req.url().query_pairs().map(|(k, _)| k.into()).collect();
+        for query in self.query.iter().filter(|q| !existing.contains(&q.name)) {
+            req.url_mut()
+                .query_pairs_mut()
+                .append_pair(&query.name, &query.value);
+        }
+
+        if !req.headers().contains_key(AUTHORIZATION) {
+            let authorization = if let Some(userpass) = self.basic_auth.as_ref() {
+                let userpass = read_secret(userpass)?;
+                Some(format!("Basic {}", BASE64_STANDARD.encode(userpass)))
+            } else if let Some(token) = self.bearer_token.as_ref() {
+                Some(format!("Bearer {}", read_secret(token)?))
+            } else {
+                None
+            };
+
+            if let Some(authorization) = authorization {
+                let mut value = HeaderValue::try_from(authorization)?;
+                value.set_sensitive(true);
+                req.headers_mut().insert(AUTHORIZATION, value);
+            }
+        }
+
+        Ok(req)
+    }
+
     /// walk_templates returns a WalkDir of all the templates in the
     /// template directory
     pub fn walk_templates(&self) -> Result<Box<dyn Iterator<Item = String>>> {
@@ -150,12 +375,114 @@ impl Endpoint {
     }
 }
 
+/// environment_table returns the configuration table for the environment. When the
+/// environment `extends` another environment the parent table is resolved first, and
+/// the child is merged on top of it. `seen` holds the environments already visited so
+/// cycles can be reported instead of recursing forever.
+fn environment_table(
+    env: &str,
+    config: &Config,
+    seen: &mut Vec<String>,
+) -> Result<Map<String, Value>> {
+    let cycle = seen.iter().any(|s| s == env);
+    seen.push(env.to_string());
+    if cycle {
+        return Err(Error::from(format!(
+            "environment {} has an `extends` cycle: {}",
+            env,
+            seen.join(" -> ")
+        )));
+    }
+
+    let table = config.get_table(&format!("environment.{}", env))?;
+    let parent = match table.get("extends") {
+        Some(parent) => parent.clone().into_string()?,
+        None => return Ok(table),
+    };
+
+    Ok(merge_tables(
+        environment_table(&parent, config, seen)?,
+        table,
+    ))
+}
+
+/// merge_tables deep merges child into parent, the child wins on conflicts. Tables are
+/// merged recursively, and arrays of tables (like `[[header]]`) are merged by `name`
+/// so a child can override a single entry of the parent.
+fn merge_tables(parent: Map<String, Value>, child: Map<String, Value>) -> Map<String, Value> {
+    let mut merged = parent;
+
+    for (key, value) in child {
+        let value = match (merged.remove(&key), value) {
+            (
+                Some(Value {
+                    kind: ValueKind::Table(parent),
+                    ..
+                }),
+                Value {
+                    kind: ValueKind::Table(child),
+                    ..
+                },
+            ) => Value::new(None, merge_tables(parent, child)),
+            (
+                Some(Value {
+                    kind: ValueKind::Array(parent),
+                    ..
+                }),
+                Value {
+                    kind: ValueKind::Array(child),
+                    ..
+                },
+            ) => Value::new(None, merge_named(parent, child)),
+            (_, value) => value,
+        };
+        merged.insert(key, value);
+    }
+
+    merged
+}
+
+/// merge_named merges two arrays of tables by their `name` value. Entries in the child
+/// replace entries in the parent with the same name, everything else is appended.
+/// Arrays of anything but tables, like `certificate`, are replaced by the child.
+fn merge_named(parent: Vec<Value>, child: Vec<Value>) -> Vec<Value> {
+    fn name(value: &Value) -> Option<String> {
+        match &value.kind {
+            ValueKind::Table(table) => table.get("name").map(|v| v.to_string()),
+            _ => None,
+        }
+    }
+    fn tables(values: &[Value]) -> bool {
+        values.iter().all(|v| matches!(v.kind, ValueKind::Table(_)))
+    }
+
+    if !tables(&parent) || !tables(&child) {
+        return child;
+    }
+
+    let overridden: HashSet<String> = child.iter().filter_map(name).collect();
+    parent
+        .into_iter()
+        .filter(|v| name(v).map(|n| !overridden.contains(&n)).unwrap_or(true))
+        .chain(child)
+        .collect()
+}
+
+/// secret_names returns the names of the values marked as secret
+pub(crate) fn secret_names(values: &[ConfigKV]) -> Vec<&String> {
+    values
+        .iter()
+        .filter(|v| v.secret)
+        .map(|v| &v.name)
+        .collect()
+}
+
 impl Display for Endpoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: [{}]\n", self.name, self.prefix)?;
+        writeln!(f, "{}: [{}]", self.name, self.prefix)?;
 
         if let Some(short_description) = self.short_description.as_ref() {
-            write!(f, "\tdescription: {}\n", short_description)?;
+            writeln!(f, "\tdescription: {}", short_description)?;
         }
 
         Ok(())
@@ -169,7 +496,7 @@ impl SkimItem for Endpoint {
 
     fn preview(&self, _context: skim::PreviewContext) -> skim::ItemPreview {
         let mut s = String::new();
-        write!(s, "{}: [{}]\n", &self.name, &self.prefix).expect("writing to string");
+        writeln!(s, "{}: [{}]", &self.name, &self.prefix).expect("writing to string");
 
         if let Some(long_description) = self.long_description.as_ref() {
             write!(s, "\n{long_description}").expect("writing to string");
@@ -218,34 +545,430 @@ pub trait WithEnvironment: Sized {
 }
 
 impl WithEnvironment for ClientBuilder {
-    async fn with_environment(self, _env: &Environment) -> Result<Self> {
-        Ok(self)
+    async fn with_environment(self, env: &Environment) -> Result<Self> {
+        let endpoint = match env {
+            Environment::Endpoint(endpoint) => endpoint,
+            Environment::Empty => return Ok(self),
+        };
+
+        let proxy_auth = endpoint.proxy_auth.as_ref();
+
+        let builder = self
+            .opt_header_agent(endpoint.user_agent.as_ref())?
+            .opt_certificate(Some(endpoint.certificate.iter()))?
+            .no_system_roots(endpoint.no_system_roots)
+            .opt_client_certificate(endpoint.client_cert.as_ref(), endpoint.client_key.as_ref())?
+            .opt_client_pkcs12(
+                endpoint.client_pkcs12.as_ref(),
+                endpoint
+                    .client_pkcs12_password
+                    .as_deref()
+                    .map(read_secret)
+                    .transpose()?
+                    .as_ref(),
+            )?
+            .danger_accept_invalid_certs(endpoint.insecure)
+            .opt_proxy(endpoint.proxy.as_ref(), proxy_auth)?
+            .opt_proxy_http(endpoint.proxy_http.as_ref(), proxy_auth)?
+            .opt_proxy_https(endpoint.proxy_https.as_ref(), proxy_auth)?
+            .opt_connect_timeout(endpoint.connect_timeout.as_ref())?
+            .opt_timeout(endpoint.timeout.as_ref())?
+            .opt_max_redirects(endpoint.max_redirects.as_ref())
+            .no_redirects(endpoint.no_redirects);
+
+        Ok(builder)
     }
 }
 
 impl WithEnvironment for RequestBuilder {
-    async fn with_environment(self, _env: &Environment) -> Result<Self> {
-        Ok(self)
+    /// with_environment adds the environment's default headers, query parameters and
+    /// authorization. Only what the request doesn't have yet is added, so call it
+    /// after the flags and the template are applied for them to take precedence.
+    async fn with_environment(self, env: &Environment) -> Result<Self> {
+        let endpoint = match env {
+            Environment::Endpoint(endpoint) => endpoint,
+            Environment::Empty => return Ok(self),
+        };
+
+        let (client, request) = self.build_split();
+        Ok(RequestBuilder::from_parts(
+            client,
+            endpoint.apply_defaults(request?)?,
+        ))
     }
 }
 
 impl WithEnvironment for Request {
+    /// with_environment adds the environment's defaults the request doesn't have
+    /// yet, and signs it when sigv4 is set. Signing comes last so it covers every
+    /// header. OAuth2 tokens need a client to be fetched, see `Environment::prepare`.
     async fn with_environment(self, env: &Environment) -> Result<Self> {
         let endpoint = match env {
             Environment::Endpoint(endpoint) => endpoint,
             Environment::Empty => return Ok(self),
         };
 
+        let request = endpoint.apply_defaults(self)?;
+
         let request = if endpoint.sigv4.unwrap_or(false) {
-            self.sign_request(
-                endpoint.sigv4_aws_profile.as_ref(),
-                endpoint.sigv4_aws_service.as_ref(),
-            )
-            .await?
+            request
+                .sign_request(
+                    endpoint.sigv4_aws_profile.as_ref(),
+                    endpoint.sigv4_aws_service.as_ref(),
+                )
+                .await?
         } else {
-            self
+            request
         };
 
         Ok(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use config::FileFormat;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .expect("valid toml")
+    }
+
+    const EXTENDS: &str = r#"
+[environment.parent]
+url = "http://parent"
+timeout = "5s"
+certificate = ["parent.pem", "shared.pem"]
+
+[environment.parent.oauth2]
+token_url = "http://parent/token"
+client_id = "parent"
+scopes = ["read", "write"]
+
+[[environment.parent.header]]
+name = "Accept"
+value = "application/json"
+[[environment.parent.header]]
+name = "X-Tenant"
+value = "parent"
+
+[environment.child]
+extends = "parent"
+url = "http://child"
+certificate = ["child.pem"]
+
+[environment.child.oauth2]
+scopes = ["read"]
+
+[[environment.child.header]]
+name = "X-Tenant"
+value = "child"
+[[environment.child.header]]
+name = "X-Child"
+value = "yes"
+"#;
+
+    #[test]
+    fn child_scalars_win() {
+        let endpoint = Endpoint::new("child", &config(EXTENDS)).expect("valid environment");
+        assert_eq!(endpoint.prefix(), "http://child/");
+        assert_eq!(endpoint.timeout.as_deref(), Some("5s"));
+    }
+
+    #[test]
+    fn child_replaces_plain_arrays() {
+        let conf = config(EXTENDS);
+        let endpoint = Endpoint::new("child", &conf).expect("valid environment");
+        assert_eq!(endpoint.certificate, vec!["child.pem".to_string()]);
+
+        let table = environment_table("child", &conf, &mut vec![]).expect("valid environment");
+        let oauth2 = table["oauth2"].clone().into_table().expect("table");
+        let scopes: Vec<String> = oauth2["scopes"]
+            .clone()
+            .into_array()
+            .expect("array")
+            .into_iter()
+            .map(|v| v.into_string().expect("string"))
+            .collect();
+        assert_eq!(scopes, vec!["read"]);
+        assert_eq!(
+            oauth2["client_id"].clone().into_string().expect("string"),
+            "parent"
+        );
+    }
+
+    #[test]
+    fn child_merges_named_tables() {
+        let endpoint = Endpoint::new("child", &config(EXTENDS)).expect("valid environment");
+        let headers: Vec<(&str, &str)> = endpoint
+            .header
+            .iter()
+            .map(|h| (h.name.as_str(), h.value.as_str()))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                ("Accept", "application/json"),
+                ("X-Tenant", "child"),
+                ("X-Child", "yes"),
+            ]
+        );
+    }
+
+    #[test]
+    fn extends_cycle_fails() {
+        let conf = config(
+            r#"
+[environment.a]
+url = "http://a"
+extends = "b"
+[environment.b]
+url = "http://b"
+extends = "a"
+"#,
+        );
+        assert!(Endpoint::new("a", &conf).is_err());
+    }
+
+    const DEFAULTS: &str = r#"
+[environment.es]
+url = "http://es"
+bearer_token = "t0k3n"
+
+[[environment.es.header]]
+name = "Accept"
+value = "application/json"
+[[environment.es.header]]
+name = "X-Tenant"
+value = "kla"
+
+[[environment.es.query]]
+name = "pretty"
+value = "true"
+[[environment.es.query]]
+name = "size"
+value = "10"
+"#;
+
+    #[tokio::test]
+    async fn request_builder_gets_defaults() {
+        let env = Environment::new(Some(&"es".into()), &config(DEFAULTS)).unwrap();
+        let request = Client::new()
+            .get("http://es/_search?size=1")
+            .header("X-Tenant", "flag")
+            .with_environment(&env)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(request.url().query(), Some("size=1&pretty=true"));
+        assert_eq!(request.headers()["accept"], "application/json");
+        assert_eq!(request.headers().get_all("x-tenant").iter().count(), 1);
+        assert_eq!(request.headers()["x-tenant"], "flag");
+        assert_eq!(request.headers()[AUTHORIZATION], "Bearer t0k3n");
+        assert!(request.headers()[AUTHORIZATION].is_sensitive());
+    }
+
+    #[tokio::test]
+    async fn request_builder_keeps_flag_authorization() {
+        let env = Environment::new(Some(&"es".into()), &config(DEFAULTS)).unwrap();
+        let request = Client::new()
+            .get("http://es/")
+            .basic_auth("me", Some("pw"))
+            .with_environment(&env)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(request.headers()[AUTHORIZATION], "Basic bWU6cHc=");
+    }
+
+    #[tokio::test]
+    async fn request_gets_defaults() {
+        let env = Environment::new(Some(&"es".into()), &config(DEFAULTS)).unwrap();
+        let request = Client::new()
+            .get("http://es/_search")
+            .build()
+            .unwrap()
+            .with_environment(&env)
+            .await
+            .unwrap();
+
+        assert_eq!(request.url().query(), Some("pretty=true&size=10"));
+        assert_eq!(request.headers()["x-tenant"], "kla");
+        assert_eq!(request.headers()[AUTHORIZATION], "Bearer t0k3n");
+    }
+
+    #[test]
+    fn cookie_jar_lives_beside_the_state() {
+        let conf = Config::builder()
+            .set_default("default.environment", "/etc/kla/.default-environment")
+            .unwrap()
+            .set_default("default.state", "/home/kla/.local/state/kla/state.json")
+            .unwrap()
+            .add_source(File::from_str(
+                "[environment.a]\nurl = \"http://a\"\ncookie_jar = true\n[environment.b]\nurl = \"http://b\"\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        let env = Environment::new(Some(&"a".into()), &conf).unwrap();
+        assert_eq!(
+            env.cookie_jar(),
+            Some(PathBuf::from("/home/kla/.local/state/kla/cookies-a.txt"))
+        );
+        let env = Environment::new(Some(&"b".into()), &conf).unwrap();
+        assert_eq!(env.cookie_jar(), None);
+    }
+
+    /// serve answers each request with the next of the responses, it returns the
+    /// url of the server and the head of every request it received
+    async fn serve(responses: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let heads = Arc::new(Mutex::new(vec![]));
+
+        let received = heads.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = vec![];
+                let mut buf = [0; 4096];
+                while !String::from_utf8_lossy(&head).contains("\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    head.extend_from_slice(&buf[..read]);
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(head).unwrap());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, heads)
+    }
+
+    fn redirect(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            location
+        )
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+
+    /// client builds the client of the environment with the settings
+    async fn client(settings: &str) -> Client {
+        let conf = config(&format!(
+            "[environment.test]\nurl = \"http://test\"\n{}",
+            settings
+        ));
+        let env = Environment::new(Some(&"test".into()), &conf).expect("valid environment");
+        ClientBuilder::new()
+            .with_environment(&env)
+            .await
+            .expect("valid client settings")
+            .build()
+            .expect("valid client")
+    }
+
+    #[tokio::test]
+    async fn client_user_agent() {
+        let (url, heads) = serve(vec![OK.into()]).await;
+        client("user_agent = \"kla-test/1.0\"")
+            .await
+            .get(&url)
+            .send()
+            .await
+            .unwrap();
+        assert!(heads.lock().unwrap()[0].contains("user-agent: kla-test/1.0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn client_no_redirects() {
+        let (url, heads) = serve(vec![redirect("/next"), OK.into()]).await;
+        let response = client("no_redirects = true")
+            .await
+            .get(&url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 302);
+        assert_eq!(heads.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_max_redirects() {
+        let (url, _) = serve(vec![redirect("/a"), OK.into()]).await;
+        let response = client("max_redirects = 1")
+            .await
+            .get(&url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let (url, _) = serve(vec![redirect("/a"), redirect("/b"), OK.into()]).await;
+        let err = client("max_redirects = 1")
+            .await
+            .get(&url)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn client_timeout() {
+        // the server accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        });
+
+        let err = client("timeout = \"100ms\"")
+            .await
+            .get(&url)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn client_proxy() {
+        let (proxy, heads) = serve(vec![OK.into()]).await;
+        let response = client(&format!("proxy = \"{}\"", proxy))
+            .await
+            .get("http://kla.invalid/path")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        assert!(heads.lock().unwrap()[0].starts_with("GET http://kla.invalid/path HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn client_invalid_settings() {
+        let conf =
+            config("[environment.test]\nurl = \"http://test\"\nconnect_timeout = \"soon\"\n");
+        let env = Environment::new(Some(&"test".into()), &conf).expect("valid environment");
+        assert!(ClientBuilder::new().with_environment(&env).await.is_err());
+    }
+}
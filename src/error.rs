@@ -76,3 +76,9 @@ impl From<reqwest::header::InvalidHeaderName> for Error {
         Error::InvalidArguments(Box::new(err))
     }
 }
+
+impl From<std::convert::Infallible> for Error {
+    fn from(err: std::convert::Infallible) -> Self {
+        match err {}
+    }
+}
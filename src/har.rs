@@ -0,0 +1,487 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
+use reqwest::{header::HeaderMap, Request, Response};
+use serde_json::{json, Value};
+
+use crate::{duplicate, write_private, Error, Expand, MultipartField, Result, Secrets, REDACTED};
+
+#[derive(Clone, Debug)]
+/// Har records requests and their responses into a HAR 1.2 file, which can be
+/// loaded by browser developer tools. Entries are appended to an existing file, so
+/// a single file can hold a whole session. Secrets are redacted unless they are
+/// shown.
+pub struct Har {
+    path: PathBuf,
+    secrets: Secrets,
+}
+
+/// Exchange is a request on its way, started by `Har::start`
+pub struct Exchange<'a> {
+    har: &'a Har,
+    request: Value,
+    started: DateTime<Utc>,
+    start: Instant,
+}
+
+impl Har {
+    /// new records into the HAR file at path
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path.shell_expansion()),
+            secrets: Secrets::default(),
+        }
+    }
+
+    /// opt_new records into the HAR file when a path is supplied
+    pub fn opt_new(path: Option<&String>) -> Option<Self> {
+        path.map(|path| Self::new(path))
+    }
+
+    /// secrets sets the secrets redacted from the recording
+    pub fn secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    /// start captures the request before it's sent, call `Exchange::finish` with
+    /// the response to record the exchange. Streamed bodies can't be recorded, the
+    /// file they're read from is recorded instead. source is the body argument the
+    /// request was built with, and multipart holds its multipart fields.
+    pub fn start(
+        &self,
+        request: &Request,
+        source: Option<&String>,
+        multipart: &[MultipartField],
+    ) -> Exchange<'_> {
+        let url = self.secrets.url(request.url());
+        let headers = request.headers();
+
+        let mut har_request = json!({
+            "method": request.method().as_str(),
+            "url": url.as_str(),
+            "httpVersion": format!("{:?}", request.version()),
+            "cookies": self.request_cookies(headers),
+            "headers": self.headers(headers),
+            "queryString": url
+                .query_pairs()
+                .map(|(name, value)| json!({"name": name, "value": value}))
+                .collect::<Vec<_>>(),
+            "headersSize": -1,
+            "bodySize": 0,
+        });
+
+        match request.body().map(|body| body.as_bytes()) {
+            Some(Some(body)) => {
+                har_request["bodySize"] = body.len().into();
+                har_request["postData"] = json!({
+                    "mimeType": content_type(headers),
+                    "text": String::from_utf8_lossy(body),
+                });
+            }
+            // streamed bodies are sent as they're read, so they can't be recorded
+            Some(None) => {
+                har_request["bodySize"] = (-1).into();
+                har_request["postData"] = streamed(content_type(headers), source, multipart);
+            }
+            None => (),
+        }
+
+        Exchange {
+            har: self,
+            request: har_request,
+            started: Utc::now(),
+            start: Instant::now(),
+        }
+    }
+
+    /// headers returns the headers as HAR name value pairs
+    fn headers(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                json!({"name": name.as_str(), "value": self.secrets.header(name.as_str(), value)})
+            })
+            .collect()
+    }
+
+    /// request_cookies returns the cookies of the Cookie headers
+    fn request_cookies(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .flat_map(|value| {
+                let secret = self.secrets.is_secret_header(COOKIE.as_str(), value);
+                String::from_utf8_lossy(value.as_bytes())
+                    .split(';')
+                    .filter_map(|cookie| cookie.trim().split_once('='))
+                    .map(|(name, value)| cookie(name, value, secret))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// response_cookies returns the cookies set by the Set-Cookie headers
+    fn response_cookies(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| {
+                let secret = self.secrets.is_secret_header(SET_COOKIE.as_str(), value);
+                let value = String::from_utf8_lossy(value.as_bytes());
+                let pair = value.split(';').next()?;
+                let (name, value) = pair.trim().split_once('=')?;
+                Some(cookie(name, value, secret))
+            })
+            .collect()
+    }
+
+    /// append adds the entry to the HAR file, creating the file when it's missing
+    fn append(&self, entry: Value) -> Result<()> {
+        let mut har = match fs::read_to_string(&self.path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => json!({
+                "log": {
+                    "version": "1.2",
+                    "creator": {"name": "kla", "version": env!("CARGO_PKG_VERSION")},
+                    "entries": [],
+                }
+            }),
+            Err(err) => return Err(err.into()),
+        };
+
+        har.pointer_mut("/log/entries")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| Error::from(format!("{} is not a HAR file", self.path.display())))?
+            .push(entry);
+
+        write_private(&self.path, serde_json::to_string_pretty(&har)?)
+    }
+}
+
+impl Exchange<'_> {
+    /// finish records the exchange with the response. The body of the response is
+    /// read to be recorded, so a new response holding the same body is returned.
+    pub async fn finish(self, response: Response) -> Result<Response> {
+        let wait = self.start.elapsed();
+        let (response, copy) = duplicate(response).await?;
+        let body = copy.bytes().await?;
+        let receive = self.start.elapsed().saturating_sub(wait);
+
+        let headers = response.headers();
+        let mut content = json!({
+            "size": body.len(),
+            "mimeType": content_type(headers),
+        });
+        match std::str::from_utf8(&body) {
+            Ok(text) => content["text"] = text.into(),
+            Err(_) => {
+                content["text"] = BASE64_STANDARD.encode(&body).into();
+                content["encoding"] = "base64".into();
+            }
+        }
+
+        let entry = json!({
+            "startedDateTime": self.started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "time": millis(wait + receive),
+            "request": self.request,
+            "response": {
+                "status": response.status().as_u16(),
+                "statusText": response.status().canonical_reason().unwrap_or_default(),
+                "httpVersion": format!("{:?}", response.version()),
+                "cookies": self.har.response_cookies(headers),
+                "headers": self.har.headers(headers),
+                "content": content,
+                "redirectURL": headers
+                    .get(LOCATION)
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+                    .unwrap_or_default(),
+                "headersSize": -1,
+                "bodySize": body.len(),
+            },
+            "cache": {},
+            // reqwest doesn't tell us how long connecting took, so it's part of wait
+            "timings": {
+                "blocked": -1,
+                "dns": -1,
+                "connect": -1,
+                "ssl": -1,
+                "send": 0,
+                "wait": millis(wait),
+                "receive": millis(receive),
+            },
+        });
+
+        self.har.append(entry)?;
+        Ok(response)
+    }
+}
+
+/// cookie returns a HAR cookie, redacting the value when it's a secret
+fn cookie(name: &str, value: &str, secret: bool) -> Value {
+    json!({
+        "name": name,
+        "value": if secret { REDACTED } else { value },
+    })
+}
+
+/// streamed returns the postData of a streamed body. The files of multipart bodies
+/// are recorded as params holding their path, other bodies record where they were
+/// read from as their text.
+fn streamed(mime: String, source: Option<&String>, multipart: &[MultipartField]) -> Value {
+    if !multipart.is_empty() {
+        let params = multipart
+            .iter()
+            .map(|field| match field.file() {
+                Some((path, mime, filename)) => json!({
+                    "name": field.name(),
+                    "value": format!("@{}", path),
+                    "fileName": filename,
+                    "contentType": mime,
+                }),
+                None => json!({"name": field.name(), "value": field.text()}),
+            })
+            .collect::<Vec<_>>();
+        return json!({
+            "mimeType": mime,
+            "params": params,
+            "comment": "the body was streamed, the value of file params is the path they were read from",
+        });
+    }
+
+    let (text, comment) = match source.map(String::as_str) {
+        Some("-") => (
+            "@-".to_string(),
+            "the body was streamed from standard in and is not recorded".to_string(),
+        ),
+        Some(source) if source.starts_with('@') => {
+            let path = source[1..].shell_expansion();
+            (
+                format!("@{}", path),
+                format!("the body was streamed from {} and is not recorded", path),
+            )
+        }
+        _ => (
+            String::new(),
+            "the body was streamed and is not recorded".to_string(),
+        ),
+    };
+
+    json!({
+        "mimeType": mime,
+        "text": text,
+        "comment": comment,
+    })
+}
+
+/// content_type returns the Content-Type header, or an empty string
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+        .unwrap_or_default()
+}
+
+/// millis returns the duration in milliseconds
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Request {
+        reqwest::Client::new()
+            .post("https://x.io/users?token=abc&page=2")
+            .header("Authorization", "Bearer abc")
+            .header("X-Api-Key", "k3y")
+            .header("Cookie", "session=s1; theme=dark")
+            .header("Accept", "application/json")
+            .body("{\"name\": \"kla\"}")
+            .build()
+            .unwrap()
+    }
+
+    fn response() -> Response {
+        http::Response::builder()
+            .status(201)
+            .header("Content-Type", "application/json")
+            .header("Set-Cookie", "session=s2; Path=/")
+            .body("{\"id\": 1}")
+            .unwrap()
+            .into()
+    }
+
+    fn value<'a>(pairs: &'a Value, name: &str) -> &'a str {
+        pairs
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair["name"].as_str().unwrap().eq_ignore_ascii_case(name))
+            .and_then(|pair| pair["value"].as_str())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_and_appends() {
+        let dir = std::env::temp_dir().join(format!("kla-har-{}", std::process::id()));
+        let path = dir.join("session.har");
+        let har = Har::new(path.to_str().unwrap()).secrets(Secrets::default().headers([
+            "x-api-key",
+            "cookie",
+            "set-cookie",
+        ]));
+
+        for _ in 0..2 {
+            let response = har
+                .start(&request(), None, &[])
+                .finish(response())
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), "{\"id\": 1}");
+        }
+
+        let recorded: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(recorded["log"]["version"], "1.2");
+        let entries = recorded["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let request = &entries[1]["request"];
+        assert_eq!(request["method"], "POST");
+        assert_eq!(
+            request["url"],
+            format!("https://x.io/users?token={}&page=2", REDACTED)
+        );
+        assert_eq!(value(&request["queryString"], "token"), REDACTED);
+        assert_eq!(value(&request["queryString"], "page"), "2");
+        assert_eq!(value(&request["headers"], "authorization"), REDACTED);
+        assert_eq!(value(&request["headers"], "x-api-key"), REDACTED);
+        assert_eq!(value(&request["headers"], "accept"), "application/json");
+        assert_eq!(value(&request["cookies"], "theme"), REDACTED);
+        assert_eq!(request["postData"]["text"], "{\"name\": \"kla\"}");
+
+        let response = &entries[1]["response"];
+        assert_eq!(response["status"], 201);
+        assert_eq!(response["content"]["text"], "{\"id\": 1}");
+        assert_eq!(value(&response["cookies"], "session"), REDACTED);
+
+        let text = fs::read_to_string(&path).unwrap();
+        for secret in ["abc", "k3y", "s1", "s2"] {
+            assert!(!text.contains(secret), "{} was recorded", secret);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_record_their_source() {
+        let dir = std::env::temp_dir().join(format!("kla-har-streamed-{}", std::process::id()));
+        let path = dir.join("session.har");
+        let har = Har::new(path.to_str().unwrap());
+        let streamed = || {
+            reqwest::Client::new()
+                .post("https://x.io/upload")
+                .header("Content-Type", "text/csv")
+                .body(reqwest::Body::wrap_stream(
+                    tokio_util::io::ReaderStream::new(tokio::io::empty()),
+                ))
+                .build()
+                .unwrap()
+        };
+
+        let source = "@/tmp/report.csv".to_string();
+        har.start(&streamed(), Some(&source), &[])
+            .finish(response())
+            .await
+            .unwrap();
+        har.start(&streamed(), Some(&"-".to_string()), &[])
+            .finish(response())
+            .await
+            .unwrap();
+        let fields = ["greeting=hi", "report=@/tmp/report.csv;filename=q1.csv"].map(String::from);
+        let multipart = MultipartField::parse(Some(fields.iter())).unwrap();
+        har.start(&streamed(), None, &multipart)
+            .finish(response())
+            .await
+            .unwrap();
+
+        let recorded: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let entries = recorded["log"]["entries"].as_array().unwrap();
+
+        let file = &entries[0]["request"];
+        assert_eq!(file["bodySize"], -1);
+        assert_eq!(file["postData"]["mimeType"], "text/csv");
+        assert_eq!(file["postData"]["text"], "@/tmp/report.csv");
+        assert_eq!(
+            file["postData"]["comment"],
+            "the body was streamed from /tmp/report.csv and is not recorded"
+        );
+
+        let stdin = &entries[1]["request"]["postData"];
+        assert_eq!(stdin["text"], "@-");
+        assert_eq!(
+            stdin["comment"],
+            "the body was streamed from standard in and is not recorded"
+        );
+
+        let params = &entries[2]["request"]["postData"]["params"];
+        assert_eq!(value(params, "greeting"), "hi");
+        assert_eq!(value(params, "report"), "@/tmp/report.csv");
+        assert_eq!(params[1]["fileName"], "q1.csv");
+        assert_eq!(params[1]["contentType"], "text/csv");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shown_secrets_are_recorded() {
+        let dir = std::env::temp_dir().join(format!("kla-har-shown-{}", std::process::id()));
+        let path = dir.join("session.har");
+        let har = Har::new(path.to_str().unwrap()).secrets(Secrets::default().show(true));
+
+        har.start(&request(), None, &[])
+            .finish(response())
+            .await
+            .unwrap();
+
+        let recorded: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let request = &recorded["log"]["entries"][0]["request"];
+        assert_eq!(value(&request["headers"], "authorization"), "Bearer abc");
+        assert_eq!(value(&request["queryString"], "token"), "abc");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn appending_to_other_json_fails() {
+        let dir = std::env::temp_dir().join(format!("kla-har-invalid-{}", std::process::id()));
+        let path = dir.join("session.har");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "{\"log\": {}}").unwrap();
+
+        let har = Har::new(path.to_str().unwrap());
+        assert!(har
+            .start(&request(), None, &[])
+            .finish(response())
+            .await
+            .is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"log\": {}}");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,697 @@
+use std::{
+    borrow::Cow,
+    fmt::{Display, Write as _},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read as _, Seek as _, SeekFrom, Write as _},
+    path::PathBuf,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use http::StatusCode;
+use reqwest::Request;
+use serde::{Deserialize, Serialize};
+use skim::SkimItem;
+
+use crate::{Error, Expand, Result, Secrets, REDACTED};
+
+/// SECRET_FLAGS are the flags whose values are redacted from the recorded arguments,
+/// unless they refer to a file or a command
+const SECRET_FLAGS: &[&str] = &[
+    "--basic-auth",
+    "--bearer-token",
+    "--digest-auth",
+    "--proxy-auth",
+    "--client-pkcs12-password",
+    "--cookie",
+];
+
+/// HEADER_FLAGS are the flags taking a header, their values are redacted when the
+/// header is a secret
+const HEADER_FLAGS: &[&str] = &["-H", "--header"];
+
+/// QUERY_FLAGS are the flags taking a query parameter, their values are redacted
+/// when the parameter is a secret
+const QUERY_FLAGS: &[&str] = &["-Q", "--query"];
+
+/// HISTORY_TAIL is how many bytes from the end of the history are read to number
+/// the next entry
+const HISTORY_TAIL: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, Default)]
+/// History records the executed requests into a JSON lines file, so they can be
+/// looked up and replayed later. An empty path turns the history off.
+pub struct History {
+    path: Option<PathBuf>,
+    args: Vec<String>,
+    /// passwords are the template password args, their values are redacted from args
+    passwords: Vec<PasswordArg>,
+}
+
+#[derive(Clone, Debug, Default)]
+/// PasswordArg is the value given to a template arg marked as a password, along with
+/// the `--long` and `-s` flags of the arg. Positional args have neither.
+pub struct PasswordArg {
+    pub value: String,
+    pub longs: Vec<String>,
+    pub shorts: Vec<char>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+/// HistoryEntry is a single executed request. The url and headers are the final
+/// ones sent, with the secrets redacted.
+pub struct HistoryEntry {
+    #[serde(rename = "id")]
+    pub id: u64,
+    #[serde(rename = "time")]
+    pub time: String,
+    #[serde(rename = "environment")]
+    pub environment: Option<String>,
+    #[serde(rename = "template")]
+    pub template: Option<String>,
+    /// args are the arguments kla was called with, replaying them runs the
+    /// request again
+    #[serde(rename = "args")]
+    pub args: Vec<String>,
+    #[serde(rename = "method")]
+    pub method: String,
+    #[serde(rename = "url")]
+    pub url: String,
+    #[serde(rename = "headers")]
+    pub headers: Vec<(String, String)>,
+    #[serde(rename = "status")]
+    pub status: u16,
+    #[serde(rename = "duration_ms")]
+    pub duration_ms: u64,
+}
+
+impl History {
+    /// new records into the file at path, an empty path turns the history off
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: (!path.is_empty()).then(|| PathBuf::from(path.shell_expansion())),
+            args: vec![],
+            passwords: vec![],
+        }
+    }
+
+    /// args sets the arguments kla was called with, the values of secrets are
+    /// redacted when an entry is made
+    pub fn args<I: IntoIterator<Item = String>>(mut self, args: I) -> Self {
+        self.args = args.into_iter().collect();
+        self
+    }
+
+    /// passwords sets the template's password args, their values are redacted from
+    /// the arguments of the template
+    pub fn passwords<I: IntoIterator<Item = PasswordArg>>(mut self, passwords: I) -> Self {
+        self.passwords = passwords.into_iter().collect();
+        self
+    }
+
+    /// entry starts an entry for the request, call `HistoryEntry::finish` once the
+    /// response arrives and `record` it. Secrets are redacted from the url, headers
+    /// and arguments, even when they are shown in the output.
+    pub fn entry(&self, request: &Request, secrets: &Secrets) -> HistoryEntry {
+        let secrets = secrets.clone().show(false);
+        HistoryEntry {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            args: redact_args(&self.args, &secrets, &self.passwords),
+            method: request.method().to_string(),
+            url: secrets.url(request.url()).to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), secrets.header(name.as_str(), value)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// record appends the entry to the history, numbering it after the last entry.
+    /// The file is locked while it's numbered and written, so kla running in
+    /// parallel doesn't record the same id twice.
+    pub fn record(&self, entry: HistoryEntry) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).create(true).append(true);
+        // the history is only readable by the user, arguments may hold secrets. A
+        // history written before keeps its mode unless it's set again.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.lock()?;
+
+        let (id, ended) = last_id(&mut file)?;
+        let mut entry = entry;
+        entry.id = id + 1;
+
+        // a line cut short by an earlier failed write is ended first
+        let mut line = match ended {
+            true => String::new(),
+            false => String::from("\n"),
+        };
+        line.push_str(&serde_json::to_string(&entry)?);
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// entries returns every entry of the history, oldest first. Lines which
+    /// aren't entries, like one cut short, are skipped.
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// get returns the entry with the id
+    pub fn get(&self, id: u64) -> Result<HistoryEntry> {
+        self.entries()?
+            .into_iter()
+            .find(|e| e.id == id)
+            .ok_or_else(|| Error::from(format!("there is no history entry {}", id)))
+    }
+}
+
+impl HistoryEntry {
+    /// environment sets the environment the request was sent to
+    pub fn environment(mut self, environment: Option<&String>) -> Self {
+        self.environment = environment.cloned();
+        self
+    }
+
+    /// template sets the template the request was built from
+    pub fn template<S: Into<String>>(mut self, template: S) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    /// finish sets the outcome of the request
+    pub fn finish(mut self, status: StatusCode, duration: Duration) -> Self {
+        self.status = status.as_u16();
+        self.duration_ms = duration.as_millis() as u64;
+        self
+    }
+
+    /// replayable returns an error when the arguments can't be replayed, because a
+    /// secret was redacted from them
+    pub fn replayable(&self) -> Result<&[String]> {
+        match self.args.iter().any(|arg| arg.contains(REDACTED)) {
+            true => Err(Error::from(format!(
+                "history entry {} holds a redacted secret, run it again with the secret or pass it with @file or cmd:",
+                self.id
+            ))),
+            false => Ok(&self.args),
+        }
+    }
+
+    /// local_time returns the time of the request in the local timezone
+    fn local_time(&self) -> String {
+        DateTime::parse_from_rfc3339(&self.time)
+            .map(|t| {
+                t.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| self.time.clone())
+    }
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>5}  {}  {}  {}  {} {}",
+            self.id,
+            self.local_time(),
+            self.environment.as_deref().unwrap_or("-"),
+            self.status,
+            self.method,
+            self.url
+        )?;
+        if let Some(template) = self.template.as_ref() {
+            write!(f, "  (run {})", template)?;
+        }
+        Ok(())
+    }
+}
+
+impl SkimItem for HistoryEntry {
+    fn text(&self) -> Cow<'_, str> {
+        Cow::from(self.to_string())
+    }
+
+    fn preview(&self, _context: skim::PreviewContext) -> skim::ItemPreview {
+        let mut s = String::new();
+        writeln!(s, "{} {}", self.method, self.url).expect("writing to string");
+        writeln!(s, "{} in {}ms", self.status, self.duration_ms).expect("writing to string");
+        writeln!(s, "\nkla {}", self.args.join(" ")).expect("writing to string");
+        writeln!(s, "\nHeaders").expect("writing to string");
+        for (name, value) in &self.headers {
+            writeln!(s, "\t{}: {}", name, value).expect("writing to string");
+        }
+
+        skim::ItemPreview::Text(s)
+    }
+}
+
+/// last_id returns the highest id at the end of the history, or 0 for an empty
+/// history, and whether the history ends with a whole line. Only the tail of the
+/// file is read, so recording stays fast however long the history grows.
+fn last_id(file: &mut fs::File) -> Result<(u64, bool)> {
+    let length = file.metadata()?.len();
+    file.seek(SeekFrom::Start(length.saturating_sub(HISTORY_TAIL)))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+
+    let id = String::from_utf8_lossy(&tail)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok())
+        .map(|entry| entry.id)
+        .max()
+        .unwrap_or_default();
+    Ok((id, tail.last().is_none_or(|c| *c == b'\n')))
+}
+
+/// redact_args redacts the values of secret flags, of headers and query parameters
+/// holding secrets, the secret query parameters of urls and the passwords. Secrets
+/// read from a file, standard in or a command are kept, as they hold no secret.
+fn redact_args(args: &[String], secrets: &Secrets, passwords: &[PasswordArg]) -> Vec<String> {
+    let redact = |flag: &str, value: &str| -> String {
+        if HEADER_FLAGS.contains(&flag) {
+            let name = value.split(['=', ':']).next().unwrap_or_default().trim();
+            return match secrets.is_secret_header_name(name) {
+                true => format!("{}={}", name, REDACTED),
+                false => value.to_string(),
+            };
+        }
+        if QUERY_FLAGS.contains(&flag) {
+            return redact_pair(value, secrets);
+        }
+        match value == "-" || value.starts_with('@') || value.starts_with("cmd:") {
+            true => value.to_string(),
+            false => REDACTED.to_string(),
+        }
+    };
+    let secret = |flag: &str| {
+        SECRET_FLAGS.contains(&flag) || HEADER_FLAGS.contains(&flag) || QUERY_FLAGS.contains(&flag)
+    };
+
+    let mut redacted = Vec::with_capacity(args.len());
+    let args = redact_passwords(args, passwords);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let short = arg
+            .get(..2)
+            .filter(|flag| !arg.starts_with("--") && secret(flag));
+        match (arg.split_once('='), short) {
+            // --flag=value
+            (Some((flag, value)), _) if flag.starts_with("--") && secret(flag) => {
+                redacted.push(format!("{}={}", flag, redact(flag, value)))
+            }
+            // -Fvalue or -F=value
+            (_, Some(flag)) if arg.len() > 2 => {
+                let value = &arg[2..];
+                let (eq, value) = match value.strip_prefix('=') {
+                    Some(value) => ("=", value),
+                    None => ("", value),
+                };
+                redacted.push(format!("{}{}{}", flag, eq, redact(flag, value)))
+            }
+            // --flag value
+            _ if secret(&arg) => {
+                let value = args.next().map(|value| redact(&arg, &value));
+                redacted.push(arg);
+                redacted.extend(value);
+            }
+            // urls, their secret query parameters are redacted
+            _ if !arg.starts_with('-') && arg.contains('?') => {
+                redacted.push(redact_url(&arg, secrets))
+            }
+            _ => redacted.push(arg),
+        }
+    }
+
+    redacted
+}
+
+/// redact_passwords redacts the values of the password args from the arguments of
+/// the template, those following `run <template>`. Values are redacted where they
+/// are given to the flags of a password arg, `--long value`, `--long=value`,
+/// `-svalue` or `-s value`, and where they are a positional equal to a password.
+fn redact_passwords(args: &[String], passwords: &[PasswordArg]) -> Vec<String> {
+    let start = args
+        .iter()
+        .position(|arg| arg == "run" || arg == "template")
+        .map_or(args.len(), |i| i + 2);
+    let mut redacted: Vec<String> = args.iter().take(start).cloned().collect();
+    let passwords: Vec<&PasswordArg> = passwords.iter().filter(|p| !p.value.is_empty()).collect();
+    let long = |flag: &str| passwords.iter().any(|p| p.longs.iter().any(|l| l == flag));
+    let short = |flag: char| passwords.iter().any(|p| p.shorts.contains(&flag));
+
+    let mut args = args.iter().skip(start);
+    while let Some(arg) = args.next() {
+        if let Some(flag) = arg.strip_prefix("--") {
+            match flag.split_once('=') {
+                // --long=value
+                Some((flag, _)) if long(flag) => {
+                    redacted.push(format!("--{}={}", flag, REDACTED));
+                }
+                // --long value
+                None if long(flag) => {
+                    redacted.push(arg.clone());
+                    redacted.extend(args.next().map(|_| REDACTED.to_string()));
+                }
+                _ => redacted.push(arg.clone()),
+            }
+            continue;
+        }
+
+        let mut chars = arg.chars();
+        match (chars.next(), chars.next()) {
+            // -svalue, -s=value or -s value
+            (Some('-'), Some(flag)) if short(flag) => {
+                let value = chars.as_str();
+                match value.strip_prefix('=') {
+                    Some(_) => redacted.push(format!("-{}={}", flag, REDACTED)),
+                    None if !value.is_empty() => redacted.push(format!("-{}{}", flag, REDACTED)),
+                    None => {
+                        redacted.push(arg.clone());
+                        redacted.extend(args.next().map(|_| REDACTED.to_string()));
+                    }
+                }
+            }
+            _ if passwords.iter().any(|p| p.value == *arg) => redacted.push(REDACTED.into()),
+            _ => redacted.push(arg.clone()),
+        }
+    }
+
+    redacted
+}
+
+/// redact_url redacts the secret query parameters of a url, the url is left as it
+/// is otherwise, as it may still be a template
+fn redact_url(url: &str, secrets: &Secrets) -> String {
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    let (path, query) = url.split_once('?').expect("urls with a query");
+
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| redact_pair(pair, secrets))
+        .collect();
+    let mut url = format!("{}?{}", path, query.join("&"));
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+/// redact_pair redacts the value of a name=value query parameter holding a secret
+fn redact_pair(pair: &str, secrets: &Secrets) -> String {
+    match pair.split_once('=') {
+        Some((name, _)) if secrets.is_secret_query(name.trim()) => {
+            format!("{}={}", name, REDACTED)
+        }
+        _ => pair.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redacted(args: &[&str]) -> Vec<String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let password = PasswordArg {
+            value: "hunter2".into(),
+            longs: vec!["password".into()],
+            shorts: vec!['p'],
+        };
+        redact_args(&args, &Secrets::default(), &[password])
+    }
+
+    #[test]
+    fn secret_flags() {
+        assert_eq!(
+            redacted(&["--bearer-token", "abc", "--basic-auth=me:pw", "/users"]),
+            [
+                "--bearer-token",
+                REDACTED,
+                "--basic-auth=REDACTED",
+                "/users"
+            ]
+        );
+        assert_eq!(
+            redacted(&["--bearer-token", "@token", "--basic-auth", "cmd:pass kla"]),
+            ["--bearer-token", "@token", "--basic-auth", "cmd:pass kla"]
+        );
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            redacted(&[
+                "-H",
+                "Authorization: Bearer abc",
+                "-HX-Api-Key:abc",
+                "--header=Cookie: a=b",
+                "-HAccept: text/plain",
+            ]),
+            [
+                "-H",
+                "Authorization=REDACTED",
+                "-HX-Api-Key=REDACTED",
+                "--header=Cookie=REDACTED",
+                "-HAccept: text/plain",
+            ]
+        );
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(
+            redacted(&[
+                "-Q",
+                "api_key=abc",
+                "-Qtoken=abc",
+                "--query=page=2",
+                "-Q=password=x"
+            ]),
+            [
+                "-Q",
+                "api_key=REDACTED",
+                "-Qtoken=REDACTED",
+                "--query=page=2",
+                "-Q=password=REDACTED"
+            ]
+        );
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            redacted(&[
+                "get",
+                "/users?token=abc&page=2#top",
+                "https://x.io/?API_KEY=1"
+            ]),
+            [
+                "get",
+                "/users?token=REDACTED&page=2#top",
+                "https://x.io/?API_KEY=REDACTED"
+            ]
+        );
+    }
+
+    #[test]
+    fn short_passwords() {
+        let password = PasswordArg {
+            value: "2".into(),
+            longs: vec!["pin".into()],
+            shorts: vec!['p'],
+        };
+        let args: Vec<String> = [
+            "--retry=2",
+            "-o=out2",
+            "--retry",
+            "2",
+            "run",
+            "login",
+            "--pin",
+            "2",
+            "--pin=2",
+            "-p2",
+            "-p",
+            "2",
+            "2",
+            "12",
+            "--count=2",
+            "-o=out2",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+        let redacted = redact_args(&args, &Secrets::default(), &[password]);
+        assert_eq!(
+            redacted,
+            [
+                "--retry=2",
+                "-o=out2",
+                "--retry",
+                "2",
+                "run",
+                "login",
+                "--pin",
+                REDACTED,
+                "--pin=REDACTED",
+                "-pREDACTED",
+                "-p",
+                REDACTED,
+                REDACTED,
+                "12",
+                "--count=2",
+                "-o=out2"
+            ]
+        );
+    }
+
+    #[test]
+    fn passwords() {
+        assert_eq!(
+            redacted(&[
+                "run",
+                "login",
+                "--password",
+                "hunter2",
+                "-phunter2",
+                "hunter2",
+                "me"
+            ]),
+            [
+                "run",
+                "login",
+                "--password",
+                REDACTED,
+                "-pREDACTED",
+                REDACTED,
+                "me"
+            ]
+        );
+    }
+
+    #[test]
+    fn record_is_private() {
+        let dir = std::env::temp_dir().join(format!("kla-history-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let history = History::new(path.to_str().unwrap()).args(["/users?token=abc".into()]);
+        let request = Request::new(
+            reqwest::Method::GET,
+            "http://localhost/users?token=abc".parse().unwrap(),
+        );
+
+        for _ in 0..2 {
+            let entry = history
+                .entry(&request, &Secrets::default().show(true))
+                .finish(StatusCode::OK, Duration::from_millis(5));
+            history.record(entry).unwrap();
+        }
+
+        let entries = history.entries().unwrap();
+        assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(entries[0].args, ["/users?token=REDACTED"]);
+        assert_eq!(entries[0].url, "http://localhost/users?token=REDACTED");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn record_skips_broken_lines() {
+        let dir = std::env::temp_dir().join(format!("kla-history-broken-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            "{\"id\":7,\"time\":\"\",\"environment\":null,\"template\":null,\"args\":[],\"method\":\"GET\",\"url\":\"http://x\",\"headers\":[],\"status\":200,\"duration_ms\":1}\n{\"id\":8,\"ti",
+        )
+        .unwrap();
+
+        let history = History::new(path.to_str().unwrap());
+        let request = Request::new(reqwest::Method::GET, "http://localhost/".parse().unwrap());
+        history
+            .record(history.entry(&request, &Secrets::default()))
+            .unwrap();
+
+        let entries = history.entries().unwrap();
+        assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), [7, 8]);
+        assert_eq!(history.get(8).unwrap().url, "http://localhost/");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parallel_records_get_their_own_id() {
+        let dir = std::env::temp_dir().join(format!("kla-history-parallel-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let history = History::new(path.to_str().unwrap());
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let request =
+                        Request::new(reqwest::Method::GET, "http://localhost/".parse().unwrap());
+                    for _ in 0..10 {
+                        history
+                            .record(history.entry(&request, &Secrets::default()))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut ids = history
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (1..=80).collect::<Vec<_>>());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
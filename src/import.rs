@@ -0,0 +1,1350 @@
+use std::{collections::HashSet, fmt::Display, fs, path::PathBuf};
+
+use base64::prelude::*;
+use log::{debug, warn};
+use serde_json::Value;
+use url::Url;
+
+use crate::{CurlRequest, Error, Expand, Result, SENSITIVE_HEADERS};
+
+/// BROWSER_HEADERS are added by browsers (and curl) to every request, they are
+/// left out of imported templates unless asked for
+const BROWSER_HEADERS: &[&str] = &[
+    "accept-encoding",
+    "accept-language",
+    "cache-control",
+    "connection",
+    "content-length",
+    "dnt",
+    "host",
+    "origin",
+    "pragma",
+    "priority",
+    "referer",
+    "te",
+    "upgrade-insecure-requests",
+    "user-agent",
+];
+
+/// BROWSER_HEADER_PREFIXES are prefixes of headers added by browsers
+const BROWSER_HEADER_PREFIXES: &[&str] = &["sec-ch-", "sec-fetch-", "sec-gpc"];
+
+/// KLA_FLAGS are the long flags of kla itself. Imported args named after them are
+/// suffixed, so `--query` of a template is never mistaken for kla's `--query`
+pub const KLA_FLAGS: &[&str] = &[
+    "agent",
+    "as-curl",
+    "basic-auth",
+    "bearer-token",
+    "certificate",
+    "client-cert",
+    "client-key",
+    "client-pkcs12",
+    "client-pkcs12-password",
+    "connect-timeout",
+    "cookie",
+    "cookie-jar",
+    "digest-auth",
+    "dry",
+    "env",
+    "failure-template",
+    "form",
+    "har",
+    "header",
+    "help",
+    "http-version",
+    "max-redirects",
+    "multipart",
+    "no-brotli",
+    "no-deflate",
+    "no-gzip",
+    "no-redirects",
+    "no-system-roots",
+    "output",
+    "output-failure",
+    "proxy",
+    "proxy-auth",
+    "proxy-http",
+    "proxy-https",
+    "query",
+    "retry",
+    "retry-all-methods",
+    "retry-connection-errors",
+    "retry-delay",
+    "retry-on",
+    "show-secrets",
+    "sigv4",
+    "sigv4-aws-profile",
+    "sigv4-service",
+    "template",
+    "timeout",
+    "verbose",
+    "version",
+];
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+#[derive(Clone, Debug, Default)]
+/// ImportKV is a `[[header]]`, `[[query]]`, `[[form]]` or `[[multipart]]` entry
+/// of an imported template
+pub struct ImportKV {
+    pub name: String,
+    pub value: String,
+    pub when: Option<String>,
+    pub secret: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+/// ImportArg is an `[[arg]]` of an imported template
+pub struct ImportArg {
+    pub name: String,
+    pub arg_type: Option<String>,
+    pub long: Option<String>,
+    pub help: Option<String>,
+    pub required: bool,
+    pub many_valued: bool,
+    pub action: Option<String>,
+    pub default_value: Option<String>,
+    pub possible_values: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Placeholder is a literal value of an imported template which may be turned
+/// into an `[[arg]]`
+pub enum Placeholder {
+    /// Segment is the index of a segment of the uri
+    Segment(usize),
+    Query(usize),
+    Form(usize),
+    Multipart(usize),
+    Header(usize),
+    /// Body is a top level key of a JSON object body
+    Body(String),
+}
+
+#[derive(Clone, Debug)]
+/// Candidate is a placeholder along with a description of the value it replaces
+pub struct Candidate {
+    pub placeholder: Placeholder,
+    label: String,
+}
+
+impl Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Import is a template built from another description of a request, like a curl
+/// command. It's written out as the TOML of a template.
+pub struct Import {
+    pub name: String,
+    pub short_description: Option<String>,
+    pub description: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub body: Option<String>,
+    pub args: Vec<ImportArg>,
+    pub header: Vec<ImportKV>,
+    pub query: Vec<ImportKV>,
+    pub form: Vec<ImportKV>,
+    pub multipart: Vec<ImportKV>,
+
+    // json holds the body when it's a JSON object, so its values can become args
+    json: Option<serde_json::Map<String, Value>>,
+}
+
+impl Import {
+    /// from_curl creates the template from a curl command. The prefix of the
+    /// environment is stripped from the url, browser headers are dropped unless
+    /// keep_headers is set.
+    pub fn from_curl(
+        request: &CurlRequest,
+        prefix: Option<&str>,
+        keep_headers: bool,
+    ) -> Result<Self> {
+        let url =
+            Url::parse(&request.url).or_else(|_| Url::parse(&format!("http://{}", request.url)))?;
+
+        let mut import = Import {
+            method: request.method.clone(),
+            uri: strip_prefix(&url, prefix),
+            ..Default::default()
+        };
+
+        import.query = url
+            .query_pairs()
+            .map(|(name, value)| ImportKV {
+                name: name.into(),
+                value: raw(&value),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut content_type = None;
+        for (name, value) in &request.headers {
+            let lower = name.to_lowercase();
+            if lower == "content-type" {
+                content_type = Some(value.to_lowercase());
+            }
+
+            let browser = BROWSER_HEADERS.contains(&lower.as_str())
+                || BROWSER_HEADER_PREFIXES.iter().any(|p| lower.starts_with(p));
+            if browser && !keep_headers {
+                continue;
+            }
+
+            import.header.push(ImportKV {
+                name: name.clone(),
+                value: raw(value),
+                secret: SENSITIVE_HEADERS.contains(&lower.as_str()),
+                ..Default::default()
+            });
+        }
+
+        if let Some(user) = request.user.as_ref() {
+            import.header.push(ImportKV {
+                name: "Authorization".into(),
+                value: format!("Basic {}", BASE64_STANDARD.encode(user)),
+                secret: true,
+                ..Default::default()
+            });
+        }
+
+        for header in import.header.iter().filter(|h| h.secret) {
+            warn!(
+                "the template holds the value of the {} header, consider moving it to the environment",
+                header.name
+            );
+        }
+
+        if !request.multipart.is_empty() {
+            import.multipart = request
+                .multipart
+                .iter()
+                .map(|(name, value)| ImportKV {
+                    name: name.clone(),
+                    value: raw(value),
+                    ..Default::default()
+                })
+                .collect();
+        }
+
+        if let Some(data) = request.data.as_ref() {
+            if data.starts_with('@') {
+                warn!(
+                    "curl reads the body from {}, it is not part of the template",
+                    data
+                );
+            }
+
+            let form = match content_type.as_deref() {
+                Some(content_type) => content_type.starts_with(FORM_CONTENT_TYPE),
+                None => is_form(data),
+            };
+
+            match form && is_form(data) {
+                true => {
+                    // the form sets its own content type
+                    import
+                        .header
+                        .retain(|h| !h.name.eq_ignore_ascii_case("content-type"));
+                    import
+                        .form
+                        .extend(url::form_urlencoded::parse(data.as_bytes()).map(
+                            |(name, value)| ImportKV {
+                                name: name.into(),
+                                value: raw(&value),
+                                ..Default::default()
+                            },
+                        ));
+                }
+                false => {
+                    // curl sends data as a form unless told otherwise
+                    if content_type.is_none() {
+                        import.header.push(ImportKV {
+                            name: "Content-Type".into(),
+                            value: FORM_CONTENT_TYPE.into(),
+                            ..Default::default()
+                        });
+                    }
+                    import.json = match serde_json::from_str(data) {
+                        Ok(Value::Object(json)) => Some(json),
+                        _ => None,
+                    };
+                    import.body = Some(raw(data));
+                }
+            }
+        }
+
+        import.name = default_name(&import.method, &import.uri);
+        import.short_description = Some(format!("{} {}", import.method, url.path()));
+
+        Ok(import)
+    }
+
+    /// candidates returns the literal values which could become args: ids in the
+    /// path, query, form and header values and the top level values of a JSON body
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates = vec![];
+
+        for (i, segment) in self.uri.split('/').enumerate() {
+            if is_id(segment) {
+                candidates.push(Candidate {
+                    placeholder: Placeholder::Segment(i),
+                    label: format!("path segment {}", segment),
+                });
+            }
+        }
+
+        let kvs = [
+            ("query", &self.query, Placeholder::Query as fn(usize) -> _),
+            ("form", &self.form, Placeholder::Form),
+            ("multipart", &self.multipart, Placeholder::Multipart),
+            ("header", &self.header, Placeholder::Header),
+        ];
+        for (kind, kvs, placeholder) in kvs {
+            for (i, kv) in kvs.iter().enumerate() {
+                candidates.push(Candidate {
+                    placeholder: placeholder(i),
+                    label: format!("{} {}={}", kind, kv.name, kv.value),
+                });
+            }
+        }
+
+        for (key, value) in self.json.iter().flatten() {
+            if !value.is_array() && !value.is_object() && !value.is_null() {
+                candidates.push(Candidate {
+                    placeholder: Placeholder::Body(key.clone()),
+                    label: format!("body {}={}", key, value),
+                });
+            }
+        }
+
+        candidates
+    }
+
+    /// apply turns the values of the placeholders into args, the original values
+    /// become the defaults
+    pub fn apply(&mut self, placeholders: &[Placeholder]) {
+        let mut names = reserved();
+        names.extend(self.args.iter().map(|a| a.name.clone()));
+        let mut unique = |name: String| unique(&mut names, name);
+
+        let mut segments: Vec<String> = self.uri.split('/').map(String::from).collect();
+        let mut json = self.json.clone();
+
+        for placeholder in placeholders {
+            match placeholder {
+                Placeholder::Segment(i) => {
+                    let previous = segments[..*i]
+                        .iter()
+                        .rev()
+                        .find(|s| !s.is_empty() && !s.contains("{{"));
+                    let name = unique(match previous {
+                        Some(previous) => {
+                            format!("{}_id", identifier(previous.trim_end_matches('s')))
+                        }
+                        None => "id".into(),
+                    });
+                    self.args.push(ImportArg {
+                        help: Some(format!("The {} in the path, e.g. {}", name, segments[*i])),
+                        name: name.clone(),
+                        required: true,
+                        ..Default::default()
+                    });
+                    segments[*i] = format!("{{{{ {} }}}}", name);
+                }
+                Placeholder::Query(i)
+                | Placeholder::Form(i)
+                | Placeholder::Multipart(i)
+                | Placeholder::Header(i) => {
+                    let kv = match placeholder {
+                        Placeholder::Query(_) => &mut self.query[*i],
+                        Placeholder::Form(_) => &mut self.form[*i],
+                        Placeholder::Multipart(_) => &mut self.multipart[*i],
+                        _ => &mut self.header[*i],
+                    };
+                    let name = unique(identifier(&kv.name));
+                    self.args.push(ImportArg {
+                        long: Some(name.replace('_', "-")),
+                        help: Some(format!("The value of {}", kv.name)),
+                        default_value: Some(kv.value.clone()),
+                        name: name.clone(),
+                        ..Default::default()
+                    });
+                    kv.value = format!("{{{{ {} }}}}", name);
+                }
+                Placeholder::Body(key) => {
+                    let json = match json.as_mut() {
+                        Some(json) => json,
+                        None => continue,
+                    };
+                    let value = match json.get(key) {
+                        Some(value) => value.clone(),
+                        None => continue,
+                    };
+                    let name = unique(identifier(key));
+                    self.args.push(ImportArg {
+                        long: Some(name.replace('_', "-")),
+                        help: Some(format!("The {} of the body", key)),
+                        default_value: Some(match &value {
+                            Value::String(v) => v.clone(),
+                            v => v.to_string(),
+                        }),
+                        name: name.clone(),
+                        ..Default::default()
+                    });
+                    json.insert(key.clone(), marker(&name, value.is_string()).into());
+                }
+            }
+        }
+
+        self.uri = segments.join("/");
+
+        if let (Some(json), true) = (
+            json,
+            placeholders
+                .iter()
+                .any(|p| matches!(p, Placeholder::Body(_))),
+        ) {
+            let mut body = serde_json::to_string_pretty(&json).expect("json values serialize");
+            for arg in &self.args {
+                for string in [true, false] {
+                    let marker = Value::from(marker(&arg.name, string)).to_string();
+                    let expression = match string {
+                        true => format!("{{{{ {} | json_encode() }}}}", arg.name),
+                        false => format!("{{{{ {} }}}}", arg.name),
+                    };
+                    body = body.replace(&marker, &expression);
+                }
+            }
+            self.body = Some(body);
+        }
+    }
+
+    /// to_toml returns the template as TOML
+    pub fn to_toml(&self) -> String {
+        let mut toml = String::new();
+
+        field(&mut toml, "", "short_description", &self.short_description);
+        field(&mut toml, "", "description", &self.description);
+        if self.method != "GET" {
+            field(&mut toml, "", "method", &Some(&self.method));
+        }
+        field(&mut toml, "", "uri", &Some(&self.uri));
+        if let Some(body) = self.body.as_ref() {
+            match body.contains('\n') && !body.contains("'''") {
+                true => toml.push_str(&format!("body = '''\n{}'''\n", body)),
+                false => field(&mut toml, "", "body", &Some(body)),
+            }
+        }
+
+        for arg in &self.args {
+            toml.push_str("\n[[arg]]\n");
+            field(&mut toml, "  ", "name", &Some(&arg.name));
+            field(&mut toml, "  ", "type", &arg.arg_type);
+            field(&mut toml, "  ", "long", &arg.long);
+            field(&mut toml, "  ", "help", &arg.help);
+            if arg.required {
+                toml.push_str("  required = true\n");
+            }
+            if arg.many_valued {
+                toml.push_str("  many_valued = true\n");
+            }
+            field(&mut toml, "  ", "action", &arg.action);
+            field(&mut toml, "  ", "default_value", &arg.default_value);
+            if !arg.possible_values.is_empty() {
+                let values: Vec<String> = arg.possible_values.iter().map(|v| quote(v)).collect();
+                toml.push_str(&format!("  possible_values = [{}]\n", values.join(", ")));
+            }
+        }
+
+        for (section, kvs) in [
+            ("header", &self.header),
+            ("query", &self.query),
+            ("form", &self.form),
+            ("multipart", &self.multipart),
+        ] {
+            for kv in kvs {
+                toml.push_str(&format!("\n[[{}]]\n", section));
+                field(&mut toml, "  ", "name", &Some(&kv.name));
+                field(&mut toml, "  ", "value", &Some(&kv.value));
+                field(&mut toml, "  ", "when", &kv.when);
+                if kv.secret {
+                    toml.push_str("  secret = true\n");
+                }
+            }
+        }
+
+        toml
+    }
+
+    /// write writes the template into the template directory, an existing template
+    /// is only replaced when force is set
+    pub fn write(&self, template_dir: &str, force: bool) -> Result<PathBuf> {
+        let mut path = PathBuf::from(template_dir.shell_expansion());
+        fs::create_dir_all(&path)?;
+        path.push(format!("{}.toml", self.name));
+
+        if path.exists() && !force {
+            return Err(Error::from(format!(
+                "{} already exists, pick another name or overwrite it with --force",
+                path.display()
+            )));
+        }
+
+        fs::write(&path, self.to_toml())?;
+        Ok(path)
+    }
+}
+
+/// METHODS are the operations a path of an OpenAPI spec may have
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// IGNORED_HEADERS are header parameters OpenAPI ignores, they are described elsewhere
+/// in the spec
+const IGNORED_HEADERS: &[&str] = &["accept", "content-type", "authorization"];
+
+/// the longest chain of `$ref`s followed before giving up
+const MAX_REFS: usize = 32;
+
+impl Import {
+    /// from_openapi creates a template for every operation of an OpenAPI 3 spec
+    pub fn from_openapi(spec: &Value) -> Result<Vec<Self>> {
+        let version = spec
+            .get("openapi")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if !version.starts_with('3') {
+            return Err(Error::from("only OpenAPI 3 specs can be imported"));
+        }
+
+        let paths = spec
+            .get("paths")
+            .and_then(Value::as_object)
+            .ok_or_else(|| Error::from("the spec has no paths"))?;
+
+        let mut imports = vec![];
+        let mut names = HashSet::new();
+        for (path, item) in paths {
+            let item = resolve(spec, item)?;
+            let shared = item
+                .get("parameters")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            for method in METHODS {
+                let operation = match item.get(*method) {
+                    Some(operation) => operation,
+                    None => continue,
+                };
+
+                let mut import = Import::from_operation(spec, path, method, operation, shared)?;
+                import.name = unique(&mut names, import.name).replace('_', "-");
+                imports.push(import);
+            }
+        }
+
+        Ok(imports)
+    }
+
+    /// from_operation creates the template of a single operation. Parameters of the
+    /// path are shared by all its operations, unless the operation overrides them.
+    fn from_operation(
+        spec: &Value,
+        path: &str,
+        method: &str,
+        operation: &Value,
+        shared: &[Value],
+    ) -> Result<Self> {
+        let text = |key: &str| operation.get(key).and_then(Value::as_str).map(str::trim);
+        let method = method.to_uppercase();
+
+        let mut import = Import {
+            name: match text("operationId") {
+                Some(id) => identifier(id).replace('_', "-"),
+                None => default_name(&method, path),
+            },
+            short_description: text("summary")
+                .or_else(|| text("description").and_then(|d| d.lines().next()))
+                .map(String::from),
+            description: text("description").map(String::from),
+            method,
+            ..Default::default()
+        };
+
+        // the operation's parameters replace the shared ones with the same name
+        let mut parameters: Vec<&Value> = vec![];
+        let own = operation
+            .get("parameters")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for parameter in shared.iter().chain(own) {
+            let parameter = resolve(spec, parameter)?;
+            parameters.retain(|p| {
+                p.get("name") != parameter.get("name") || p.get("in") != parameter.get("in")
+            });
+            parameters.push(parameter);
+        }
+
+        let mut names = reserved();
+        let mut uri = path.to_string();
+        let mut positional = vec![];
+
+        for parameter in parameters {
+            let name = parameter
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| {
+                    Error::from(format!(
+                        "{} {} has a parameter without a name",
+                        import.method, path
+                    ))
+                })?;
+            let location = parameter
+                .get("in")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if location == "header" && IGNORED_HEADERS.contains(&name.to_lowercase().as_str()) {
+                continue;
+            }
+
+            let schema = resolve(spec, parameter.get("schema").unwrap_or(&Value::Null))?;
+            let required = parameter
+                .get("required")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            let ident = unique(&mut names, identifier(name));
+            let mut arg = schema_arg(&ident, parameter, schema, required);
+
+            let value = match arg.many_valued {
+                true => format!("{{{{ {} | join(sep=\",\") }}}}", ident),
+                false => format!("{{{{ {} }}}}", ident),
+            };
+            let kv = ImportKV {
+                name: name.into(),
+                value,
+                when: (!required).then(|| format!("{{% if {} %}}yes{{% endif %}}", ident)),
+                ..Default::default()
+            };
+
+            match location {
+                "path" => {
+                    uri = uri.replace(&format!("{{{}}}", name), &format!("{{{{ {} }}}}", ident));
+                    arg.required = true;
+                    arg.long = None;
+                    positional.push(arg);
+                }
+                "query" => {
+                    import.query.push(kv);
+                    import.args.push(arg);
+                }
+                "header" => {
+                    import.header.push(kv);
+                    import.args.push(arg);
+                }
+                location => debug!(
+                    "{} parameter {} of {} {} is not imported",
+                    location, name, import.method, path
+                ),
+            }
+        }
+
+        // positional args are numbered in the order they are defined
+        positional.append(&mut import.args);
+        import.args = positional;
+        import.uri = uri;
+
+        if let Some(body) = operation.get("requestBody") {
+            import.request_body(spec, resolve(spec, body)?, &mut names)?;
+        }
+
+        Ok(import)
+    }
+
+    /// request_body turns the request body into the `body` of the template. The
+    /// properties of JSON and form bodies become args, other bodies are taken as a
+    /// whole from the `body` arg.
+    fn request_body(
+        &mut self,
+        spec: &Value,
+        body: &Value,
+        names: &mut HashSet<String>,
+    ) -> Result<()> {
+        let content = match body.get("content").and_then(Value::as_object) {
+            Some(content) => content,
+            None => return Ok(()),
+        };
+
+        let (media_type, media) = match content
+            .iter()
+            .find(|(m, _)| is_json(m))
+            .or_else(|| content.iter().next())
+        {
+            Some(media) => media,
+            None => return Ok(()),
+        };
+
+        let schema = resolve(spec, media.get("schema").unwrap_or(&Value::Null))?;
+        let required: HashSet<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+
+        let mut properties = vec![];
+        for (name, property) in schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            let property = resolve(spec, property)?;
+            if property
+                .get("readOnly")
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            let ident = unique(names, identifier(name));
+            let arg = schema_arg(&ident, property, property, required.contains(name.as_str()));
+            properties.push((name, property, arg));
+        }
+
+        let multipart = media_type == "multipart/form-data";
+        let form = media_type == FORM_CONTENT_TYPE || multipart;
+        match (properties.is_empty(), is_json(media_type), form) {
+            (false, true, _) => {
+                let optional = properties.iter().any(|(_, _, arg)| !arg.required);
+                let mut lines = vec![];
+                for (name, property, arg) in &properties {
+                    let expression = match property.get("type").and_then(Value::as_str) {
+                        Some("string") => format!("{{{{ {} | json_encode() }}}}", arg.name),
+                        _ => format!("{{{{ {} }}}}", arg.name),
+                    };
+                    let line = format!("  {}: {}", Value::from(name.as_str()), expression);
+                    lines.push(match (optional, arg.required) {
+                        (false, _) => line,
+                        (true, true) => format!("{{{{- sep }}}}\n{}{{% set sep = \",\" %}}", line),
+                        (true, false) => format!(
+                            "{{%- if {} %}}{{{{ sep }}}}\n{}{{% set sep = \",\" %}}{{% endif %}}",
+                            condition(arg),
+                            line
+                        ),
+                    });
+                }
+
+                self.body = Some(match optional {
+                    false => format!("{{\n{}\n}}", lines.join(",\n")),
+                    // commas are only added between the properties that are present
+                    true => format!("{{% set sep = \"\" -%}}\n{{\n{}\n}}", lines.join("\n")),
+                });
+                self.header.push(ImportKV {
+                    name: "Content-Type".into(),
+                    value: media_type.clone(),
+                    ..Default::default()
+                });
+            }
+            (false, _, true) => {
+                for (name, property, arg) in properties.iter_mut() {
+                    // binary properties of multipart bodies are files, uploaded from
+                    // the path given to the arg
+                    let file = multipart
+                        && property.get("format").and_then(Value::as_str) == Some("binary");
+                    if file {
+                        arg.help = Some(match arg.help.take() {
+                            Some(help) => format!("The path of the file to upload. {}", help),
+                            None => "The path of the file to upload".into(),
+                        });
+                    }
+
+                    let kv = ImportKV {
+                        name: name.to_string(),
+                        value: format!("{}{{{{ {} }}}}", if file { "@" } else { "" }, arg.name),
+                        when: (!arg.required)
+                            .then(|| format!("{{% if {} %}}yes{{% endif %}}", arg.name)),
+                        ..Default::default()
+                    };
+                    match multipart {
+                        true => self.multipart.push(kv),
+                        false => self.form.push(kv),
+                    }
+                }
+            }
+            _ => {
+                let name = unique(names, "body".into());
+                self.args.push(ImportArg {
+                    help: Some(format!("The {} body of the request", media_type)),
+                    long: Some(name.replace('_', "-")),
+                    required: body
+                        .get("required")
+                        .and_then(Value::as_bool)
+                        .unwrap_or(false),
+                    name: name.clone(),
+                    ..Default::default()
+                });
+                self.body = Some(format!("{{{{ {} }}}}", name));
+                self.header.push(ImportKV {
+                    name: "Content-Type".into(),
+                    value: media_type.clone(),
+                    ..Default::default()
+                });
+                return Ok(());
+            }
+        }
+
+        self.args
+            .extend(properties.into_iter().map(|(_, _, arg)| arg));
+        Ok(())
+    }
+}
+
+/// schema_arg creates the arg of a parameter or property. Enums become the possible
+/// values, booleans flags and arrays many valued args.
+fn schema_arg(name: &str, described: &Value, schema: &Value, required: bool) -> ImportArg {
+    let mut arg = ImportArg {
+        name: name.into(),
+        long: Some(name.replace('_', "-")),
+        required,
+        ..Default::default()
+    };
+
+    let description = described
+        .get("description")
+        .and_then(Value::as_str)
+        .and_then(|d| d.lines().next())
+        .map(str::trim);
+    let default = schema.get("default").map(|v| match v {
+        Value::String(v) => v.clone(),
+        v => v.to_string(),
+    });
+
+    let (items, array) = match schema.get("type").and_then(Value::as_str) {
+        Some("array") => (schema.get("items").unwrap_or(&Value::Null), true),
+        _ => (schema, false),
+    };
+
+    arg.possible_values = items
+        .get("enum")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|v| match v {
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        })
+        .collect();
+
+    match (items.get("type").and_then(Value::as_str), array) {
+        // optional booleans are flags, set when they should be sent
+        (Some("boolean"), false) if !required && default.as_deref() != Some("true") => {
+            arg.arg_type = Some("bool".into());
+            arg.action = Some("set_true".into());
+            arg.help = description.map(String::from);
+            return arg;
+        }
+        (Some("boolean"), _) => arg.possible_values = vec!["true".into(), "false".into()],
+        _ => (),
+    }
+
+    if array {
+        arg.many_valued = true;
+        arg.action = Some("append".into());
+    }
+
+    let json = matches!(schema.get("type").and_then(Value::as_str), Some("object")) && !array;
+    arg.help = match (description, json) {
+        (Some(description), true) => Some(format!("{}, as JSON", description)),
+        (None, true) => Some("A JSON object".into()),
+        (description, false) => description.map(String::from),
+    };
+
+    // the default is left for the server to apply unless the value is required
+    match (required, default) {
+        (true, default) => arg.default_value = default,
+        (false, Some(default)) => {
+            arg.help = Some(
+                format!("{} (defaults to {})", arg.help.unwrap_or_default(), default)
+                    .trim_start()
+                    .into(),
+            )
+        }
+        (false, None) => (),
+    }
+
+    arg
+}
+
+/// condition returns the Tera condition which is true when the optional arg was
+/// given. Flags are always defined, so they are sent when set.
+fn condition(arg: &ImportArg) -> String {
+    match arg.action.as_deref() {
+        Some("set_true") => arg.name.clone(),
+        _ => format!("{} is defined", arg.name),
+    }
+}
+
+/// resolve follows the `$ref`s of the value within the spec
+fn resolve<'a>(spec: &'a Value, value: &'a Value) -> Result<&'a Value> {
+    let mut value = value;
+    for _ in 0..MAX_REFS {
+        let reference = match value.get("$ref").and_then(Value::as_str) {
+            Some(reference) => reference,
+            None => return Ok(value),
+        };
+        value = reference
+            .strip_prefix('#')
+            .and_then(|pointer| spec.pointer(pointer))
+            .ok_or_else(|| {
+                Error::from(format!(
+                    "could not resolve {}, only references within the spec are supported",
+                    reference
+                ))
+            })?;
+    }
+    Err(Error::from("too many nested $ref in the spec"))
+}
+
+/// is_json returns true for JSON media types, like application/json and
+/// application/vnd.api+json
+fn is_json(media_type: &str) -> bool {
+    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// strip_prefix returns the path and fragment of the url relative to the prefix of
+/// the environment, or the whole url (without the query) when it isn't under it
+fn strip_prefix(url: &Url, prefix: Option<&str>) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    let full = url.to_string();
+
+    let relative = prefix
+        .map(|p| p.trim_end_matches('/'))
+        .and_then(|p| full.strip_prefix(p))
+        .filter(|rest| rest.is_empty() || rest.starts_with('/') || rest.starts_with('#'));
+
+    match relative {
+        Some(rest) => raw(&format!("/{}", rest.trim_start_matches('/'))),
+        None => raw(&full),
+    }
+}
+
+/// is_form returns true when the data looks like url encoded `key=value` pairs
+fn is_form(data: &str) -> bool {
+    !data.is_empty()
+        && !data.starts_with(['{', '[', '@'])
+        && !data.contains('\n')
+        && data.split('&').all(|pair| pair.contains('='))
+}
+
+/// is_id returns true for path segments that look like identifiers, numbers and
+/// uuids
+fn is_id(segment: &str) -> bool {
+    let uuid = segment.len() == 36
+        && segment.chars().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    let number = !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit());
+    uuid || number
+}
+
+/// default_name names the template after the method and the path, leaving out
+/// identifiers
+fn default_name(method: &str, uri: &str) -> String {
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let mut parts = vec![method.to_lowercase()];
+    parts.extend(
+        path.split('/')
+            .filter(|s| !s.is_empty() && !is_id(s) && !s.contains('{') && !s.contains(':'))
+            .map(|s| identifier(s).replace('_', "-")),
+    );
+    parts.join("-")
+}
+
+/// reserved returns the names args can't take, the flags of kla
+fn reserved() -> HashSet<String> {
+    KLA_FLAGS
+        .iter()
+        .map(|flag| flag.replace('-', "_"))
+        .collect()
+}
+
+/// unique returns the name, suffixed with a number when it's already taken
+fn unique(names: &mut HashSet<String>, name: String) -> String {
+    let mut candidate = name.clone();
+    let mut n = 2;
+    while !names.insert(candidate.clone()) {
+        candidate = format!("{}_{}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// identifier turns the name into a valid Tera identifier, camelCase becomes
+/// snake_case
+fn identifier(name: &str) -> String {
+    let mut ident = String::new();
+    let mut previous = ' ';
+    for c in name.chars() {
+        match c.is_ascii_alphanumeric() {
+            true => {
+                if c.is_ascii_uppercase()
+                    && (previous.is_ascii_lowercase() || previous.is_ascii_digit())
+                {
+                    ident.push('_');
+                }
+                ident.push(c.to_ascii_lowercase());
+            }
+            false => ident.push('_'),
+        }
+        previous = c;
+    }
+    ident = ident.trim_matches('_').to_string();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert_str(0, "arg_");
+    }
+    ident
+}
+
+/// raw protects literal values which look like Tera syntax from being rendered
+fn raw(value: &str) -> String {
+    match ["{{", "{%", "{#"].iter().any(|s| value.contains(s)) {
+        true => format!("{{% raw %}}{}{{% endraw %}}", value),
+        false => value.to_string(),
+    }
+}
+
+/// marker returns the string swapped for the expression of the arg once the body is
+/// serialized
+fn marker(name: &str, string: bool) -> String {
+    format!("\u{0}kla:{}:{}", name, string)
+}
+
+/// field writes the `key = "value"` line when the value is set
+fn field<S: AsRef<str>>(toml: &mut String, indent: &str, key: &str, value: &Option<S>) {
+    if let Some(value) = value {
+        toml.push_str(&format!("{}{} = {}\n", indent, key, quote(value.as_ref())));
+    }
+}
+
+/// quote quotes the value as a TOML string
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ::tera::Tera;
+    use clap::Command;
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+    use crate::config::ConfigCommand;
+
+    fn import(command: &str) -> Import {
+        Import::from_curl(
+            &CurlRequest::parse(command).unwrap(),
+            Some("https://api.x.io/v1/"),
+            false,
+        )
+        .unwrap()
+    }
+
+    /// render reads the TOML of the import back as a template, and renders it with
+    /// the args the way `kla run` does
+    fn render(import: &Import, args: &[&str]) -> HashMap<String, String> {
+        let conf = Config::builder()
+            .add_source(File::from_str(&import.to_toml(), FileFormat::Toml))
+            .build()
+            .unwrap();
+        let cmd = ConfigCommand::with_name("imported", conf).unwrap();
+        let matches = Command::try_from(cmd.clone())
+            .unwrap()
+            .try_get_matches_from([&["imported"], args].concat())
+            .unwrap();
+        let context = cmd.args_context(&matches).unwrap();
+
+        // entries whose `when` renders empty are left out
+        let mut excluded = HashSet::new();
+        for (section, kvs) in [
+            ("header", &cmd.header),
+            ("query", &cmd.query),
+            ("form", &cmd.form),
+            ("multipart", &cmd.multipart),
+        ] {
+            for (i, kv) in kvs.iter().enumerate() {
+                if !kv.included(&context).unwrap() {
+                    excluded.insert(format!("{}.{}", section, i));
+                }
+            }
+        }
+
+        let templates = cmd.templates().unwrap();
+        let mut tera = Tera::default();
+        tera.add_raw_templates(templates.iter().map(|(name, t)| (name, t.as_str())))
+            .unwrap();
+        templates
+            .iter()
+            .filter(|(name, _)| !excluded.contains(name))
+            .map(|(name, _)| (name.clone(), tera.render(name, &context).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn form_data() {
+        let import = import(
+            "curl https://api.x.io/v1/users -H 'Content-Type: application/x-www-form-urlencoded' -d 'name=kla&tag=a+b'",
+        );
+        assert_eq!(import.method, "POST");
+        assert_eq!(import.uri, "/users");
+        assert_eq!(import.body, None);
+        assert!(import.header.is_empty());
+        assert_eq!(
+            import
+                .form
+                .iter()
+                .map(|kv| (kv.name.as_str(), kv.value.as_str()))
+                .collect::<Vec<_>>(),
+            [("name", "kla"), ("tag", "a b")]
+        );
+    }
+
+    #[test]
+    fn json_data() {
+        let import = import(
+            r#"curl https://api.x.io/v1/users -H 'content-type: application/json' -H 'User-Agent: x' -d '{"name":"kla","age":3}'"#,
+        );
+        assert!(import.form.is_empty());
+        assert_eq!(import.body.as_deref(), Some(r#"{"name":"kla","age":3}"#));
+        assert_eq!(
+            import
+                .header
+                .iter()
+                .map(|kv| kv.name.as_str())
+                .collect::<Vec<_>>(),
+            ["content-type"]
+        );
+        let labels: Vec<String> = import.candidates().iter().map(|c| c.to_string()).collect();
+        assert!(labels.contains(&r#"body name="kla""#.to_string()));
+        assert!(labels.contains(&"body age=3".to_string()));
+    }
+
+    #[test]
+    fn data_without_content_type_is_a_form() {
+        // curl sends the data as a form, even when it isn't one
+        let import = import(r#"curl https://api.x.io/v1/users -d '{"name":"kla"}'"#);
+        assert_eq!(import.body.as_deref(), Some(r#"{"name":"kla"}"#));
+        assert_eq!(import.header[0].name, "Content-Type");
+        assert_eq!(import.header[0].value, FORM_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn user() {
+        let import = import("curl -u me:pw https://api.x.io/v1/me");
+        assert_eq!(import.header[0].name, "Authorization");
+        assert_eq!(import.header[0].value, "Basic bWU6cHc=");
+        assert!(import.header[0].secret);
+        assert!(import.to_toml().contains("  secret = true\n"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut import = import(
+            r#"curl -X PUT 'https://api.x.io/v1/users/1234/notes?draft=true' -H 'Content-Type: application/json' -d '{"text":"it'"'"'s {here}","pinned":false}'"#,
+        );
+        assert_eq!(import.name, "put-users-notes");
+
+        let candidates = import.candidates();
+        let placeholders: Vec<Placeholder> = candidates
+            .iter()
+            .map(|c| c.placeholder.clone())
+            .filter(|p| !matches!(p, Placeholder::Header(_)))
+            .collect();
+        import.apply(&placeholders);
+
+        // path segments are required, the rest defaults to the imported values
+        let rendered = render(&import, &["1234"]);
+        assert_eq!(rendered["method"], "PUT");
+        assert_eq!(rendered["uri"], "/users/1234/notes");
+        assert_eq!(rendered["query.0"], "true");
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered["body"]).unwrap(),
+            serde_json::json!({"text": "it's {here}", "pinned": false})
+        );
+
+        let rendered = render(
+            &import,
+            &[
+                "42", "--draft", "false", "--text", "a \"b\"", "--pinned", "true",
+            ],
+        );
+        assert_eq!(rendered["uri"], "/users/42/notes");
+        assert_eq!(rendered["query.0"], "false");
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered["body"]).unwrap(),
+            serde_json::json!({"text": "a \"b\"", "pinned": true})
+        );
+    }
+
+    #[test]
+    fn values_named_after_flags() {
+        let mut import = import(
+            "curl 'https://api.x.io/v1/search?query=kla&timeout=5' -H 'Cookie: a=b' -d 'form=x'",
+        );
+        let placeholders: Vec<Placeholder> = import
+            .candidates()
+            .iter()
+            .map(|c| c.placeholder.clone())
+            .collect();
+        import.apply(&placeholders);
+
+        let longs: Vec<&str> = import
+            .args
+            .iter()
+            .filter_map(|a| a.long.as_deref())
+            .collect();
+        assert_eq!(longs, ["query-2", "timeout-2", "form-2", "cookie-2"]);
+
+        let rendered = render(&import, &["--query-2", "rust", "--timeout-2", "9"]);
+        assert_eq!(rendered["query.0"], "rust");
+        assert_eq!(rendered["query.1"], "9");
+        assert_eq!(rendered["form.0"], "x");
+    }
+
+    fn pets() -> Vec<Import> {
+        let spec: Value = serde_yaml::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/openapi/pets.yaml"
+        )))
+        .unwrap();
+        Import::from_openapi(&spec).unwrap()
+    }
+
+    fn arg<'a>(import: &'a Import, name: &str) -> &'a ImportArg {
+        import.args.iter().find(|a| a.name == name).unwrap()
+    }
+
+    #[test]
+    fn openapi_refs() {
+        let imports = pets();
+        assert_eq!(
+            imports.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(),
+            ["get-pet", "update-pet"]
+        );
+
+        let get = &imports[0];
+        assert_eq!(get.method, "GET");
+        assert_eq!(get.uri, "/pets/{{ pet_id }}");
+        assert_eq!(get.short_description.as_deref(), Some("Get a pet"));
+        assert_eq!(
+            arg(get, "pet_id").help.as_deref(),
+            Some("The id of the pet")
+        );
+        assert!(arg(get, "pet_id").required);
+        // verbose is a flag of kla, so the arg is suffixed
+        assert_eq!(arg(get, "verbose_2").action.as_deref(), Some("set_true"));
+        assert_eq!(get.query[0].name, "verbose");
+        assert_eq!(
+            get.query[0].when.as_deref(),
+            Some("{% if verbose_2 %}yes{% endif %}")
+        );
+
+        // the enum of the referenced schema becomes the possible values
+        let tag = arg(&imports[1], "tag");
+        assert_eq!(tag.possible_values, ["dog", "cat"]);
+        assert_eq!(tag.help.as_deref(), Some("The kind of pet"));
+    }
+
+    #[test]
+    fn openapi_parameters_named_after_flags() {
+        let spec = serde_json::json!({
+            "openapi": "3.0.0",
+            "paths": {"/search": {"get": {
+                "operationId": "search",
+                "parameters": [
+                    {"name": "query", "in": "query", "required": true, "schema": {"type": "string"}},
+                    {"name": "timeout", "in": "query", "schema": {"type": "string"}},
+                ],
+            }}},
+        });
+        let search = &Import::from_openapi(&spec).unwrap()[0];
+        assert_eq!(arg(search, "query_2").long.as_deref(), Some("query-2"));
+        assert_eq!(arg(search, "timeout_2").long.as_deref(), Some("timeout-2"));
+        assert!(search
+            .args
+            .iter()
+            .all(|a| !KLA_FLAGS.contains(&a.long.as_deref().unwrap_or_default())));
+
+        let rendered = render(search, &["--query-2", "foo", "--timeout-2", "3"]);
+        assert_eq!(search.query[0].name, "query");
+        assert_eq!(rendered["query.0"], "foo");
+        assert_eq!(rendered["query.1"], "3");
+    }
+
+    #[test]
+    fn openapi_path_parameter_override() {
+        let put = &pets()[1];
+        let ids: Vec<&ImportArg> = put
+            .args
+            .iter()
+            .filter(|a| a.name.starts_with("pet_id"))
+            .collect();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[0].help.as_deref(), Some("The numeric id of the pet"));
+        assert_eq!(put.uri, "/pets/{{ pet_id }}");
+        // path parameters come first, so they are positional
+        assert_eq!(put.args[0].name, "pet_id");
+        assert_eq!(put.header[0].name, "X-Request-Id");
+    }
+
+    #[test]
+    fn openapi_optional_properties() {
+        let put = &pets()[1];
+        assert!(put.args.iter().all(|a| a.name != "id"));
+
+        let rendered = render(put, &["7", "--name", "rex"]);
+        assert_eq!(rendered["uri"], "/pets/7");
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered["body"]).unwrap(),
+            serde_json::json!({"name": "rex"})
+        );
+
+        let rendered = render(
+            put,
+            &[
+                "7",
+                "--name",
+                "rex \"jr\"",
+                "--tag",
+                "dog",
+                "--age",
+                "3",
+                "--vaccinated",
+            ],
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered["body"]).unwrap(),
+            serde_json::json!({"name": "rex \"jr\"", "tag": "dog", "age": 3, "vaccinated": true})
+        );
+
+        // properties are left out without leaving a comma behind
+        let rendered = render(put, &["7", "--name", "rex", "--age", "3"]);
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered["body"]).unwrap(),
+            serde_json::json!({"name": "rex", "age": 3})
+        );
+    }
+}
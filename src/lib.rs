@@ -1,20 +1,44 @@
+mod cookies; // persisting cookies between requests
+mod curl; // turning requests into curl commands
+mod digest; // answering http digest challenges
 mod environment; // environment struct and logic
 mod error; // package error handling
+mod har; // recording requests into HAR files
+mod history; // the log of executed requests
+mod import; // turning other descriptions of requests into templates
+mod oauth2; // fetching oauth2 access tokens
 mod opt;
 mod output; // managing the output of kla
 mod reqwest;
+mod retry; // retrying failed requests
 mod sigv4;
+mod state; // values persisted per environment
+mod template; // runnable templates
 mod tera; // templating responses
 mod url_builder;
 
-use std::env;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
 
+pub use cookies::*;
+pub use curl::*;
+pub use digest::*;
 pub use environment::*;
 pub use error::*;
+pub use har::*;
+pub use history::*;
+pub use import::*;
+pub use oauth2::*;
 pub use opt::*;
 pub use output::*;
 pub use reqwest::*;
+pub use retry::*;
 pub use sigv4::*;
+pub use state::*;
 pub use template::*;
 pub use tera::*;
 pub use url_builder::*;
@@ -58,3 +82,83 @@ impl Expand for &String {
         self.as_str().shell_expansion()
     }
 }
+
+/// write_private writes contents to the file at path, only readable by the user as
+/// it may hold secrets. The contents are written next to the file and renamed into
+/// place, so a failed write never leaves half a file behind.
+pub(crate) fn write_private<C: AsRef<[u8]>>(path: &Path, contents: C) -> Result<()> {
+    let (mut file, tmp) = create_private(path)?;
+    let written = file
+        .write_all(contents.as_ref())
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(written?)
+}
+
+/// create_private creates the file write_private writes to before it's renamed to
+/// path, for contents which are streamed. It returns the file and its location,
+/// rename it to path once everything is written or remove it when writing fails.
+/// The file is new and unique to this write, so kla processes saving the same file
+/// at once never write into each other's files.
+pub(crate) fn create_private(path: &Path) -> Result<(fs::File, PathBuf)> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "kla".into());
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{:016x}.tmp",
+        name,
+        std::process::id(),
+        fastrand::u64(..)
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let file = options.open(&tmp)?;
+    Ok((file, tmp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_files_are_written_through_unique_files() {
+        let dir = env::temp_dir().join(format!("kla-private-{}", std::process::id()));
+        let path = dir.join("state.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("state.tmp"), "mine").unwrap();
+
+        // writes of the same file at once, or of files differing by extension,
+        // never share a file
+        let (_, first) = create_private(&path).unwrap();
+        let (_, second) = create_private(&path).unwrap();
+        let (_, other) = create_private(&dir.join("state.txt")).unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(first.parent(), Some(dir.as_path()));
+        for tmp in [first, second, other] {
+            fs::remove_file(tmp).unwrap();
+        }
+
+        write_private(&path, "{}").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        assert_eq!(fs::read_to_string(dir.join("state.tmp")).unwrap(), "mine");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // a failed write leaves nothing behind, a directory can't be replaced
+        fs::create_dir(dir.join("sub")).unwrap();
+        assert!(write_private(&dir.join("sub"), "{}").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,873 @@
+use std::{path::Path, time::Duration};
+
+use base64::prelude::*;
+use chrono::Utc;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{read_secret, Error, Result, State};
+
+/// The key the token is cached under in the state of the environment
+pub const OAUTH2_STATE_KEY: &str = "oauth2";
+
+/// Tokens expiring within this many seconds are fetched again, so they don't
+/// expire while the request is in flight
+const EXPIRY_LEEWAY: i64 = 30;
+
+/// How long the authorization code flow waits for the user to log in
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The grant type used when polling the token endpoint in the device code flow
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+/// Grant is the OAuth2 grant type used to fetch an access token
+pub enum Grant {
+    #[serde(rename = "client_credentials")]
+    #[default]
+    ClientCredentials,
+    #[serde(rename = "refresh_token")]
+    RefreshToken,
+    /// device_code and authorization_code are interactive, and run through
+    /// `kla login`. Later requests use the refresh token handed out there.
+    #[serde(rename = "device_code")]
+    DeviceCode,
+    #[serde(rename = "authorization_code")]
+    AuthorizationCode,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+/// OAuth2 holds the settings of an `[environment.x.oauth2]` table. The access token
+/// is fetched from `token_url` and cached in the state of the environment until it
+/// expires.
+pub struct OAuth2 {
+    #[serde(rename = "token_url")]
+    token_url: String,
+    #[serde(rename = "client_id")]
+    client_id: String,
+    /// client_secret may be preceded by an `@` to read it from a file
+    #[serde(rename = "client_secret")]
+    client_secret: Option<String>,
+    #[serde(rename = "scopes", default)]
+    scopes: Vec<String>,
+    #[serde(rename = "grant", default)]
+    grant: Grant,
+    /// refresh_token is used by the refresh_token grant until the server hands
+    /// out a new one, it may be preceded by an `@` to read it from a file
+    #[serde(rename = "refresh_token")]
+    refresh_token: Option<String>,
+    /// device_url is the device authorization endpoint of the device_code grant
+    #[serde(rename = "device_url")]
+    device_url: Option<String>,
+    /// authorize_url is the authorization endpoint of the authorization_code grant
+    #[serde(rename = "authorize_url")]
+    authorize_url: Option<String>,
+    /// redirect_port is the port of the localhost redirect listener used by the
+    /// authorization_code grant, a free port is picked when it's not set
+    #[serde(rename = "redirect_port", default)]
+    redirect_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Token is an access token as it's cached in the state
+struct Token {
+    access_token: String,
+    /// expires_at is a unix timestamp, tokens without one are never reused
+    #[serde(default)]
+    expires_at: Option<i64>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+impl Token {
+    /// valid returns true when the token can still be used
+    fn valid(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - EXPIRY_LEEWAY > Utc::now().timestamp())
+    }
+}
+
+#[derive(Deserialize)]
+/// TokenResponse is the successful response of a token endpoint
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+/// TokenError is the error response of a token endpoint
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+/// DeviceAuthorization is the response of the device authorization endpoint
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+impl OAuth2 {
+    /// access_token returns an access token for the environment. A cached token is
+    /// returned while it's valid, otherwise a new one is fetched with the client and
+    /// cached in the state file at `state`, if one is given.
+    pub async fn access_token(
+        &self,
+        client: &Client,
+        env: &str,
+        state: Option<&Path>,
+    ) -> Result<String> {
+        let mut state = state.map(State::load).transpose()?;
+        let cached = cached_token(state.as_ref(), env);
+
+        if let Some(token) = cached.as_ref().filter(|token| token.valid()) {
+            return Ok(token.access_token.clone());
+        }
+
+        let previous = cached.and_then(|token| token.refresh_token);
+        let form = match (self.grant, previous) {
+            (Grant::ClientCredentials, _) => {
+                vec![("grant_type", "client_credentials".to_string())]
+            }
+            (_, Some(refresh_token)) => vec![
+                ("grant_type", "refresh_token".to_string()),
+                ("refresh_token", refresh_token),
+            ],
+            (Grant::RefreshToken, None) => vec![
+                ("grant_type", "refresh_token".to_string()),
+                (
+                    "refresh_token",
+                    read_secret(self.refresh_token.as_deref().ok_or_else(|| {
+                        Error::from("the refresh_token grant needs a refresh_token")
+                    })?)?,
+                ),
+            ],
+            (Grant::DeviceCode | Grant::AuthorizationCode, None) => {
+                return Err(Error::from(format!(
+                    "not logged in to {}, run `kla login -e {}`",
+                    env, env
+                )))
+            }
+        };
+
+        let token = self
+            .request_token(client, form)
+            .await
+            .map_err(|err| match self.grant {
+                Grant::DeviceCode | Grant::AuthorizationCode => Error::from(format!(
+                    "{}, run `kla login -e {}` to log in again",
+                    err, env
+                )),
+                _ => err,
+            })?;
+
+        store_token(state.as_mut(), env, &token)?;
+        Ok(token.access_token)
+    }
+
+    /// login fetches a new token for the environment and stores it in the state,
+    /// running the interactive device code or authorization code flow when those
+    /// grants are configured.
+    pub async fn login(&self, client: &Client, env: &str, state: Option<&Path>) -> Result<()> {
+        let token = match self.grant {
+            Grant::DeviceCode => self.device_code(client).await?,
+            Grant::AuthorizationCode => self.authorization_code(client).await?,
+            Grant::ClientCredentials | Grant::RefreshToken => {
+                // expire the cached token, keeping the refresh token, so a new one
+                // is fetched
+                let mut store = state.map(State::load).transpose()?;
+                if let Some(mut token) = cached_token(store.as_ref(), env) {
+                    token.expires_at = None;
+                    store_token(store.as_mut(), env, &token)?;
+                }
+                self.access_token(client, env, state).await?;
+                return Ok(());
+            }
+        };
+
+        let mut store = state.map(State::load).transpose()?;
+        store_token(store.as_mut(), env, &token)
+    }
+
+    /// device_code runs the device code flow (RFC 8628). The user is asked to visit
+    /// the verification url, and the token endpoint is polled until they did.
+    async fn device_code(&self, client: &Client) -> Result<Token> {
+        let device_url = self
+            .device_url
+            .as_ref()
+            .ok_or_else(|| Error::from("the device_code grant needs a device_url"))?;
+
+        let response = client
+            .post(device_url)
+            .form(&self.client_form(vec![])?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::from(format!(
+                "device authorization request to {} failed with {}: {}",
+                device_url,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        let device: DeviceAuthorization = serde_json::from_slice(&response.bytes().await?)?;
+
+        match device.verification_uri_complete.as_ref() {
+            Some(uri) => println!(
+                "Open {} to log in, and confirm the code {}",
+                uri, device.user_code
+            ),
+            None => println!(
+                "Open {} to log in, and enter the code {}",
+                device.verification_uri, device.user_code
+            ),
+        }
+
+        let mut interval = Duration::from_secs(device.interval.unwrap_or(5));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(device.expires_in);
+        let form = self.client_form(vec![
+            ("grant_type", DEVICE_CODE_GRANT.to_string()),
+            ("device_code", device.device_code),
+        ])?;
+
+        loop {
+            tokio::time::sleep(interval).await;
+            if tokio::time::Instant::now() > deadline {
+                return Err(Error::from("the device code expired before logging in"));
+            }
+
+            let response = client.post(&self.token_url).form(&form).send().await?;
+            if response.status().is_success() {
+                return token(serde_json::from_slice(&response.bytes().await?)?, None);
+            }
+
+            let body = response.bytes().await?;
+            match serde_json::from_slice::<TokenError>(&body) {
+                Ok(err) if err.error == "authorization_pending" => continue,
+                Ok(err) if err.error == "slow_down" => interval += Duration::from_secs(5),
+                Ok(err) => {
+                    return Err(Error::from(format!(
+                        "log in failed: {} {}",
+                        err.error,
+                        err.error_description.unwrap_or_default()
+                    )))
+                }
+                Err(_) => {
+                    return Err(Error::from(format!(
+                        "log in failed: {}",
+                        String::from_utf8_lossy(&body)
+                    )))
+                }
+            }
+        }
+    }
+
+    /// authorization_code runs the authorization code flow with PKCE (RFC 7636). The
+    /// user is asked to visit the authorization url, and the code is received by a
+    /// listener on localhost.
+    async fn authorization_code(&self, client: &Client) -> Result<Token> {
+        self.authorize(client, random_string()?, random_string()?)
+            .await
+    }
+
+    /// authorize runs the authorization code flow with the PKCE verifier and the
+    /// csrf value sent as `state`, which the callback must send back.
+    async fn authorize(&self, client: &Client, verifier: String, csrf: String) -> Result<Token> {
+        let authorize_url = self
+            .authorize_url
+            .as_ref()
+            .ok_or_else(|| Error::from("the authorization_code grant needs an authorize_url"))?;
+
+        let listener = TcpListener::bind(("127.0.0.1", self.redirect_port)).await?;
+        let redirect_uri = format!(
+            "http://127.0.0.1:{}/callback",
+            listener.local_addr()?.port()
+        );
+
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let mut url = Url::parse(authorize_url)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("state", &csrf)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        if !self.scopes.is_empty() {
+            url.query_pairs_mut()
+                .append_pair("scope", &self.scopes.join(" "));
+        }
+
+        println!("Open {} to log in", url);
+
+        let deadline = tokio::time::Instant::now() + LOGIN_TIMEOUT;
+        let timed_out = |_| Error::from("timed out waiting for the log in");
+
+        let code = loop {
+            let (mut stream, _) = tokio::time::timeout_at(deadline, listener.accept())
+                .await
+                .map_err(timed_out)??;
+
+            // the request line is all we need, it's at the start of the request
+            let mut buf = vec![0; 8192];
+            let read = tokio::time::timeout_at(deadline, stream.read(&mut buf))
+                .await
+                .map_err(timed_out)??;
+            let request = String::from_utf8_lossy(&buf[..read]);
+            let target = request.split_whitespace().nth(1).unwrap_or_default();
+
+            let callback = Url::parse("http://127.0.0.1")?.join(target)?;
+            if callback.path() != "/callback" {
+                stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                    .await?;
+                continue;
+            }
+
+            let param = |name: &str| {
+                callback
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.to_string())
+            };
+
+            let (message, result) = match (param("code"), param("state"), param("error")) {
+                (_, _, Some(error)) => (
+                    "Log in failed, you can close this window.",
+                    Err(Error::from(format!(
+                        "log in failed: {} {}",
+                        error,
+                        param("error_description").unwrap_or_default()
+                    ))),
+                ),
+                (Some(code), Some(state), None) if state == csrf => {
+                    ("Logged in, you can close this window.", Ok(code))
+                }
+                _ => (
+                    "Log in failed, you can close this window.",
+                    Err(Error::from("log in failed: invalid callback")),
+                ),
+            };
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        message.len(),
+                        message
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            break result?;
+        };
+
+        self.request_token(
+            client,
+            vec![
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("code_verifier", verifier),
+            ],
+        )
+        .await
+    }
+
+    /// client_form adds the client id, secret and scopes to the form
+    fn client_form(
+        &self,
+        mut form: Vec<(&'static str, String)>,
+    ) -> Result<Vec<(&'static str, String)>> {
+        form.push(("client_id", self.client_id.clone()));
+        if let Some(secret) = self.client_secret.as_deref() {
+            form.push(("client_secret", read_secret(secret)?));
+        }
+        if !self.scopes.is_empty() {
+            form.push(("scope", self.scopes.join(" ")));
+        }
+        Ok(form)
+    }
+
+    /// request_token posts the form to the token endpoint and returns the token.
+    async fn request_token(
+        &self,
+        client: &Client,
+        form: Vec<(&'static str, String)>,
+    ) -> Result<Token> {
+        let previous = form
+            .iter()
+            .find(|(k, _)| *k == "refresh_token")
+            .map(|(_, v)| v.clone());
+
+        let response = client
+            .post(&self.token_url)
+            .form(&self.client_form(form)?)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::from(format!(
+                "token request to {} failed with {}: {}",
+                self.token_url,
+                status,
+                response.text().await.unwrap_or_default()
+            )));
+        }
+
+        token(serde_json::from_slice(&response.bytes().await?)?, previous)
+    }
+}
+
+/// token turns the response of the token endpoint into a Token. Servers don't have
+/// to rotate refresh tokens, so the previous one is kept when they don't.
+fn token(response: TokenResponse, previous: Option<String>) -> Result<Token> {
+    Ok(Token {
+        access_token: response.access_token,
+        expires_at: response
+            .expires_in
+            .map(|expires_in| Utc::now().timestamp() + expires_in),
+        refresh_token: response.refresh_token.or(previous),
+    })
+}
+
+/// cached_token returns the token stored in the state of the environment
+fn cached_token(state: Option<&State>, env: &str) -> Option<Token> {
+    state
+        .and_then(|state| state.get(env, OAUTH2_STATE_KEY))
+        .and_then(|token| serde_json::from_value(token.clone()).ok())
+}
+
+/// store_token saves the token in the state of the environment
+fn store_token(state: Option<&mut State>, env: &str, token: &Token) -> Result<()> {
+    if let Some(state) = state {
+        state.set(env, OAUTH2_STATE_KEY, serde_json::to_value(token)?);
+        state.save()?;
+    }
+    Ok(())
+}
+
+/// random_string returns 32 random bytes, base64 encoded so they are url safe
+fn random_string() -> Result<String> {
+    let mut buf = [0u8; 32];
+    getrandom::fill(&mut buf).map_err(|err| Error::from(err.to_string()))?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// serve starts a mock token endpoint answering each request with the next of
+    /// the responses. It returns the url of the endpoint and the bodies it received.
+    async fn serve(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+        serve_status(responses.into_iter().map(|r| ("200 OK", r)).collect()).await
+    }
+
+    /// serve_status is serve, answering each request with the status next to the
+    /// response. The same url serves the device and token endpoints.
+    async fn serve_status(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(vec![]));
+
+        let received = bodies.clone();
+        tokio::spawn(async move {
+            for (status, response) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                let body = loop {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_string)
+                            })
+                            .and_then(|l| l.trim().parse::<usize>().ok())
+                            .unwrap_or_default();
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                received.lock().expect("not poisoned").push(body);
+
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (url, bodies)
+    }
+
+    fn oauth2(settings: serde_json::Value) -> OAuth2 {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    fn state_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kla-oauth2-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn client_credentials() {
+        let (url, bodies) = serve(vec![r#"{"access_token":"a1","expires_in":3600}"#]).await;
+        let state = state_file("client-credentials");
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "client_id": "kla",
+            "client_secret": "s3cret",
+            "scopes": ["read", "write"],
+        }));
+
+        let token = oauth2
+            .access_token(&Client::new(), "prod", Some(&state))
+            .await
+            .unwrap();
+        assert_eq!(token, "a1");
+        assert_eq!(
+            bodies.lock().unwrap()[0],
+            "grant_type=client_credentials&client_id=kla&client_secret=s3cret&scope=read+write"
+        );
+
+        let cached = cached_token(Some(&State::load(&state).unwrap()), "prod").unwrap();
+        assert_eq!(cached.access_token, "a1");
+        assert!(cached.valid());
+
+        std::fs::remove_file(state).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cached_token_is_reused() {
+        let (url, bodies) = serve(vec![
+            r#"{"access_token":"a1","expires_in":3600}"#,
+            r#"{"access_token":"a2","expires_in":3600}"#,
+        ])
+        .await;
+        let state = state_file("cached");
+        let oauth2 = oauth2(json!({"token_url": url, "client_id": "kla"}));
+        let client = Client::new();
+
+        let first = oauth2.access_token(&client, "prod", Some(&state)).await;
+        let second = oauth2.access_token(&client, "prod", Some(&state)).await;
+        assert_eq!(first.unwrap(), "a1");
+        assert_eq!(second.unwrap(), "a1");
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+
+        std::fs::remove_file(state).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refreshed() {
+        let (url, bodies) = serve(vec![r#"{"access_token":"a2","expires_in":3600}"#]).await;
+        let state = state_file("refresh");
+        let mut store = State::load(&state).unwrap();
+        store_token(
+            Some(&mut store),
+            "prod",
+            &Token {
+                access_token: "a1".into(),
+                expires_at: Some(Utc::now().timestamp() - 60),
+                refresh_token: Some("r1".into()),
+            },
+        )
+        .unwrap();
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "client_id": "kla",
+            "grant": "refresh_token",
+            "refresh_token": "r0",
+        }));
+
+        let token = oauth2
+            .access_token(&Client::new(), "prod", Some(&state))
+            .await
+            .unwrap();
+        assert_eq!(token, "a2");
+        assert_eq!(
+            bodies.lock().unwrap()[0],
+            "grant_type=refresh_token&refresh_token=r1&client_id=kla"
+        );
+
+        // the server didn't hand out a new refresh token, so the previous one is kept
+        let cached = cached_token(Some(&State::load(&state).unwrap()), "prod").unwrap();
+        assert_eq!(cached.access_token, "a2");
+        assert_eq!(cached.refresh_token.as_deref(), Some("r1"));
+
+        std::fs::remove_file(state).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refresh_token_grant_without_cache() {
+        let (url, bodies) = serve(vec![
+            r#"{"access_token":"a1","expires_in":3600,"refresh_token":"r2"}"#,
+        ])
+        .await;
+        let state = state_file("refresh-grant");
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "client_id": "kla",
+            "grant": "refresh_token",
+            "refresh_token": "r0",
+        }));
+
+        let token = oauth2
+            .access_token(&Client::new(), "prod", Some(&state))
+            .await
+            .unwrap();
+        assert_eq!(token, "a1");
+        assert_eq!(
+            bodies.lock().unwrap()[0],
+            "grant_type=refresh_token&refresh_token=r0&client_id=kla"
+        );
+
+        let cached = cached_token(Some(&State::load(&state).unwrap()), "prod").unwrap();
+        assert_eq!(cached.refresh_token.as_deref(), Some("r2"));
+
+        std::fs::remove_file(state).unwrap();
+    }
+
+    #[tokio::test]
+    async fn interactive_grants_need_a_login() {
+        let oauth2 = oauth2(json!({
+            "token_url": "http://127.0.0.1:1/token",
+            "client_id": "kla",
+            "grant": "device_code",
+        }));
+
+        let err = oauth2
+            .access_token(&Client::new(), "prod", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("kla login -e prod"));
+    }
+
+    #[tokio::test]
+    async fn environment_attaches_the_token() {
+        let (url, _) = serve(vec![r#"{"access_token":"a1","expires_in":3600}"#]).await;
+        let state = state_file("environment");
+        let conf = config::Config::builder()
+            .set_default("default.state", state.to_str().unwrap())
+            .unwrap()
+            .add_source(config::File::from_str(
+                &format!(
+                    "[environment.prod]\nurl = \"http://prod\"\n[environment.prod.oauth2]\ntoken_url = \"{}\"\nclient_id = \"kla\"\n",
+                    url
+                ),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let env = crate::Environment::new(Some(&"prod".into()), &conf).unwrap();
+        let client = Client::new();
+
+        let request = env
+            .prepare(Some(&client), client.get("http://prod/").build().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer a1");
+
+        // a dry run has no client, so no token is fetched
+        let request = env
+            .prepare(None, client.get("http://prod/").build().unwrap())
+            .await
+            .unwrap();
+        assert!(!request.headers().contains_key("authorization"));
+    }
+
+    const PENDING: (&str, &str) = ("400 Bad Request", r#"{"error":"authorization_pending"}"#);
+
+    #[tokio::test(start_paused = true)]
+    async fn device_code_polls_until_logged_in() {
+        let (url, bodies) = serve_status(vec![
+            (
+                "200 OK",
+                r#"{"device_code":"d1","user_code":"ABCD","verification_uri":"http://idp/device","expires_in":600,"interval":1}"#,
+            ),
+            PENDING,
+            ("400 Bad Request", r#"{"error":"slow_down"}"#),
+            ("200 OK", r#"{"access_token":"a1","expires_in":3600}"#),
+        ])
+        .await;
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "device_url": url,
+            "client_id": "kla",
+            "grant": "device_code",
+        }));
+
+        let start = tokio::time::Instant::now();
+        let token = oauth2.device_code(&Client::new()).await.unwrap();
+        assert_eq!(token.access_token, "a1");
+
+        // waits 1s before the pending and slow_down polls, then 5s more after
+        // being asked to slow down
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(8) && elapsed < Duration::from_secs(9),
+            "{:?}",
+            elapsed
+        );
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies[0], "client_id=kla");
+        assert_eq!(bodies.len(), 4);
+        assert_eq!(
+            bodies[3],
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code&device_code=d1&client_id=kla"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_code_expires() {
+        let (url, bodies) = serve_status(vec![
+            (
+                "200 OK",
+                r#"{"device_code":"d1","user_code":"ABCD","verification_uri":"http://idp/device","expires_in":2,"interval":1}"#,
+            ),
+            PENDING,
+            PENDING,
+            PENDING,
+        ])
+        .await;
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "device_url": url,
+            "client_id": "kla",
+            "grant": "device_code",
+        }));
+
+        let err = oauth2.device_code(&Client::new()).await.unwrap_err();
+        assert!(err.to_string().contains("expired"), "{}", err);
+        // polled at 1s and 2s, the code expired before the third poll
+        assert_eq!(bodies.lock().unwrap().len(), 3);
+    }
+
+    /// free_port returns a port nothing is listening on
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// callback sends the browser's redirect to the listener of the authorization
+    /// code flow, once it's listening
+    async fn callback(port: u16, query: &'static str) {
+        let url = format!("http://127.0.0.1:{}/callback?{}", port, query);
+        for _ in 0..100 {
+            if Client::new().get(&url).send().await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the callback listener never started");
+    }
+
+    #[tokio::test]
+    async fn authorization_code_sends_the_verifier() {
+        let (url, bodies) = serve(vec![r#"{"access_token":"a1","expires_in":3600}"#]).await;
+        let port = free_port();
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "authorize_url": "http://idp/authorize",
+            "client_id": "kla",
+            "grant": "authorization_code",
+            "redirect_port": port,
+        }));
+
+        tokio::spawn(callback(port, "code=c1&state=csrf"));
+        let token = oauth2
+            .authorize(&Client::new(), "v1".into(), "csrf".into())
+            .await
+            .unwrap();
+        assert_eq!(token.access_token, "a1");
+        assert_eq!(
+            bodies.lock().unwrap()[0],
+            format!(
+                "grant_type=authorization_code&code=c1&redirect_uri=http%3A%2F%2F127.0.0.1%3A{}%2Fcallback&code_verifier=v1&client_id=kla",
+                port
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn authorization_code_rejects_another_state() {
+        let (url, bodies) = serve(vec![r#"{"access_token":"a1","expires_in":3600}"#]).await;
+        let port = free_port();
+        let oauth2 = oauth2(json!({
+            "token_url": url,
+            "authorize_url": "http://idp/authorize",
+            "client_id": "kla",
+            "grant": "authorization_code",
+            "redirect_port": port,
+        }));
+
+        tokio::spawn(callback(port, "code=c1&state=forged"));
+        let err = oauth2
+            .authorize(&Client::new(), "v1".into(), "csrf".into())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid callback"), "{}", err);
+        assert!(bodies.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn authorization_code_times_out() {
+        let oauth2 = oauth2(json!({
+            "token_url": "http://127.0.0.1:1/token",
+            "authorize_url": "http://idp/authorize",
+            "client_id": "kla",
+            "grant": "authorization_code",
+        }));
+
+        let start = tokio::time::Instant::now();
+        let err = oauth2
+            .authorize(&Client::new(), "v1".into(), "csrf".into())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(start.elapsed() >= LOGIN_TIMEOUT);
+    }
+}
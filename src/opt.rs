@@ -53,7 +53,7 @@ pub trait Ok: Sized {
 #[macro_export]
 macro_rules! impl_opt {
     ($for:ty) => {
-        impl crate::opt::Opt for $for {
+        impl $crate::opt::Opt for $for {
             /// with_some allows you to send an optional value to the type which will only be
             /// called when v is Some. An Example usecase is
             ///
@@ -78,7 +78,7 @@ macro_rules! impl_opt {
 #[macro_export]
 macro_rules! impl_when {
     ($for:ty) => {
-        impl crate::opt::When for $for {
+        impl $crate::opt::When for $for {
             fn when<F>(self, v: bool, f: F) -> Self
             where
                 F: Fn(Self) -> Self,
@@ -95,7 +95,7 @@ macro_rules! impl_when {
 #[macro_export]
 macro_rules! impl_ok {
     ($for:ty, $error:ty) => {
-        impl crate::opt::Ok for $for {
+        impl $crate::opt::Ok for $for {
             /// Error is the type of error value shared between the result coming from the underlying
             /// closure, and the incoming value
             type Error = $error;
@@ -1,13 +1,130 @@
-use std::pin::Pin;
+use std::{collections::HashSet, fs, path::PathBuf, pin::Pin};
 
-use crate::{impl_opt, impl_when, ContextBuilder, Expand, FetchMany, Result};
-use reqwest::{Request, Response};
+use crate::{create_private, impl_opt, impl_when, ContextBuilder, Expand, FetchMany, Result};
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    Request, Response, Url,
+};
 use tera::Tera;
 use tokio::{
     fs::File,
     io::{stdout, AsyncWriteExt},
 };
 
+/// REDACTED replaces the value of secrets in the prelude
+pub(crate) const REDACTED: &str = "REDACTED";
+
+/// SENSITIVE_HEADERS are redacted from the prelude unless secrets are shown
+pub(crate) const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-amz-security-token",
+    "x-api-key",
+    "api-key",
+    "x-auth-token",
+];
+
+/// SENSITIVE_QUERY are the query parameters redacted from the prelude unless secrets
+/// are shown
+const SENSITIVE_QUERY: &[&str] = &[
+    "access_token",
+    "api_key",
+    "apikey",
+    "client_secret",
+    "password",
+    "token",
+    "x-amz-credential",
+    "x-amz-security-token",
+    "x-amz-signature",
+];
+
+#[derive(Clone, Debug)]
+/// Secrets decides which header and query parameter values are redacted when
+/// requests and responses are shown or recorded
+pub struct Secrets {
+    show: bool,
+    headers: HashSet<String>,
+    query: HashSet<String>,
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Self {
+            show: false,
+            headers: SENSITIVE_HEADERS.iter().map(|v| v.to_string()).collect(),
+            query: SENSITIVE_QUERY.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+impl Secrets {
+    /// show disables the redaction of secrets
+    pub fn show(mut self, show: bool) -> Self {
+        self.show = show;
+        self
+    }
+
+    /// headers adds headers to redact, on top of the known sensitive headers
+    pub fn headers<S: AsRef<str>, I: IntoIterator<Item = S>>(mut self, names: I) -> Self {
+        self.headers
+            .extend(names.into_iter().map(|v| v.as_ref().to_lowercase()));
+        self
+    }
+
+    /// query adds query parameters to redact, on top of the known sensitive
+    /// parameters
+    pub fn query<S: AsRef<str>, I: IntoIterator<Item = S>>(mut self, names: I) -> Self {
+        self.query
+            .extend(names.into_iter().map(|v| v.as_ref().to_lowercase()));
+        self
+    }
+
+    /// is_secret_header returns true when the value of the header should be redacted
+    pub fn is_secret_header(&self, name: &str, value: &HeaderValue) -> bool {
+        !self.show && value.is_sensitive() || self.is_secret_header_name(name)
+    }
+
+    /// is_secret_header_name returns true when values of the header should be
+    /// redacted, regardless of the value
+    pub fn is_secret_header_name(&self, name: &str) -> bool {
+        !self.show && self.headers.contains(&name.to_lowercase())
+    }
+
+    /// is_secret_query returns true when the value of the query parameter should be
+    /// redacted
+    pub fn is_secret_query(&self, name: &str) -> bool {
+        !self.show && self.query.contains(&name.to_lowercase())
+    }
+
+    /// header returns the value of the header, or REDACTED when it's a secret
+    pub fn header(&self, name: &str, value: &HeaderValue) -> String {
+        match self.is_secret_header(name, value) {
+            true => REDACTED.to_string(),
+            false => String::from_utf8_lossy(value.as_bytes()).to_string(),
+        }
+    }
+
+    /// url returns the url with the values of secret query parameters redacted
+    pub fn url(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        if !url.query_pairs().any(|(k, _)| self.is_secret_query(&k)) {
+            return url;
+        }
+
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| match self.is_secret_query(&k) {
+                true => (k.to_string(), REDACTED.to_string()),
+                false => (k.to_string(), v.to_string()),
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+        url
+    }
+}
+
 // OutputBuilder collects all the info needed to render the output once
 // kla has made the http request. (or reqwest rather)
 pub struct OutputBuilder {
@@ -15,9 +132,20 @@ pub struct OutputBuilder {
     tmpl: Tera,
     prelude: Vec<String>,
 
+    // secrets are redacted from the prelude unless they are shown
+    secrets: Secrets,
+
     // output
     prelude_output: Option<Pin<Box<dyn tokio::io::AsyncWrite>>>,
     output: Pin<Box<dyn tokio::io::AsyncWrite>>,
+    // rename moves the file written by opt_output into place once it's complete
+    rename: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for OutputBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OutputBuilder {
@@ -29,19 +157,72 @@ impl OutputBuilder {
             prelude_output: None,
             tmpl: Tera::default(),
             prelude: vec![],
+            secrets: Secrets::default(),
+            rename: None,
+        }
+    }
+
+    /// show_secrets disables the redaction of secrets in the prelude
+    pub fn show_secrets(mut self, show: bool) -> Self {
+        self.secrets = self.secrets.show(show);
+        self
+    }
+
+    /// secret_headers adds headers to redact from the prelude, on top of the known
+    /// sensitive headers. Call this before adding the prelude.
+    pub fn secret_headers<S: AsRef<str>, I: IntoIterator<Item = S>>(mut self, names: I) -> Self {
+        self.secrets = self.secrets.headers(names);
+        self
+    }
+
+    /// secret_query adds query parameters to redact from the prelude, on top of the
+    /// known sensitive parameters. Call this before adding the prelude.
+    pub fn secret_query<S: AsRef<str>, I: IntoIterator<Item = S>>(mut self, names: I) -> Self {
+        self.secrets = self.secrets.query(names);
+        self
+    }
+
+    /// secrets returns the secrets redacted from the prelude
+    pub fn secrets(&self) -> &Secrets {
+        &self.secrets
+    }
+
+    /// headers formats the headers for the prelude, redacting the secrets
+    fn headers(&self, headers: &HeaderMap) -> String {
+        let mut buf = String::new();
+        for (key, val) in headers {
+            match self.secrets.is_secret_header(key.as_str(), val) {
+                true => buf.push_str(format!("\t{}: {}\n", key.as_str(), REDACTED).as_str()),
+                false => buf.push_str(
+                    format!(
+                        "\t{}: {:?}\n",
+                        key.as_str(),
+                        String::from_utf8_lossy(val.as_bytes())
+                    )
+                    .as_str(),
+                ),
+            }
         }
+        buf
     }
 
     /// opt_output takes a command line argument and turns it into an output.
     /// the value Some(`-`) will output to standard out as will None
-    /// any value passed is interpreted as a file path, and a new file
-    /// is created.
+    /// any value passed is interpreted as a file path. The file is replaced once
+    /// the output is complete, and is only readable by the user as it often holds
+    /// tokens.
     /// This output is used as the output location of the main body or template
     /// output of the request. Defaults to standard out
     pub async fn opt_output(mut self, output: Option<&String>) -> Result<Self> {
+        self.discard_output();
         self.output = match output.map(|v| v.as_str()) {
             Some("-") => Box::pin(stdout()),
-            Some(output) => Box::pin(File::create_new(output.shell_expansion()).await?),
+            Some(output) => {
+                let path = PathBuf::from(output.shell_expansion());
+                let (file, tmp) = create_private(&path)?;
+                self.rename = Some((tmp, path));
+                Box::pin(File::from_std(file))
+            }
             None => Box::pin(stdout()),
         };
         Ok(self)
@@ -65,9 +246,18 @@ impl OutputBuilder {
     // output sets the output of kla. This defaults to standard out
     pub fn output(mut self, output: Pin<Box<dyn tokio::io::AsyncWrite>>) -> Self {
         self.output = output;
+        self.discard_output();
         self
     }
 
+    // discard_output removes the file opened by opt_output when the output is
+    // replaced before it was written
+    fn discard_output(&mut self) {
+        if let Some((tmp, _)) = self.rename.take() {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+
     pub fn request_prelude(self, req: &Request) -> Self {
         self.request_version_prelude(req)
             .method_prelude(req)
@@ -78,17 +268,14 @@ impl OutputBuilder {
 
     // header_prelude adds a header to the prelude
     pub fn request_header_prelude(mut self, req: &Request) -> Self {
-        let mut buf = String::from("Request Headers\n");
-
-        for (key, val) in req.headers() {
-            buf.push_str(format!("\t{}: {:?}\n", key.as_str(), val).as_str());
-        }
+        let buf = format!("Request Headers\n{}", self.headers(req.headers()));
         self.prelude.push(buf);
         self
     }
 
     pub fn url_prelude(mut self, req: &Request) -> Self {
-        self.prelude.push(format!("URL: {}", req.url()));
+        let url = self.secrets.url(req.url());
+        self.prelude.push(format!("URL: {}", url));
         self
     }
 
@@ -123,11 +310,7 @@ impl OutputBuilder {
 
     // header_prelude adds a header to the prelude
     pub fn response_header_prelude(mut self, resp: &Response) -> Self {
-        let mut buf = String::from("Response Headers\n");
-
-        for (key, val) in resp.headers() {
-            buf.push_str(format!("\t{}: {:?}\n", key.as_str(), val).as_str());
-        }
+        let buf = format!("Response Headers\n{}", self.headers(resp.headers()));
         self.prelude.push(buf);
         self
     }
@@ -164,13 +347,28 @@ impl OutputBuilder {
     }
 
     // build creates the output
-    pub async fn render(self, response: Response) -> Result<()> {
+    pub async fn render(mut self, response: Response) -> Result<()> {
+        let rename = self.rename.take();
+        let written = self.write(response).await.and_then(|_| match &rename {
+            Some((tmp, path)) => Ok(fs::rename(tmp, path)?),
+            None => Ok(()),
+        });
+        // a failed output leaves the file as it was
+        if let (Err(_), Some((tmp, _))) = (&written, &rename) {
+            let _ = fs::remove_file(tmp);
+        }
+        written
+    }
+
+    // write writes the prelude and the body to the outputs
+    async fn write(self, response: Response) -> Result<()> {
         let mut response = response;
         let OutputBuilder {
             tmpl,
             mut prelude_output,
             mut output,
             prelude,
+            ..
         } = self;
 
         let prelude_output = prelude_output.as_mut().unwrap_or(&mut output);
@@ -202,9 +400,90 @@ impl OutputBuilder {
             }
         }
 
+        output.flush().await?;
         Ok(())
     }
 }
 
 impl_when!(OutputBuilder);
 impl_opt!(OutputBuilder);
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+
+    use super::*;
+
+    fn request() -> Request {
+        Client::new()
+            .get("http://localhost/search?q=kla&token=t0ken&sig=s1gned")
+            .bearer_auth("b3arer")
+            .header("x-amz-security-token", "amz-t0ken")
+            .header("x-tenant-key", "t3nant")
+            .header("accept", "application/json")
+            .build()
+            .unwrap()
+    }
+
+    fn prelude(show: bool) -> String {
+        OutputBuilder::new()
+            .show_secrets(show)
+            .secret_headers(["X-Tenant-Key"])
+            .secret_query(["sig"])
+            .request_prelude(&request())
+            .prelude
+            .join("\n")
+    }
+
+    #[test]
+    fn prelude_redacts_secrets() {
+        let prelude = prelude(false);
+        for secret in ["b3arer", "amz-t0ken", "t3nant", "t0ken", "s1gned"] {
+            assert!(!prelude.contains(secret), "{} in {}", secret, prelude);
+        }
+        assert!(prelude.contains("\tauthorization: REDACTED\n"));
+        assert!(prelude.contains("\tx-amz-security-token: REDACTED\n"));
+        assert!(prelude.contains("\tx-tenant-key: REDACTED\n"));
+        assert!(prelude.contains("URL: http://localhost/search?q=kla&token=REDACTED&sig=REDACTED"));
+        assert!(prelude.contains("\taccept: \"application/json\"\n"));
+    }
+
+    #[test]
+    fn prelude_shows_secrets() {
+        let prelude = prelude(true);
+        assert!(!prelude.contains(REDACTED), "{}", prelude);
+        assert!(prelude.contains("\tauthorization: \"Bearer b3arer\"\n"));
+        assert!(prelude.contains("\tx-amz-security-token: \"amz-t0ken\"\n"));
+        assert!(prelude.contains("\tx-tenant-key: \"t3nant\"\n"));
+        assert!(prelude.contains("URL: http://localhost/search?q=kla&token=t0ken&sig=s1gned"));
+    }
+
+    #[tokio::test]
+    async fn output_replaces_the_file_privately() {
+        let dir = std::env::temp_dir().join(format!("kla-output-{}", std::process::id()));
+        let path = dir.join("token");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "an older and longer token").unwrap();
+
+        let output = path.to_str().unwrap().to_string();
+        OutputBuilder::new()
+            .opt_output(Some(&output))
+            .await
+            .unwrap()
+            .render(Response::from(http::Response::new("t0ken")))
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "t0ken");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // only the output is left in the directory
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,23 @@
-use crate::{Error, Result};
+use crate::{impl_when, read_secret, CookieJar, Error, Result};
 
+use base64::prelude::*;
 use duration_string::DurationString;
-use reqwest::{header::HeaderValue, redirect::Policy, Certificate, ClientBuilder};
+use inquire::Password;
+use log::warn;
+use p12_keystore::{KeyStore, Pkcs12ImportPolicy};
+use reqwest::{header::HeaderValue, redirect::Policy, Certificate, ClientBuilder, Identity};
 use std::str::FromStr;
-use std::{fs, path::Path, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 // KlaClientBuilder is a trait that adds additional functionality to the reqwest::ClientBuilder
 // object. These functions make it easier to marry the functionality with Clap
 pub trait KlaClientBuilder {
-    fn opt_header_agent<'a>(self, agent: Option<&'a String>) -> Result<ClientBuilder>;
+    fn opt_header_agent(self, agent: Option<&String>) -> Result<ClientBuilder>;
 
     fn opt_max_redirects(self, redirects: Option<&usize>) -> ClientBuilder;
 
@@ -28,11 +37,29 @@ pub trait KlaClientBuilder {
         userpass: Option<&String>,
     ) -> Result<ClientBuilder>;
 
-    fn connect_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder>;
+    fn opt_connect_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder>;
+
+    fn opt_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder>;
+
+    fn opt_client_pkcs12(
+        self,
+        pkcs12: Option<&String>,
+        password: Option<&String>,
+    ) -> Result<ClientBuilder>;
+
+    fn opt_client_certificate(
+        self,
+        certificate: Option<&String>,
+        key: Option<&String>,
+    ) -> Result<ClientBuilder>;
 
     fn opt_certificate<'a, T>(self, certificates: Option<T>) -> Result<ClientBuilder>
     where
         T: Iterator<Item = &'a String>;
+
+    fn no_system_roots(self, no_system_roots: bool) -> ClientBuilder;
+
+    fn opt_cookie_jar(self, jar: Option<Arc<CookieJar>>) -> ClientBuilder;
 }
 
 // Implementation of the trait to extend ClientBuilder
@@ -41,7 +68,7 @@ impl KlaClientBuilder for ClientBuilder {
     where
         T: Iterator<Item = &'a String>,
     {
-        if let None = certificates {
+        if certificates.is_none() {
             return Ok(self);
         }
         let certificates = certificates.unwrap();
@@ -49,30 +76,62 @@ impl KlaClientBuilder for ClientBuilder {
         let mut me = self;
 
         for certificate in certificates {
-            let ext = Path::new(certificate).extension().and_then(|s| s.to_str());
-            match ext {
-                Some("pem") => {
-                    let pem = fs::read_to_string(certificate)?;
-                    let certificate = Certificate::from_pem(pem.as_bytes())?;
-                    me = me.add_root_certificate(certificate);
-                }
-                Some("der") => {
-                    let pem = fs::read_to_string(certificate)?;
-                    let certificate = Certificate::from_der(pem.as_bytes())?;
+            let path = Path::new(certificate);
+            if !path.is_dir() {
+                for certificate in read_certificates(path)? {
                     me = me.add_root_certificate(certificate);
                 }
-                _ => {
-                    return Err(Error::from(format!(
-                        "Invalid certificate file extension: {}",
-                        certificate
-                    )))
+                continue;
+            }
+
+            // a directory is treated as a collection of certificates, every file
+            // within it is loaded. Directories like /etc/ssl/certs hold other files
+            // too, so files which aren't certificates are skipped.
+            let mut files = fs::read_dir(path)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<std::result::Result<Vec<PathBuf>, std::io::Error>>()?;
+            files.retain(|f| f.is_file());
+            files.sort();
+
+            let mut found = false;
+            for file in files {
+                match read_certificates(&file) {
+                    Ok(certificates) => {
+                        for certificate in certificates {
+                            me = me.add_root_certificate(certificate);
+                            found = true;
+                        }
+                    }
+                    Err(err) => warn!("skipping {}: {}", file.display(), err),
                 }
             }
+
+            if !found {
+                return Err(Error::from(format!(
+                    "{} does not contain any certificates",
+                    path.display()
+                )));
+            }
         }
 
         Ok(me)
     }
 
+    fn no_system_roots(self, no_system_roots: bool) -> ClientBuilder {
+        if no_system_roots {
+            self.tls_built_in_root_certs(false)
+        } else {
+            self
+        }
+    }
+
+    fn opt_cookie_jar(self, jar: Option<Arc<CookieJar>>) -> ClientBuilder {
+        match jar {
+            Some(jar) => self.cookie_provider(jar),
+            None => self,
+        }
+    }
+
     fn no_redirects(self, no_redirects: bool) -> ClientBuilder {
         if no_redirects {
             self.redirect(Policy::none())
@@ -82,7 +141,7 @@ impl KlaClientBuilder for ClientBuilder {
     }
 
     fn opt_max_redirects(self, redirects: Option<&usize>) -> ClientBuilder {
-        if let None = redirects {
+        if redirects.is_none() {
             return self;
         }
 
@@ -90,8 +149,8 @@ impl KlaClientBuilder for ClientBuilder {
         self.redirect(Policy::limited(*redirects))
     }
 
-    fn opt_header_agent<'a>(self, agent: Option<&'a String>) -> Result<ClientBuilder> {
-        if let None = agent {
+    fn opt_header_agent(self, agent: Option<&String>) -> Result<ClientBuilder> {
+        if agent.is_none() {
             return Ok(self);
         }
         let agent = HeaderValue::from_str(agent.unwrap())?;
@@ -99,16 +158,17 @@ impl KlaClientBuilder for ClientBuilder {
     }
 
     fn opt_proxy(self, proxy: Option<&String>, userpass: Option<&String>) -> Result<ClientBuilder> {
-        if let None = proxy {
+        if proxy.is_none() {
             return Ok(self);
         }
 
         let proxy = reqwest::Proxy::all(proxy.unwrap())?;
-        if let None = userpass {
+        if userpass.is_none() {
             return Ok(self.proxy(proxy));
         }
 
-        let mut parts = userpass.unwrap().splitn(2, ":");
+        let userpass = read_secret(userpass.unwrap())?;
+        let mut parts = userpass.splitn(2, ":");
 
         Ok(self.proxy(proxy.basic_auth(parts.next().unwrap(), parts.next().unwrap_or_default())))
     }
@@ -118,16 +178,17 @@ impl KlaClientBuilder for ClientBuilder {
         proxy: Option<&String>,
         userpass: Option<&String>,
     ) -> Result<ClientBuilder> {
-        if let None = proxy {
+        if proxy.is_none() {
             return Ok(self);
         }
 
         let proxy = reqwest::Proxy::http(proxy.unwrap())?;
-        if let None = userpass {
+        if userpass.is_none() {
             return Ok(self.proxy(proxy));
         }
 
-        let mut parts = userpass.unwrap().splitn(2, ":");
+        let userpass = read_secret(userpass.unwrap())?;
+        let mut parts = userpass.splitn(2, ":");
 
         Ok(self.proxy(proxy.basic_auth(parts.next().unwrap(), parts.next().unwrap_or_default())))
     }
@@ -137,21 +198,89 @@ impl KlaClientBuilder for ClientBuilder {
         proxy: Option<&String>,
         userpass: Option<&String>,
     ) -> Result<ClientBuilder> {
-        if let None = proxy {
+        if proxy.is_none() {
             return Ok(self);
         }
 
         let proxy = reqwest::Proxy::https(proxy.unwrap())?;
-        if let None = userpass {
+        if userpass.is_none() {
             return Ok(self.proxy(proxy));
         }
 
-        let mut parts = userpass.unwrap().splitn(2, ":");
+        let userpass = read_secret(userpass.unwrap())?;
+        let mut parts = userpass.splitn(2, ":");
         Ok(self.proxy(proxy.basic_auth(parts.next().unwrap(), parts.next().unwrap_or_default())))
     }
 
-    fn connect_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder> {
-        if let None = timeout {
+    fn opt_client_certificate(
+        self,
+        certificate: Option<&String>,
+        key: Option<&String>,
+    ) -> Result<ClientBuilder> {
+        let certificate = match certificate {
+            Some(certificate) => certificate,
+            None => return Ok(self),
+        };
+
+        // the private key may be in the certificate file, or in a file of its own.
+        // PKCS#8, PKCS#1 (RSA) and SEC1 (EC) keys are all accepted.
+        let mut pem = fs::read(certificate)?;
+        if let Some(key) = key {
+            pem.push(b'\n');
+            pem.extend(fs::read(key)?);
+        }
+
+        Ok(self.identity(Identity::from_pem(&pem)?))
+    }
+
+    fn opt_client_pkcs12(
+        self,
+        pkcs12: Option<&String>,
+        password: Option<&String>,
+    ) -> Result<ClientBuilder> {
+        let pkcs12 = match pkcs12 {
+            Some(pkcs12) => pkcs12,
+            None => return Ok(self),
+        };
+
+        let password = match password {
+            Some(password) => password.clone(),
+            None => Password::new(&format!("Password for {}:", pkcs12))
+                .without_confirmation()
+                .prompt()
+                .map_err(|err| Error::InvalidArguments(Box::new(err)))?,
+        };
+
+        let der = fs::read(pkcs12)?;
+        let keystore = KeyStore::from_pkcs12(&der, &password, Pkcs12ImportPolicy::Strict)
+            .map_err(|err| Error::from(format!("{} could not be read: {}", pkcs12, err)))?;
+        let (_, chain) = keystore
+            .private_key_chain()
+            .ok_or_else(|| Error::from(format!("{} does not contain a private key", pkcs12)))?;
+
+        // rustls only takes pem identities, so the key and chain are written as pem
+        let mut pem = pem_block("PRIVATE KEY", chain.key().as_der());
+        for certificate in chain.certs() {
+            pem.push_str(&pem_block("CERTIFICATE", certificate.as_der()));
+        }
+        Ok(self.identity(Identity::from_pem(pem.as_bytes())?))
+    }
+
+    fn opt_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder> {
+        if timeout.is_none() {
+            return Ok(self);
+        }
+
+        let timeout: Duration = match DurationString::from_str(timeout.unwrap()) {
+            Ok(v) => Ok(v),
+            Err(msg) => Err(Error::from(msg.as_str())),
+        }?
+        .into();
+        Ok(self.timeout(timeout))
+    }
+
+    fn opt_connect_timeout(self, timeout: Option<&String>) -> Result<ClientBuilder> {
+        if timeout.is_none() {
             return Ok(self);
         }
 
@@ -163,3 +292,321 @@ impl KlaClientBuilder for ClientBuilder {
         Ok(self.connect_timeout(timeout))
     }
 }
+
+/// read_certificates reads every certificate from the file. The contents are sniffed
+/// to decide the format, anything with a pem header is read as a (possibly multi
+/// certificate) pem bundle, and everything else is assumed to be DER.
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let data = fs::read(path)?;
+    let invalid = |err| {
+        Error::from(format!(
+            "{} is not a valid certificate: {}",
+            path.display(),
+            err
+        ))
+    };
+
+    if data.windows(11).any(|w| w == b"-----BEGIN ") {
+        let certificates = Certificate::from_pem_bundle(&data).map_err(invalid)?;
+        if certificates.is_empty() {
+            return Err(Error::from(format!(
+                "{} does not contain any certificates",
+                path.display()
+            )));
+        }
+        Ok(certificates)
+    } else if der_sequence(&data) {
+        Ok(vec![Certificate::from_der(&data).map_err(invalid)?])
+    } else {
+        Err(Error::from(format!(
+            "{} is not a PEM or DER certificate",
+            path.display()
+        )))
+    }
+}
+
+/// der_sequence returns true when the data is a single DER sequence, as a DER
+/// certificate is. The certificate itself is only parsed once the client is built,
+/// so this keeps other files from being taken for certificates.
+fn der_sequence(data: &[u8]) -> bool {
+    let (length, header) = match data {
+        [0x30, length, ..] if *length < 0x80 => (*length as usize, 2),
+        [0x30, size, rest @ ..] if (0x81..=0x84).contains(size) => {
+            let size = (*size & 0x7f) as usize;
+            match rest.get(..size) {
+                Some(bytes) => (
+                    bytes.iter().fold(0, |length, b| length << 8 | *b as usize),
+                    2 + size,
+                ),
+                None => return false,
+            }
+        }
+        _ => return false,
+    };
+    header + length == data.len()
+}
+
+/// pem_block encodes der data as a pem block with the label
+fn pem_block(label: &str, der: &[u8]) -> String {
+    let encoded = BASE64_STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+impl_when!(ClientBuilder);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// serve starts a tls listener, requiring a client certificate signed by the
+    /// test ca when verify_clients is set. It answers a single request and returns
+    /// the url to send it to
+    async fn serve(verify_clients: bool) -> String {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap())
+            .unwrap();
+        let verifier = match verify_clients {
+            true => WebPkiClientVerifier::builder_with_provider(roots.into(), provider.clone())
+                .build()
+                .unwrap(),
+            false => WebPkiClientVerifier::no_client_auth(),
+        };
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![CertificateDer::from_pem_file(fixture("server.pem")).unwrap()],
+                PrivateKeyDer::from_pem_file(fixture("server.key")).unwrap(),
+            )
+            .unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let Ok(mut stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                .await;
+            let _ = stream.shutdown().await;
+        });
+
+        url
+    }
+
+    fn client() -> ClientBuilder {
+        ClientBuilder::new()
+            .no_system_roots(true)
+            .opt_certificate(Some([fixture("ca.pem")].iter()))
+            .unwrap()
+    }
+
+    async fn send(builder: ClientBuilder) -> reqwest::Result<String> {
+        let url = serve(true).await;
+        builder.build()?.get(url).send().await?.text().await
+    }
+
+    /// send_trusting sends a request to a server which doesn't ask for a client
+    /// certificate, trusting only the certificates at paths
+    async fn send_trusting(paths: &[String]) -> reqwest::Result<String> {
+        let url = serve(false).await;
+        ClientBuilder::new()
+            .no_system_roots(true)
+            .opt_certificate(Some(paths.iter()))
+            .expect("valid certificates")
+            .build()?
+            .get(url)
+            .send()
+            .await?
+            .text()
+            .await
+    }
+
+    /// temp_dir returns an empty directory unique to the test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kla-certs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn certificate_and_key_in_one_file() {
+        let builder = client()
+            .opt_client_certificate(Some(&fixture("client-rsa.combined.pem")), None)
+            .unwrap();
+        assert_eq!(send(builder).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn certificate_and_pkcs8_key() {
+        let builder = client()
+            .opt_client_certificate(
+                Some(&fixture("client-rsa.pem")),
+                Some(&fixture("client-rsa.pkcs8.key")),
+            )
+            .unwrap();
+        assert_eq!(send(builder).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn certificate_and_rsa_key() {
+        let builder = client()
+            .opt_client_certificate(
+                Some(&fixture("client-rsa.pem")),
+                Some(&fixture("client-rsa.pkcs1.key")),
+            )
+            .unwrap();
+        assert_eq!(send(builder).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn certificate_and_ec_key() {
+        let builder = client()
+            .opt_client_certificate(
+                Some(&fixture("client-ec.pem")),
+                Some(&fixture("client-ec.sec1.key")),
+            )
+            .unwrap();
+        assert_eq!(send(builder).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn pkcs12() {
+        let builder = client()
+            .opt_client_pkcs12(Some(&fixture("client-rsa.p12")), Some(&"kla-test".into()))
+            .unwrap();
+        assert_eq!(send(builder).await.unwrap(), "ok");
+    }
+
+    #[test]
+    fn pkcs12_wrong_password() {
+        let result = ClientBuilder::new()
+            .opt_client_pkcs12(Some(&fixture("client-rsa.p12")), Some(&"wrong".into()));
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_client_certificate() {
+        assert!(send(client()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn der_certificate() {
+        let dir = temp_dir("der");
+        let der = dir.join("ca.der");
+        fs::write(
+            &der,
+            CertificateDer::from_pem_file(fixture("ca.pem")).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(read_certificates(&der).unwrap().len(), 1);
+        let result = send_trusting(&[der.display().to_string()]).await;
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(result.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn pem_bundle() {
+        let dir = temp_dir("bundle");
+        let bundle = dir.join("bundle.pem");
+        let pem = [fixture("client-ec.pem"), fixture("ca.pem")]
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect::<String>();
+        fs::write(&bundle, pem).unwrap();
+
+        assert_eq!(read_certificates(&bundle).unwrap().len(), 2);
+        let result = send_trusting(&[bundle.display().to_string()]).await;
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(result.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn directory_of_certificates() {
+        let dir = temp_dir("directory");
+        fs::copy(fixture("client-ec.pem"), dir.join("client-ec.pem")).unwrap();
+        fs::copy(fixture("ca.pem"), dir.join("ca.pem")).unwrap();
+        // files which aren't certificates are skipped
+        fs::write(dir.join("README"), "certificates trusted by kla\n").unwrap();
+        fs::copy(fixture("server.key"), dir.join("server.key")).unwrap();
+
+        let result = send_trusting(&[dir.display().to_string()]).await;
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(result.unwrap(), "ok");
+    }
+
+    #[test]
+    fn directory_without_certificates() {
+        let dir = temp_dir("empty");
+        fs::write(dir.join("README"), "nothing to see\n").unwrap();
+
+        let result = ClientBuilder::new().opt_certificate(Some([dir.display().to_string()].iter()));
+        fs::remove_dir_all(dir).unwrap();
+        let err = result.expect_err("no certificates");
+        assert!(err
+            .to_string()
+            .contains("does not contain any certificates"));
+    }
+
+    #[test]
+    fn not_a_certificate() {
+        let err = read_certificates(Path::new(&fixture("server.key"))).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not contain any certificates"));
+
+        let dir = temp_dir("text");
+        let text = dir.join("ca.crt");
+        fs::write(&text, b"\x30\x82\x01\x00 is too short").unwrap();
+        let result = read_certificates(&text);
+        fs::remove_dir_all(dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_system_roots_without_certificates() {
+        let url = serve(false).await;
+        let result = ClientBuilder::new()
+            .no_system_roots(true)
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use duration_string::DurationString;
 use http::Version;
 use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH},
+    multipart::{Form, Part},
     Body, RequestBuilder,
 };
 use std::str::FromStr;
@@ -9,10 +10,128 @@ use std::{
     collections::HashMap,
     fs,
     io::{self, Read},
+    path::Path,
+    process::{self, Stdio},
+    sync::{Mutex, OnceLock},
     time::Duration,
 };
+use tokio_util::io::ReaderStream;
+
+use crate::{impl_opt, Error, Expand, RenderGroup, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// SecretSource is where the value of a credential, like `--basic-auth`,
+/// `--bearer-token` or `--proxy-auth`, is read from. Parse it from the value given
+/// by the user:
+/// - `@path` reads the file at path, with any trailing whitespace removed.
+/// - `-` reads standard in, with any trailing whitespace removed.
+/// - `cmd:command` runs the command with `sh` and uses its trimmed output.
+/// - anything else is the secret itself.
+pub enum SecretSource {
+    Value(String),
+    File(String),
+    Stdin,
+    Command(String),
+}
+
+/// SECRET_COMMANDS holds the output of the `cmd:` secrets that have been run
+static SECRET_COMMANDS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// SECRET_STDIN holds the secret read from standard in, which can only be read once
+static SECRET_STDIN: OnceLock<String> = OnceLock::new();
+
+/// STDIN_READER is what standard in is read for, either a secret or the body
+static STDIN_READER: OnceLock<&'static str> = OnceLock::new();
+
+impl From<&str> for SecretSource {
+    fn from(value: &str) -> Self {
+        if value == "-" {
+            return Self::Stdin;
+        }
+        if let Some(command) = value.strip_prefix("cmd:") {
+            return Self::Command(command.trim().to_string());
+        }
+        match value.strip_prefix('@') {
+            Some(path) => Self::File(path.to_string()),
+            None => Self::Value(value.to_string()),
+        }
+    }
+}
+
+impl SecretSource {
+    /// read returns the secret. Commands and standard in are only read once, later
+    /// reads return the same secret.
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::File(path) => read_trimmed(fs::File::open(path.shell_expansion())?),
+            Self::Stdin => {
+                claim_stdin(&STDIN_READER, "a secret")?;
+                if let Some(secret) = SECRET_STDIN.get() {
+                    return Ok(secret.clone());
+                }
+                let secret = read_trimmed(io::stdin())?;
+                Ok(SECRET_STDIN.get_or_init(|| secret).clone())
+            }
+            Self::Command(command) => secret_command(command),
+        }
+    }
+}
+
+/// read_secret returns the secret of the value, see `SecretSource`
+pub(crate) fn read_secret(value: &str) -> Result<String> {
+    SecretSource::from(value).read()
+}
+
+/// claim_stdin records that standard in is read for reader. Standard in can only be
+/// read once, so it's an error for anything else to have read it already.
+fn claim_stdin(claimed: &OnceLock<&'static str>, reader: &'static str) -> Result<()> {
+    match *claimed.get_or_init(|| reader) {
+        claimant if claimant == reader => Ok(()),
+        claimant => Err(Error::from(format!(
+            "standard in can't be read for both {} and {}, read one of them from a file",
+            claimant, reader
+        ))),
+    }
+}
+
+/// read_trimmed reads everything from the reader, removing trailing whitespace
+fn read_trimmed<R: Read>(mut reader: R) -> Result<String> {
+    let mut secret = String::new();
+    reader.read_to_string(&mut secret)?;
+    secret.truncate(secret.trim_end().len());
+    Ok(secret)
+}
+
+/// secret_command runs the command with `sh`, returning the trimmed standard out.
+/// Standard in and error are left attached so the command can prompt the user.
+fn secret_command(command: &str) -> Result<String> {
+    let cache = SECRET_COMMANDS.get_or_init(Default::default);
+    if let Some(secret) = cache.lock().expect("not poisoned").get(command) {
+        return Ok(secret.clone());
+    }
 
-use crate::{impl_opt, Error, RenderGroup, Result};
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::from(format!(
+            "secret command `{}` failed with {}",
+            command, output.status
+        )));
+    }
+
+    let secret = std::str::from_utf8(&output.stdout)?.trim().to_string();
+    cache
+        .lock()
+        .expect("not poisoned")
+        .insert(command.to_string(), secret.clone());
+    Ok(secret)
+}
 
 #[derive(Debug, Clone)]
 /// KeyValue enables you to turn a string like `key=value` into an actual key value
@@ -49,6 +168,21 @@ impl TryFrom<&String> for KeyValue {
     }
 }
 
+impl KeyValue {
+    /// header parses a header given as `name: value` or `name=value`. Header names
+    /// can't hold either separator, so it's split at the first of them.
+    pub fn header(value: &String) -> Result<Self> {
+        let (name, header) = value
+            .split_once([':', '='])
+            .ok_or_else(|| Error::from(format!("{value} is not a valid name: value header")))?;
+
+        Ok(KeyValue {
+            name: name.trim().into(),
+            value: header.trim().into(),
+        })
+    }
+}
+
 /// This implementation allows for a template to be turned into a
 /// key value object
 impl<'a> TryFrom<RenderGroup<'a>> for KeyValue {
@@ -65,6 +199,145 @@ impl<'a> TryFrom<RenderGroup<'a>> for KeyValue {
     }
 }
 
+#[derive(Debug, Clone)]
+/// MultipartField is a field of a multipart/form-data body, parsed from a key value
+/// like `name=value`. A value preceded by `@` is a file, which may be followed by
+/// `;type=mime` and `;filename=name` to set its content type and file name, e.g.
+/// `name=@path;type=mime;filename=x`. Files are streamed from disk as the request
+/// is sent.
+pub struct MultipartField {
+    name: String,
+    value: MultipartValue,
+}
+
+#[derive(Debug, Clone)]
+enum MultipartValue {
+    Text(String),
+    File {
+        path: String,
+        mime: Option<String>,
+        filename: Option<String>,
+    },
+}
+
+impl TryFrom<KeyValue> for MultipartField {
+    type Error = crate::Error;
+
+    fn try_from(kv: KeyValue) -> Result<Self> {
+        let file = match kv.value.strip_prefix('@') {
+            Some(file) => file,
+            None => {
+                return Ok(MultipartField {
+                    name: kv.name,
+                    value: MultipartValue::Text(kv.value),
+                })
+            }
+        };
+
+        let mut params = file.split(';');
+        let path = params.next().unwrap_or_default().trim().to_string();
+        let (mut mime, mut filename) = (None, None);
+        for param in params {
+            match param.trim().split_once('=') {
+                Some(("type", value)) => mime = Some(value.trim().to_string()),
+                Some(("filename", value)) => {
+                    filename = Some(value.trim().trim_matches('"').to_string())
+                }
+                _ => {
+                    return Err(Error::from(format!(
+                        "{} has an unknown parameter {:?}, expected type= or filename=",
+                        kv.name, param
+                    )))
+                }
+            }
+        }
+
+        Ok(MultipartField {
+            name: kv.name,
+            value: MultipartValue::File {
+                path,
+                mime,
+                filename,
+            },
+        })
+    }
+}
+
+impl MultipartField {
+    /// parse returns the multipart fields of the key values
+    pub fn parse<E, T, V>(fields: Option<T>) -> Result<Vec<Self>>
+    where
+        E: Into<Error>,
+        V: TryInto<KeyValue, Error = E>,
+        T: Iterator<Item = V>,
+    {
+        fields
+            .into_iter()
+            .flatten()
+            .map(|item| Self::try_from(item.try_into().map_err(|err| err.into())?))
+            .collect()
+    }
+
+    /// name returns the name of the field
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// text returns the value of a text field
+    pub fn text(&self) -> Option<&str> {
+        match &self.value {
+            MultipartValue::Text(text) => Some(text),
+            MultipartValue::File { .. } => None,
+        }
+    }
+
+    /// file returns the path, content type and file name of a file field. The
+    /// content type is guessed from the extension when it isn't given, and the file
+    /// name is taken from the path.
+    pub fn file(&self) -> Option<(String, String, String)> {
+        let MultipartValue::File {
+            path,
+            mime,
+            filename,
+        } = &self.value
+        else {
+            return None;
+        };
+
+        let path = path.shell_expansion();
+        let mime = mime.clone().unwrap_or_else(|| {
+            mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string()
+        });
+        let filename = filename.clone().unwrap_or_else(|| {
+            Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+        Some((path, mime, filename))
+    }
+
+    /// part returns the part of the form. Files are opened but not read.
+    fn part(self) -> Result<(String, Part)> {
+        let part = match self.file() {
+            None => Part::text(self.text().unwrap_or_default().to_string()),
+            Some((path, mime, filename)) => {
+                let file = fs::File::open(&path)
+                    .map_err(|err| Error::from(format!("could not open {}: {}", path, err)))?;
+                let length = file.metadata()?.len();
+
+                Part::stream_with_length(Body::from(tokio::fs::File::from_std(file)), length)
+                    .file_name(filename)
+                    .mime_str(&mime)?
+            }
+        };
+
+        Ok((self.name, part))
+    }
+}
+
 // This allows us to extend the reqwest RequestBuilder so that we can pass data from clap
 // directly into it, creating a seamless interface. This implementation leaves the raw data
 // within clap, and greatly reduces the number of copies needed.
@@ -89,11 +362,19 @@ pub trait KlaRequestBuilder {
         V: TryInto<KeyValue, Error = E>,
         T: Iterator<Item = V>;
 
-    fn opt_body<'a>(self, body: Option<&String>) -> Result<RequestBuilder>;
+    // opt_multipart sends the fields as a multipart/form-data body, replacing any
+    // other body. All fields must be given in a single call.
+    fn opt_multipart<E, T, V>(self, fields: Option<T>) -> Result<RequestBuilder>
+    where
+        E: Into<Error>,
+        V: TryInto<KeyValue, Error = E>,
+        T: Iterator<Item = V>;
+
+    fn opt_body(self, body: Option<&String>) -> Result<RequestBuilder>;
 
-    fn opt_basic_auth(self, userpass: Option<&String>) -> RequestBuilder;
+    fn opt_basic_auth(self, userpass: Option<&String>) -> Result<RequestBuilder>;
 
-    fn opt_bearer_auth(self, token: Option<&String>) -> RequestBuilder;
+    fn opt_bearer_auth(self, token: Option<&String>) -> Result<RequestBuilder>;
 
     fn opt_timeout(self, timeout: Option<&String>) -> Result<RequestBuilder>;
 
@@ -102,7 +383,7 @@ pub trait KlaRequestBuilder {
 
 impl KlaRequestBuilder for RequestBuilder {
     fn opt_version(self, version: Option<&String>) -> Result<RequestBuilder> {
-        if let None = version {
+        if version.is_none() {
             return Ok(self);
         }
 
@@ -119,7 +400,7 @@ impl KlaRequestBuilder for RequestBuilder {
     }
 
     fn opt_timeout(self, timeout: Option<&String>) -> Result<RequestBuilder> {
-        if let None = timeout {
+        if timeout.is_none() {
             return Ok(self);
         }
 
@@ -135,47 +416,51 @@ impl KlaRequestBuilder for RequestBuilder {
         Ok(self.timeout(d))
     }
 
-    fn opt_basic_auth(self, userpass: Option<&String>) -> RequestBuilder {
-        if let None = userpass {
-            return self;
+    fn opt_basic_auth(self, userpass: Option<&String>) -> Result<RequestBuilder> {
+        if userpass.is_none() {
+            return Ok(self);
         }
-        let userpass = userpass.unwrap();
+        let userpass = read_secret(userpass.unwrap())?;
         let mut parts = userpass.splitn(2, ":");
-        self.basic_auth(parts.next().unwrap(), parts.next())
+        Ok(self.basic_auth(parts.next().unwrap(), parts.next()))
     }
 
-    fn opt_bearer_auth(self, token: Option<&String>) -> RequestBuilder {
-        if let None = token {
-            return self;
+    fn opt_bearer_auth(self, token: Option<&String>) -> Result<RequestBuilder> {
+        if token.is_none() {
+            return Ok(self);
         }
 
-        self.bearer_auth(token.unwrap())
+        Ok(self.bearer_auth(read_secret(token.unwrap())?))
     }
 
-    fn opt_body<'a>(self, body: Option<&String>) -> Result<RequestBuilder> {
-        if let None = body {
+    // opt_body sets the body. Files and standard in are streamed as the request is
+    // sent, rather than read into memory, so they may be binary and any size.
+    fn opt_body(self, body: Option<&String>) -> Result<RequestBuilder> {
+        if body.is_none() {
             return Ok(self);
         }
         let body = body.unwrap();
 
         let mut body_chars = body.chars();
 
-        let body = match body_chars.next() {
+        match body_chars.next() {
             Some('@') => {
-                let name = body_chars.collect::<String>();
-                Some(Body::from(fs::read_to_string(name)?))
+                let name = body_chars.collect::<String>().shell_expansion();
+                let file = fs::File::open(&name)
+                    .map_err(|err| Error::from(format!("could not open {}: {}", name, err)))?;
+                let length = file.metadata()?.len();
+                let stream = ReaderStream::new(tokio::fs::File::from_std(file));
+                Ok(self
+                    .header(CONTENT_LENGTH, length)
+                    .body(Body::wrap_stream(stream)))
             }
             Some('-') => {
-                let mut buf = String::new();
-                io::stdin().read_to_string(&mut buf)?;
-                Some(Body::from(buf))
+                claim_stdin(&STDIN_READER, "the body")?;
+                Ok(self.body(Body::wrap_stream(ReaderStream::new(tokio::io::stdin()))))
             }
-            Some(_) => Some(Body::from(body.to_owned())),
-            None => None,
+            Some(_) => Ok(self.body(Body::from(body.to_owned()))),
+            None => Err(Error::from("you must supply a body")),
         }
-        .ok_or(Error::from("you must supply a body"))?;
-
-        Ok(self.body(body))
     }
 
     fn opt_query<E, T, V>(self, query: Option<T>) -> Result<RequestBuilder>
@@ -190,17 +475,18 @@ impl KlaRequestBuilder for RequestBuilder {
             return Ok(self);
         };
 
-        let mut map = HashMap::new();
+        // pairs keep every value of a repeated name, in the order given
+        let mut pairs = vec![];
 
         for item in query {
             let item: KeyValue = item.try_into().map_err(|err| err.into())?;
-            map.insert(item.name, item.value);
+            pairs.push((item.name, item.value));
         }
 
-        if map.is_empty() {
+        if pairs.is_empty() {
             Ok(self)
         } else {
-            Ok(self.query(&map))
+            Ok(self.query(&pairs))
         }
     }
 
@@ -216,20 +502,41 @@ impl KlaRequestBuilder for RequestBuilder {
             return Ok(self);
         };
 
-        let mut map = HashMap::new();
+        // pairs keep every value of a repeated name, in the order given
+        let mut pairs = vec![];
 
         for item in form {
             let item: KeyValue = item.try_into().map_err(|err| err.into())?;
-            map.insert(item.name, item.value);
+            pairs.push((item.name, item.value));
         }
 
-        if map.is_empty() {
+        if pairs.is_empty() {
             Ok(self)
         } else {
-            Ok(self.form(&map))
+            Ok(self.form(&pairs))
         }
     }
 
+    fn opt_multipart<E, T, V>(self, fields: Option<T>) -> Result<RequestBuilder>
+    where
+        E: Into<Error>,
+        V: TryInto<KeyValue, Error = E>,
+        T: Iterator<Item = V>,
+    {
+        let fields = MultipartField::parse(fields)?;
+        if fields.is_empty() {
+            return Ok(self);
+        }
+
+        let mut form = Form::new();
+        for field in fields {
+            let (name, part) = field.part()?;
+            form = form.part(name, part);
+        }
+
+        Ok(self.multipart(form))
+    }
+
     fn opt_headers<E, T, V>(self, headers: Option<T>) -> Result<RequestBuilder>
     where
         E: Into<Error>,
@@ -246,7 +553,8 @@ impl KlaRequestBuilder for RequestBuilder {
 
         for item in headers {
             let item: KeyValue = item.try_into().map_err(|err| err.into())?;
-            map.insert(
+            // append keeps every value of a repeated header
+            map.append(
                 HeaderName::try_from(item.name)?,
                 HeaderValue::try_from(item.value)?,
             );
@@ -261,3 +569,350 @@ impl KlaRequestBuilder for RequestBuilder {
 }
 
 impl_opt!(RequestBuilder);
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use base64::prelude::*;
+    use reqwest::{header::AUTHORIZATION, Client};
+
+    use super::*;
+
+    /// secret_file writes the contents into a file unique to the test
+    fn secret_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kla-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).expect("writable temp dir");
+        path
+    }
+
+    /// authorization builds the request and returns its Authorization header
+    fn authorization(builder: Result<RequestBuilder>) -> String {
+        let request = builder
+            .expect("valid secret")
+            .build()
+            .expect("valid request");
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .expect("authorization header")
+            .to_str()
+            .expect("ascii header")
+            .to_string()
+    }
+
+    #[test]
+    fn parses_secret_sources() {
+        assert_eq!(
+            SecretSource::from("hunter2"),
+            SecretSource::Value("hunter2".into())
+        );
+        assert_eq!(
+            SecretSource::from("@~/token"),
+            SecretSource::File("~/token".into())
+        );
+        assert_eq!(SecretSource::from("-"), SecretSource::Stdin);
+        assert_eq!(
+            SecretSource::from("cmd: pass show api"),
+            SecretSource::Command("pass show api".into())
+        );
+        // only a lone dash reads standard in
+        assert_eq!(
+            SecretSource::from("-abc"),
+            SecretSource::Value("-abc".into())
+        );
+    }
+
+    #[test]
+    fn reads_files_without_trailing_whitespace() {
+        let path = secret_file("file", "  token with spaces \n\n");
+        let secret = read_secret(&format!("@{}", path.display()));
+        fs::remove_file(&path).ok();
+
+        assert_eq!(secret.expect("readable file"), "  token with spaces");
+    }
+
+    #[test]
+    fn fails_on_missing_files() {
+        assert!(read_secret("@/this/file/does/not/exist").is_err());
+    }
+
+    #[tokio::test]
+    async fn body_streams_files() {
+        let path = secret_file("body", "{\"a\": 1}");
+        let request = Client::new()
+            .post("http://localhost/")
+            .opt_body(Some(&format!("@{}", path.display())))
+            .expect("readable file")
+            .build()
+            .expect("valid request");
+        fs::remove_file(&path).ok();
+
+        assert_eq!(request.headers()[CONTENT_LENGTH], "8");
+        assert!(request.body().expect("body").as_bytes().is_none());
+        assert!(Client::new()
+            .post("http://localhost/")
+            .opt_body(Some(&"@/this/file/does/not/exist".into()))
+            .is_err());
+    }
+
+    #[test]
+    fn stdin_is_read_once() {
+        let claimed = OnceLock::new();
+        assert!(claim_stdin(&claimed, "a secret").is_ok());
+        assert!(claim_stdin(&claimed, "a secret").is_ok());
+        let err = claim_stdin(&claimed, "the body").unwrap_err();
+        assert!(err.to_string().contains("both a secret and the body"));
+
+        let claimed = OnceLock::new();
+        assert!(claim_stdin(&claimed, "the body").is_ok());
+        assert!(claim_stdin(&claimed, "a secret").is_err());
+    }
+
+    #[test]
+    fn reads_without_trailing_whitespace() {
+        let secret = read_trimmed("user:pass\r\n".as_bytes()).expect("readable");
+        assert_eq!(secret, "user:pass");
+    }
+
+    #[test]
+    fn runs_commands() {
+        assert_eq!(
+            read_secret("cmd:echo '  s3cret  '").expect("command runs"),
+            "s3cret"
+        );
+        assert!(read_secret("cmd:exit 3").is_err());
+    }
+
+    #[test]
+    fn bearer_auth_reads_the_secret() {
+        let path = secret_file("bearer", "abc123\n");
+        let token = format!("@{}", path.display());
+        let header = authorization(
+            Client::new()
+                .get("http://localhost/")
+                .opt_bearer_auth(Some(&token)),
+        );
+        fs::remove_file(&path).ok();
+
+        assert_eq!(header, "Bearer abc123");
+    }
+
+    #[test]
+    fn basic_auth_reads_the_secret() {
+        let path = secret_file("basic", "user:pass\n");
+        let userpass = format!("@{}", path.display());
+        let header = authorization(
+            Client::new()
+                .get("http://localhost/")
+                .opt_basic_auth(Some(&userpass)),
+        );
+        fs::remove_file(&path).ok();
+
+        assert_eq!(
+            header,
+            format!("Basic {}", BASE64_STANDARD.encode("user:pass"))
+        );
+    }
+
+    fn multipart_field(value: &str) -> Result<MultipartField> {
+        MultipartField::try_from(KeyValue::try_from(&value.to_string())?)
+    }
+
+    #[test]
+    fn parses_multipart_files() {
+        let field = multipart_field("report=@~/r.csv; type=text/csv; filename=\"q1.csv\"")
+            .expect("valid field");
+        assert_eq!(field.name, "report");
+        match field.value {
+            MultipartValue::File {
+                path,
+                mime,
+                filename,
+            } => {
+                assert_eq!(path, "~/r.csv");
+                assert_eq!(mime.as_deref(), Some("text/csv"));
+                assert_eq!(filename.as_deref(), Some("q1.csv"));
+            }
+            value => panic!("expected a file, got {:?}", value),
+        }
+
+        match multipart_field("avatar=@me.png")
+            .expect("valid field")
+            .value
+        {
+            MultipartValue::File { mime, filename, .. } => {
+                assert_eq!(mime, None);
+                assert_eq!(filename, None);
+            }
+            value => panic!("expected a file, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn parses_multipart_text() {
+        let field = multipart_field("greeting=hello; world").expect("valid field");
+        assert_eq!(field.name, "greeting");
+        match field.value {
+            MultipartValue::Text(text) => assert_eq!(text, "hello; world"),
+            value => panic!("expected text, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_multipart_parameters() {
+        let err = multipart_field("report=@r.csv;charset=utf-8").unwrap_err();
+        assert!(err.to_string().contains("unknown parameter"), "{}", err);
+        assert!(multipart_field("report=@r.csv;type").is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_multipart_parts() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let path = secret_file("upload.csv", "a,b\n1,2\n");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            // the body ends with the closing boundary
+            while !String::from_utf8_lossy(&request).ends_with("--\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let fields = [
+            "greeting=hello".to_string(),
+            format!("report=@{};type=text/csv;filename=q1.csv", path.display()),
+            format!("raw=@{}", path.display()),
+        ];
+        let response = Client::new()
+            .post(&url)
+            .opt_multipart(Some(fields.iter()))
+            .expect("valid fields")
+            .send()
+            .await
+            .expect("request is sent");
+        fs::remove_file(&path).ok();
+        assert_eq!(response.status(), 200);
+
+        let request = server.await.unwrap();
+        assert!(request.contains("content-type: multipart/form-data; boundary="));
+        assert!(
+            request.contains("Content-Disposition: form-data; name=\"greeting\"\r\n\r\nhello\r\n")
+        );
+        assert!(request.contains(
+            "Content-Disposition: form-data; name=\"report\"; filename=\"q1.csv\"\r\nContent-Type: text/csv\r\n\r\na,b\n1,2\n\r\n"
+        ));
+        // without a type or filename they come from the path
+        assert!(request.contains(&format!(
+            "Content-Disposition: form-data; name=\"raw\"; filename=\"{}\"\r\nContent-Type: text/csv\r\n\r\na,b\n1,2\n\r\n",
+            path.file_name().unwrap().to_string_lossy()
+        )));
+    }
+
+    #[test]
+    fn repeated_query_params_are_kept_in_order() {
+        let query = ["tag=a", "filter_path=hits", "tag=b"].map(String::from);
+        let request = Client::new()
+            .get("http://localhost/?page=1")
+            .opt_query(Some(query.iter()))
+            .expect("valid query")
+            .build()
+            .expect("valid request");
+
+        assert_eq!(
+            request.url().query(),
+            Some("page=1&tag=a&filter_path=hits&tag=b")
+        );
+    }
+
+    #[test]
+    fn repeated_form_fields_are_kept_in_order() {
+        let form = ["tag=a", "tag=b"].map(String::from);
+        let request = Client::new()
+            .post("http://localhost/")
+            .opt_form(Some(form.iter()))
+            .expect("valid form")
+            .build()
+            .expect("valid request");
+
+        assert_eq!(
+            request.body().and_then(|body| body.as_bytes()),
+            Some("tag=a&tag=b".as_bytes())
+        );
+    }
+
+    #[test]
+    fn parses_headers() {
+        let header = |value: &str| KeyValue::header(&value.to_string()).expect("valid header");
+        let accept = header("Accept: application/json");
+        assert_eq!(
+            (accept.name.as_str(), accept.value.as_str()),
+            ("Accept", "application/json")
+        );
+        let accept = header("Accept=text/plain");
+        assert_eq!(
+            (accept.name.as_str(), accept.value.as_str()),
+            ("Accept", "text/plain")
+        );
+        // the value may hold either separator
+        let auth = header("Authorization: Basic a=:b");
+        assert_eq!(auth.value, "Basic a=:b");
+        assert!(KeyValue::header(&"Accept".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_repeated_headers() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let headers = ["Accept: a", "Accept: b"]
+            .map(|h| KeyValue::header(&h.to_string()).expect("valid header"));
+        Client::new()
+            .get(&url)
+            .opt_headers(Some(headers.into_iter()))
+            .expect("valid headers")
+            .send()
+            .await
+            .expect("request is sent");
+
+        let request = server.await.unwrap();
+        let accept = request
+            .lines()
+            .filter_map(|line| line.strip_prefix("accept: "))
+            .collect::<Vec<_>>();
+        assert_eq!(accept, ["a", "b"]);
+    }
+}
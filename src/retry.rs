@@ -0,0 +1,434 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use duration_string::DurationString;
+use http::{header::RETRY_AFTER, Method};
+use log::warn;
+use reqwest::{Body, Client, Request, Response};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+use crate::{Error, Expand, Result};
+
+/// The longest we will ever wait between two attempts, when backing off or when
+/// asked to by a `Retry-After` header
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone, Debug)]
+/// RetryPolicy decides if, and when, a request should be sent again. It can be
+/// declared in a template with a `[retry]` table, and the `--retry*` flags take
+/// precedence over anything set there.
+pub struct RetryPolicy {
+    /// retries is the number of times the request is retried, 0 disables retries
+    #[serde(rename = "retries", default)]
+    retries: u32,
+    /// on holds the response status codes which are retried
+    #[serde(rename = "on", default = "default_retry_on")]
+    on: Vec<u16>,
+    /// connection_errors retries requests which failed to connect or timed out
+    #[serde(rename = "connection_errors", default)]
+    connection_errors: bool,
+    /// all_methods retries requests with methods that are not idempotent
+    #[serde(rename = "all_methods", default)]
+    all_methods: bool,
+    /// delay is the base delay, which is doubled for every attempt
+    #[serde(rename = "delay")]
+    delay: Option<String>,
+    /// body is the file the body of the request is streamed from, it's opened again
+    /// for every attempt
+    #[serde(skip)]
+    body: Option<String>,
+}
+
+// default_retry_on specifies the status codes retried when none are supplied
+fn default_retry_on() -> Vec<u16> {
+    vec![429, 502, 503, 504]
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            on: default_retry_on(),
+            connection_errors: false,
+            all_methods: false,
+            delay: None,
+            body: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// with_args applies the `--retry*` arguments on top of the policy
+    pub fn with_args(mut self, args: &ArgMatches) -> Result<Self> {
+        if let Some(retries) = args.get_one::<u32>("retry") {
+            self.retries = *retries;
+        }
+
+        if let Some(on) = args.get_many::<u16>("retry-on") {
+            self.on = on.copied().collect();
+        }
+
+        if args.get_flag("retry-connection-errors") {
+            self.connection_errors = true;
+        }
+
+        if args.get_flag("retry-all-methods") {
+            self.all_methods = true;
+        }
+
+        if let Some(delay) = args.get_one::<String>("retry-delay") {
+            self.delay = Some(delay.clone());
+        }
+
+        if let Ok(Some(body)) = args.try_get_one::<String>("body") {
+            self.body = body.strip_prefix('@').map(|path| path.shell_expansion());
+        }
+
+        // make sure the delay is valid before any request is made
+        self.delay()?;
+
+        Ok(self)
+    }
+
+    /// delay returns the base delay between attempts, defaults to 1 second
+    fn delay(&self) -> Result<Duration> {
+        match self.delay.as_ref() {
+            Some(delay) => match DurationString::from_str(delay) {
+                Ok(v) => Ok(v.into()),
+                Err(msg) => Err(Error::from(msg)),
+            },
+            None => Ok(Duration::from_secs(1)),
+        }
+    }
+
+    /// backoff returns the time to wait before the given attempt. The delay doubles
+    /// on each attempt and half of it is randomized so many clients don't retry at
+    /// the same moment.
+    fn backoff(&self, attempt: u32) -> Result<Duration> {
+        let delay = self
+            .delay()?
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_DELAY);
+        let half = delay / 2;
+        Ok(half + half.mul_f64(fastrand::f64()))
+    }
+
+    /// retryable returns true when requests with the method can be retried. Only
+    /// idempotent methods are retried unless `all_methods` is set.
+    fn retryable(&self, method: &Method) -> bool {
+        self.all_methods
+            || matches!(
+                *method,
+                Method::GET
+                    | Method::HEAD
+                    | Method::OPTIONS
+                    | Method::TRACE
+                    | Method::PUT
+                    | Method::DELETE
+            )
+    }
+
+    /// reopen returns a copy of the request with its body streamed from the file
+    /// again, when the body is read from one
+    fn reopen(&self, request: &Request) -> Option<Request> {
+        let path = self.body.as_ref()?;
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("could not open {} again: {}", path, err);
+                return None;
+            }
+        };
+
+        let mut next = Request::new(request.method().clone(), request.url().clone());
+        *next.headers_mut() = request.headers().clone();
+        *next.timeout_mut() = request.timeout().copied();
+        *next.version_mut() = request.version();
+        *next.body_mut() = Some(Body::wrap_stream(ReaderStream::new(
+            tokio::fs::File::from_std(file),
+        )));
+        Some(next)
+    }
+
+    /// execute sends the request with the client, retrying it according to the
+    /// policy. Requests with a streaming body can't be cloned, bodies streamed from a
+    /// file are opened again and anything else is only sent once.
+    pub async fn execute(&self, client: &Client, request: Request) -> Result<Response> {
+        let mut request = request;
+        let mut attempt = 0;
+
+        loop {
+            let next = match attempt < self.retries && self.retryable(request.method()) {
+                true => {
+                    let next = request.try_clone().or_else(|| self.reopen(&request));
+                    if next.is_none() {
+                        warn!(
+                            "the body of {} is streamed and can't be sent again, it won't be retried",
+                            request.url()
+                        );
+                    }
+                    next
+                }
+                false => None,
+            };
+
+            let result = client.execute(request).await;
+            let next = match next {
+                Some(next) => next,
+                None => return Ok(result?),
+            };
+
+            let delay = match &result {
+                Ok(response) if self.on.contains(&response.status().as_u16()) => {
+                    match retry_after(response) {
+                        // the server won't be back any time soon, so the response
+                        // is returned instead of hanging for as long as it asks
+                        Some(delay) if delay > MAX_DELAY => {
+                            warn!(
+                                "{} asked to be retried in {:?}, longer than the {:?} kla waits, giving up",
+                                next.url(),
+                                delay,
+                                MAX_DELAY
+                            );
+                            return Ok(result?);
+                        }
+                        Some(delay) => delay,
+                        None => self.backoff(attempt)?,
+                    }
+                }
+                Err(err) if self.connection_errors && (err.is_connect() || err.is_timeout()) => {
+                    self.backoff(attempt)?
+                }
+                _ => return Ok(result?),
+            };
+
+            match &result {
+                Ok(response) => warn!(
+                    "{} responded with {}, retrying in {:?}",
+                    next.url(),
+                    response.status(),
+                    delay
+                ),
+                Err(err) => warn!("{} failed: {}, retrying in {:?}", next.url(), err, delay),
+            }
+
+            tokio::time::sleep(delay).await;
+            request = next;
+            attempt += 1;
+        }
+    }
+}
+
+/// retry_after reads the `Retry-After` header of the response, which is either a
+/// number of seconds or an http date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::KlaRequestBuilder;
+
+    fn policy(delay: Option<&str>) -> RetryPolicy {
+        RetryPolicy {
+            retries: 3,
+            delay: delay.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn response(retry_after: Option<&str>) -> Response {
+        let mut response = http::Response::builder();
+        if let Some(retry_after) = retry_after {
+            response = response.header(RETRY_AFTER, retry_after);
+        }
+        Response::from(response.body(Vec::<u8>::new()).unwrap())
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let policy = policy(Some("100ms"));
+        for attempt in 0..4 {
+            let full = Duration::from_millis(100 * 2u64.pow(attempt));
+            for _ in 0..50 {
+                let delay = policy.backoff(attempt).unwrap();
+                assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = policy(None);
+        for attempt in [5, 6, 31, 32, 1000] {
+            let delay = policy.backoff(attempt).unwrap();
+            assert!(delay >= MAX_DELAY / 2 && delay <= MAX_DELAY, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn invalid_delay() {
+        assert!(policy(Some("soon")).backoff(0).is_err());
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            retry_after(&response(Some(" 120 "))),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(retry_after(&response(None)), None);
+        assert_eq!(retry_after(&response(Some("soon"))), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let date = (Utc::now() + TimeDelta::seconds(90))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let delay = retry_after(&response(Some(&date))).unwrap();
+        assert!(delay > Duration::from_secs(85) && delay <= Duration::from_secs(90));
+
+        // dates in the past fall back to the backoff
+        assert_eq!(
+            retry_after(&response(Some("Sun, 06 Nov 1994 08:49:37 GMT"))),
+            None
+        );
+    }
+
+    #[test]
+    fn retryable_methods() {
+        let policy = policy(None);
+        assert!(policy.retryable(&Method::GET));
+        assert!(policy.retryable(&Method::PUT));
+        assert!(!policy.retryable(&Method::POST));
+        assert!(!policy.retryable(&Method::PATCH));
+
+        let all = RetryPolicy {
+            all_methods: true,
+            ..policy
+        };
+        assert!(all.retryable(&Method::POST));
+    }
+
+    #[tokio::test]
+    async fn execute_retries_the_status_codes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            for status in ["503 Service Unavailable", "429 Too Many Requests", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = Client::new();
+        let request = client.get(&url).build().unwrap();
+        let response = policy(None).execute(&client, request).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn execute_opens_file_bodies_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let path = std::env::temp_dir().join(format!("kla-retry-{}.txt", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+
+        let requests = tokio::spawn(async move {
+            let mut requests = vec![];
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                while !request.ends_with(b"hello") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let response = format!(
+                    "HTTP/1.1 {}\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+
+        let client = Client::new();
+        let body = format!("@{}", path.display());
+        let request = client
+            .put(&url)
+            .opt_body(Some(&body))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(request.try_clone().is_none());
+        let policy = RetryPolicy {
+            body: Some(path.to_string_lossy().to_string()),
+            ..policy(None)
+        };
+
+        let response = policy.execute(&client, request).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(response.status(), 200);
+
+        let requests = requests.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            requests[1].contains("content-length: 5\r\n"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_gives_up_on_long_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nretry-after: 86400\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let client = Client::new();
+        let request = client.get(&url).build().unwrap();
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            policy(None).execute(&client, request),
+        )
+        .await
+        .expect("doesn't wait for the retry-after")
+        .unwrap();
+        assert_eq!(response.status(), 503);
+    }
+}
@@ -6,7 +6,8 @@ use http::{
     header::{self, ToStrError},
     HeaderName, HeaderValue,
 };
-use reqwest::Request;
+use http_body_util::BodyExt as _;
+use reqwest::{Body, Request};
 
 use anyhow::Context as _;
 use aws_credential_types::{provider::ProvideCredentials, Credentials};
@@ -20,6 +21,9 @@ use aws_sigv4::{
 
 use crate::Opt;
 
+/// The service name of S3, which accepts unsigned payloads
+const S3_SERVICE: &str = "s3";
+
 #[derive(thiserror::Error, Debug)]
 /// SigningError will be returned from the builder when any issues arise
 /// building the signature
@@ -213,12 +217,12 @@ impl SigV4Builder {
             req.method().as_str(),
             req.url().to_string(),
             signed_headers.into_iter(),
-            SignableBody::Bytes(
-                req.body()
-                    .map(|m| m.as_bytes())
-                    .flatten()
-                    .unwrap_or_default(),
-            ),
+            // streamed bodies can't be hashed before they're sent
+            match req.body().map(|b| b.as_bytes()) {
+                Some(Some(bytes)) => SignableBody::Bytes(bytes),
+                Some(None) => SignableBody::UnsignedPayload,
+                None => SignableBody::Bytes(&[]),
+            },
         )?;
 
         // Sign the request
@@ -273,19 +277,40 @@ impl Sigv4Request for Request {
             .await
             .context("could not fetch credentials")?;
 
+        let service = service.map(|s| s.as_str()).unwrap_or("execute-api");
+
+        // S3 accepts an unsigned payload, so large bodies can still be streamed.
+        // Every other service needs the hash of the body, so it's read into memory.
+        let req = match service == S3_SERVICE {
+            true => self,
+            false => buffer_body(self).await?,
+        };
+
         let req = SigV4Builder::new()
             .date(SystemTime::now())
             .region(config.region().map(|r| r.to_string()).unwrap_or_default())
-            .service(
-                service
-                    .map(|s| s.as_str())
-                    .unwrap_or("execute-api")
-                    .to_string(),
-            )
+            .service(service.to_string())
             .credentials(credentials)
-            .sign(self)
+            .sign(req)
             .context("Could not sign request")?;
 
         Ok(req)
     }
 }
+
+/// buffer_body reads a streamed body into memory, so it can be hashed when signing
+/// or sent again
+pub(crate) async fn buffer_body(mut req: Request) -> Result<Request, anyhow::Error> {
+    if req.body().is_none_or(|body| body.as_bytes().is_some()) {
+        return Ok(req);
+    }
+
+    let body = req.body_mut().take().expect("streamed body");
+    let bytes = body
+        .collect()
+        .await
+        .context("could not read the body into memory")?
+        .to_bytes();
+    *req.body_mut() = Some(Body::from(bytes));
+    Ok(req)
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+use tera::Context;
+
+use crate::{write_private, Result};
+
+#[derive(Clone, Debug, Default)]
+/// State is a small key value store persisted to disk. Values are kept per
+/// environment, and are available to templates as `{{ state.<key> }}`.
+pub struct State {
+    /// path is the file the state is loaded from and saved to
+    path: PathBuf,
+    /// values holds the keys and values of each environment
+    values: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+impl State {
+    /// load reads the state from the file at path. A missing file is an empty
+    /// state, the file will be created when the state is saved.
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+
+        let values = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, values })
+    }
+
+    /// path returns the location of the state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// get returns the value of key in the environment
+    pub fn get(&self, env: &str, key: &str) -> Option<&Value> {
+        self.values.get(env).and_then(|values| values.get(key))
+    }
+
+    /// set stores the value of key in the environment
+    pub fn set<K: Into<String>>(&mut self, env: &str, key: K, value: Value) {
+        self.values
+            .entry(env.to_string())
+            .or_default()
+            .insert(key.into(), value);
+    }
+
+    /// unset removes key from the environment, returning the value it had
+    pub fn unset(&mut self, env: &str, key: &str) -> Option<Value> {
+        let values = self.values.get_mut(env)?;
+        let value = values.remove(key);
+        if values.is_empty() {
+            self.values.remove(env);
+        }
+        value
+    }
+
+    /// list returns the keys and values of the environment, sorted by key
+    pub fn list(&self, env: &str) -> impl Iterator<Item = (&String, &Value)> {
+        self.values.get(env).into_iter().flatten()
+    }
+
+    /// context returns a Tera Context with the values of the environment under `state`
+    pub fn context(&self, env: &str) -> Context {
+        let mut ctx = Context::new();
+        match self.values.get(env) {
+            Some(values) => ctx.insert("state", values),
+            None => ctx.insert("state", &BTreeMap::<String, Value>::new()),
+        }
+        ctx
+    }
+
+    /// save writes the state to disk, only readable by the user since it holds
+    /// tokens.
+    pub fn save(&self) -> Result<()> {
+        write_private(&self.path, serde_json::to_string_pretty(&self.values)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("kla-state-{}", std::process::id()));
+        let path = dir.join("state.json");
+
+        let mut state = State::load(&path).unwrap();
+        state.set("prod", "user_id", 42.into());
+        state.save().unwrap();
+
+        let state = State::load(&path).unwrap();
+        assert_eq!(state.get("prod", "user_id"), Some(&42.into()));
+        assert_eq!(state.get("dev", "user_id"), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
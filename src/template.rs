@@ -1,19 +1,24 @@
+use std::{path::Path, time::Instant};
+
 use anyhow::Context as _;
 use clap::ArgMatches;
 use http::Method;
+use log::warn;
 use reqwest::{Client, RequestBuilder, Response};
 use tera::{Context, Tera};
 
-use crate::config::{ConfigCommand, FilterWhen as _};
+use crate::config::{ConfigCommand, ConfigKV};
 use crate::{
-    Environment, Error, FetchMany as _, KlaRequestBuilder, Opt, OutputBuilder, Result,
-    Sigv4Request, URLBuilder, When, WithEnvironment,
+    secret_names, shell_quote, to_curl, write_private, ContextBuilder, DigestAuth, Environment,
+    Error, Expand as _, Har, History, KeyValue, KlaRequestBuilder, MultipartField, Opt,
+    OutputBuilder, RenderGroup, Result, Sigv4Request, State, URLBuilder, When, WithEnvironment,
 };
 
 #[derive(Clone, Debug, Default)]
 /// Template Builder is used to create a new template. Required fields are
 /// - config, set through `Self::config` or `Self::try_config`
 /// - client, set through `Self::client`
+///
 /// Everything else is optional.
 pub struct TemplateBuilder {
     /// config specifies the configCommand for this template.
@@ -23,6 +28,11 @@ pub struct TemplateBuilder {
     /// Optional context that serves as the base context we will render out of
     /// arguments.
     context: Option<Context>,
+    /// Optional state of the environments, available as `{{ state.* }}` and
+    /// written to by `[[extract]]` entries.
+    state: Option<State>,
+    /// Optional history the executed request is recorded into.
+    history: Option<History>,
 }
 
 impl TemplateBuilder {
@@ -30,6 +40,7 @@ impl TemplateBuilder {
     /// which returns an empty builder. You are still required to add
     /// - ConfigCommand
     /// - Client
+    ///
     /// before calling `build`
     pub fn new() -> Self {
         Self::default()
@@ -80,12 +91,27 @@ impl TemplateBuilder {
         Ok(self)
     }
 
+    /// state sets the state the template reads from and extracts values into.
+    /// Without it `{{ state.* }}` is empty and extracting into state fails.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// history sets the history the executed request is recorded into.
+    pub fn history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
     /// build the template
     pub fn build(self) -> Result<Template> {
         let Self {
             config,
             client,
             context,
+            state,
+            history,
         } = self;
 
         let config =
@@ -95,14 +121,20 @@ impl TemplateBuilder {
         let mut tmpl = Tera::default();
         tmpl.add_raw_templates(config.templates()?)
             .context("invalid template")?;
+        // mistakes in `[[extract]]` are reported before the request is sent
+        for extract in &config.extract {
+            extract.validate()?;
+        }
 
-        let context = context.unwrap_or_else(|| Context::default());
+        let context = context.unwrap_or_else(Context::default);
 
         Ok(Template {
             client,
             tmpl,
             context,
             config,
+            state,
+            history,
         })
     }
 }
@@ -115,21 +147,40 @@ pub struct Template {
     tmpl: Tera,
     context: Context,
     config: ConfigCommand,
+    state: Option<State>,
+    history: Option<History>,
 }
 
 impl Template {
-    pub async fn run(&self, env: &Environment, args: &ArgMatches) -> Result<()> {
-        let verbose = args
+    /// run sends the request of the template and writes out the response. flags are
+    /// the matches of kla's own flags and args the matches of the template's args.
+    pub async fn run(
+        &self,
+        env: &Environment,
+        flags: &ArgMatches,
+        args: &ArgMatches,
+    ) -> Result<()> {
+        let verbose = flags
             .get_one::<bool>("verbose")
-            .map(|v| *v)
+            .copied()
             .unwrap_or_default();
 
-        let mut context = self.context.clone();
-        context.extend(
-            self.config
-                .args_context(args)
-                .context("Invalid Arguments Supplied")?,
-        );
+        let context = self.context(env, args)?;
+
+        // the multipart fields of the flags and the template make up a single
+        // body, so they're sent together
+        let multipart = flags
+            .get_many::<String>("multipart")
+            .into_iter()
+            .flatten()
+            .map(KeyValue::try_from)
+            .chain(
+                self.render_group("multipart", &self.config.multipart, &context)?
+                    .into_iter()
+                    .map(KeyValue::try_from),
+            )
+            .collect::<Result<Vec<_>>>()?;
+        let fields = MultipartField::parse(Some(multipart.iter().cloned()))?;
 
         // TODO: Think through these, they should be applied in the following order
         // - Environment specific configuration
@@ -144,7 +195,7 @@ impl Template {
                 Method::try_from(
                     self.tmpl
                         .render("method", &context)
-                        .with_context(|| format!("could not render method template"))?
+                        .with_context(|| "could not render method template".to_string())?
                         .to_uppercase()
                         .as_str(),
                 )?,
@@ -152,125 +203,183 @@ impl Template {
                     &self
                         .tmpl
                         .render("uri", &context)
-                        .with_context(|| format!("could not render uri template"))?,
+                        .with_context(|| "could not render uri template".to_string())?,
                 )?,
             )
-            .with_environment(&env)
-            .await?
             .with_some(
                 self.tmpl
                     .render("body", &context)
-                    .map(|v| Some(v))
+                    .map(Some)
                     .or_else(|err| match err.kind {
                         tera::ErrorKind::TemplateNotFound(_) => Ok(None),
                         _ => Err(err),
                     })
-                    .with_context(|| format!("could not render body template"))?,
+                    .with_context(|| "could not render body template".to_string())?,
                 RequestBuilder::body,
             )
-            .opt_headers(args.get_many("header"))
+            .opt_headers(Some(
+                flags
+                    .get_many::<String>("header")
+                    .into_iter()
+                    .flatten()
+                    .map(KeyValue::header)
+                    .collect::<Result<Vec<_>>>()
+                    .context("invalid --header")?
+                    .into_iter(),
+            ))
             .with_context(|| {
                 format!(
                     "could not set header: {:?}",
-                    args.get_many::<String>("header")
+                    flags.get_many::<String>("header")
                 )
             })?
-            // TODO: Fix `when`. Now that we are defering to render templates until we
-            // actually call them we need to implement `when` here. Good call on RenderGroups
-            // previous paul, they are needed now.
-            // Implementation should add a filter which could be called with
-            // .filter(config.filterWhen)
             .opt_headers(Some(
-                self.tmpl
-                    .fetch_with_prefix("header.", &context)
-                    .filter_map(|v| match self.config.header.filter_when(&v) {
-                        Ok(true) => Some(Ok(v)),
-                        Ok(false) => None,
-                        Err(err) => Some(Err(err)),
-                    })
-                    .collect::<Result<Vec<_>>>()?
+                self.render_group("header", &self.config.header, &context)?
                     .into_iter(),
             ))
-            .with_context(|| format!("headers could not be loaded"))?
-            .opt_bearer_auth(args.get_one("bearer-token"))
-            .opt_basic_auth(args.get_one("basic-auth"))
-            .opt_query(args.get_many("query"))
+            .with_context(|| "headers could not be loaded".to_string())?
+            .opt_bearer_auth(flags.get_one("bearer-token"))
+            .context("could not read --bearer-token")?
+            .opt_basic_auth(flags.get_one("basic-auth"))
+            .context("could not read --basic-auth")?
+            .opt_query(flags.get_many("query"))
             .with_context(|| {
                 format!(
                     "could not set query param: {:?}",
-                    args.get_many::<String>("query")
+                    flags.get_many::<String>("query")
                 )
             })?
             .opt_query(Some(
-                self.tmpl
-                    .fetch_with_prefix("query.", &context)
-                    .filter_map(|v| match self.config.query.filter_when(&v) {
-                        Ok(true) => Some(Ok(v)),
-                        Ok(false) => None,
-                        Err(err) => Some(Err(err)),
-                    })
-                    .collect::<Result<Vec<_>>>()?
+                self.render_group("query", &self.config.query, &context)?
                     .into_iter(),
             ))
-            .with_context(|| format!("query params could not be loaded",))?
-            .opt_form(args.get_many("form"))
-            .with_context(|| format!("could not set form: {:?}", args.get_many::<String>("form")))?
+            .with_context(|| "query params could not be loaded".to_string())?
+            // the form of the arguments and the template make up a single body, so
+            // they're sent together
             .opt_form(Some(
-                self.tmpl
-                    .fetch_with_prefix("form.", &context)
-                    .filter_map(|v| match self.config.form.filter_when(&v) {
-                        Ok(true) => Some(Ok(v)),
-                        Ok(false) => None,
-                        Err(err) => Some(Err(err)),
-                    })
-                    .collect::<Result<Vec<_>>>()?
+                flags
+                    .get_many::<String>("form")
+                    .into_iter()
+                    .flatten()
+                    .map(KeyValue::try_from)
+                    .chain(
+                        self.render_group("form", &self.config.form, &context)?
+                            .into_iter()
+                            .map(KeyValue::try_from),
+                    )
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| {
+                        format!("could not set form: {:?}", flags.get_many::<String>("form"))
+                    })?
                     .into_iter(),
             ))
-            .with_context(|| format!("form params could not be loaded",))?
-            .opt_timeout(args.get_one("timeout"))
+            .with_context(|| "form params could not be loaded".to_string())?
+            .opt_multipart(Some(multipart.into_iter()))
+            .with_context(|| "multipart fields could not be loaded".to_string())?
+            .opt_timeout(flags.get_one("timeout"))
             .with_context(|| {
                 format!(
                     "{:?} is not a valid format",
-                    args.get_one::<String>("timeout")
+                    flags.get_one::<String>("timeout")
                 )
             })?
-            .opt_version(args.get_one("http-version"))
+            .opt_version(flags.get_one("http-version"))
             .with_context(|| {
                 format!(
                     "{:?} is not a valid http-version",
-                    args.get_one::<String>("http-version")
+                    flags.get_one::<String>("http-version")
                 )
             })?
-            .build()
-            .context("could not build http request")?
+            // the environment only adds what the flags and template didn't set
             .with_environment(env)
-            .await?;
+            .await
+            .context("could not build http request")?
+            .build()
+            .context("could not build http request")?;
+        let dry = flags.get_flag("dry");
+        let request = env.prepare((!dry).then_some(&self.client), request).await?;
 
-        let request = if args.get_one("sigv4").map(|v| *v).unwrap_or(false) {
+        let request = if flags.get_one("sigv4").copied().unwrap_or(false) {
             request
                 .sign_request(
-                    args.get_one::<String>("sigv4-aws-profile"),
-                    args.get_one::<String>("sigv4-aws-service"),
+                    flags.get_one::<String>("sigv4-aws-profile"),
+                    flags.get_one::<String>("sigv4-service"),
                 )
                 .await?
         } else {
             request
         };
 
-        let output =
-            OutputBuilder::new().when(verbose, |builder| builder.request_prelude(&request));
+        if flags.get_flag("as-curl") {
+            println!("{}", to_curl(&request, None, &fields));
+            return Ok(());
+        }
 
-        let response = match args.get_one("dry").map(|b| *b).unwrap_or_default() {
+        let output = OutputBuilder::new()
+            .show_secrets(flags.get_flag("show-secrets"))
+            .secret_headers(env.secret_headers())
+            .secret_headers(secret_names(&self.config.header))
+            .secret_query(env.secret_query())
+            .secret_query(secret_names(&self.config.query))
+            .when(verbose, |builder| builder.request_prelude(&request));
+
+        let response = match dry {
             true => Response::from(http::Response::<Vec<u8>>::default()),
-            false => self
-                .client
-                .execute(request)
-                .await
-                .with_context(|| format!("request failed!"))?,
+            false => {
+                let policy = self
+                    .config
+                    .retry
+                    .clone()
+                    .with_args(args)
+                    .context("invalid retry settings")?;
+                let har = Har::opt_new(flags.get_one("har"))
+                    .map(|har| har.secrets(output.secrets().clone()));
+                let exchange = har.as_ref().map(|har| har.start(&request, None, &fields));
+                let entry = self.history.as_ref().map(|history| {
+                    history
+                        .clone()
+                        .passwords(self.config.passwords(args))
+                        .entry(&request, output.secrets())
+                        .environment(env.name())
+                        .template(&self.config.name)
+                });
+                let start = Instant::now();
+                let response =
+                    match DigestAuth::opt_new(flags.get_one("digest-auth").or(env.digest_auth()))
+                        .context("invalid digest auth")?
+                    {
+                        Some(digest) => digest.execute(&policy, &self.client, request).await,
+                        None => policy.execute(&self.client, request).await,
+                    }
+                    .with_context(|| "request failed!".to_string())?;
+                if let (Some(history), Some(entry)) = (self.history.as_ref(), entry) {
+                    if let Err(err) =
+                        history.record(entry.finish(response.status(), start.elapsed()))
+                    {
+                        warn!("could not record the request in the history: {}", err);
+                    }
+                }
+                match exchange {
+                    Some(exchange) => exchange
+                        .finish(response)
+                        .await
+                        .context("could not record the request in the HAR file")?,
+                    None => response,
+                }
+            }
         };
 
         let succeed = response.status().is_success();
 
+        // a dry run has no response to extract from
+        let response = match succeed && !dry && !self.config.extract.is_empty() {
+            true => self
+                .extract(env, response)
+                .await
+                .context("could not extract values from the response")?,
+            false => response,
+        };
+
         // TODO: This is shitty, and should be derived some other way. There should be
         // an output type that is generated by the template, and the caller can decide
         // how to use that thing. Likely an enum that specifies if it's raw data or a
@@ -281,25 +390,305 @@ impl Template {
                 false => self.config.template_failure.as_ref(),
             }
         )
-        .with_context(|| format!("Your request was sent but the output or failure-template within could not be parsed, run with -v to see if your request was successful"))?
+        .with_context(|| "Your request was sent but the output or failure-template within could not be parsed, run with -v to see if your request was successful".to_string())?
         .opt_template(match succeed {
-            true => args.get_one("template"),
-            false => args.get_one("failure-template"),
+            true => flags.get_one("template"),
+            false => flags.get_one("failure-template"),
         })
-        .with_context(|| format!("Your request was sent but the --template or --failure-template could not be parsed, run with -v to see if your request was successful"))?
-        .opt_output(match succeed {
-                true => self.config.output.as_ref(),
-                false => self.config.output_failure.as_ref().or(self.config.output.as_ref())
-            })
-            .await.with_context(|| format!("could not set --output"))?
+        .with_context(|| "Your request was sent but the --template or --failure-template could not be parsed, run with -v to see if your request was successful".to_string())?
+        // only open a single output, flags win over the template
         .opt_output(match succeed {
-            true => args.get_one("output"),
-            false => args.get_one("output-failure").or(args.get_one("output")),
+            true => flags.get_one("output").or(self.config.output.as_ref()),
+            false => flags
+                .get_one("output-failure")
+                .or(self.config.output_failure.as_ref())
+                .or(flags.get_one("output"))
+                .or(self.config.output.as_ref()),
         })
-        .await.with_context(|| format!("could not set --output"))?
+        .await.with_context(|| "could not set output".to_string())?
         .when(verbose, |builder| builder.response_prelude(&response))
         .render(response)
-        .await.with_context(|| format!("could not write output to specified location!"))?;
+        .await.with_context(|| "could not write output to specified location!".to_string())?;
         Ok(())
     }
+
+    /// context returns the context the template is rendered with. The `vars` of the
+    /// environment come first, then the state, and the arguments override both.
+    fn context(&self, env: &Environment, args: &ArgMatches) -> Result<Context> {
+        let mut context = self.context.clone();
+        context.extend(env.context());
+        if let (Some(state), Some(name)) = (self.state.as_ref(), env.name()) {
+            context.extend(state.context(name));
+        }
+        context.extend(
+            self.config
+                .args_context(args)
+                .context("Invalid Arguments Supplied")?,
+        );
+        Ok(context)
+    }
+
+    /// render_group returns the entries of a section like `[[query]]` in the order
+    /// they're defined, leaving out the ones whose `when` renders empty. Names may
+    /// repeat, every entry is kept.
+    fn render_group<'a>(
+        &'a self,
+        section: &str,
+        kvs: &[ConfigKV],
+        context: &'a Context,
+    ) -> Result<Vec<RenderGroup<'a>>> {
+        let mut groups = vec![];
+        for (i, kv) in kvs.iter().enumerate() {
+            if kv.included(context)? {
+                groups.push(RenderGroup {
+                    name: kv.name.clone(),
+                    tmpl_name: format!("{}.{}", section, i),
+                    tmpl: &self.tmpl,
+                    context,
+                });
+            }
+        }
+        Ok(groups)
+    }
+
+    /// extract pulls the `[[extract]]` values out of the response and sends them to
+    /// their destinations. The body can only be read once, so the response is
+    /// buffered and a copy is returned for the output.
+    async fn extract(&self, env: &Environment, response: Response) -> Result<Response> {
+        let (response, copy) = duplicate(response).await?;
+        let ctx = ContextBuilder::new().insert_response(copy).await?.build();
+
+        let mut stored = vec![];
+        for extract in &self.config.extract {
+            let value = extract.extract(&ctx)?;
+            let text = match &value {
+                serde_json::Value::String(v) => v.clone(),
+                v => v.to_string(),
+            };
+
+            if let Some(file) = extract.file.as_ref() {
+                write_private(Path::new(&file.shell_expansion()), &text)
+                    .with_context(|| format!("could not write {} to {}", extract.name, file))?;
+            }
+
+            if extract.export {
+                println!("export {}={}", extract.name, shell_quote(&text));
+            }
+
+            if extract.state {
+                stored.push((extract.name.as_str(), value));
+            }
+        }
+
+        if !stored.is_empty() {
+            let (state, name) = match (self.state.as_ref(), env.name()) {
+                (Some(state), Some(name)) => (state, name),
+                _ => {
+                    return Err(Error::from(format!(
+                        "{} can't be stored in state without an environment",
+                        stored[0].0
+                    )))
+                }
+            };
+
+            // the state was loaded before the request went out, since then the
+            // environment may have stored a fresh oauth2 token in it. Load it again
+            // so saving doesn't throw the token away.
+            let mut state = State::load(state.path()).context("could not load state")?;
+            for (key, value) in stored {
+                state.set(name, key, value);
+            }
+            state.save().context("could not save state")?;
+        }
+
+        Ok(response)
+    }
+}
+
+/// duplicate reads the body of the response and returns two identical responses
+pub(crate) async fn duplicate(response: Response) -> Result<(Response, Response)> {
+    let status = response.status();
+    let version = response.version();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+
+    let build = || -> Result<Response> {
+        let mut builder = http::Response::builder().status(status).version(version);
+        if let Some(h) = builder.headers_mut() {
+            h.extend(headers.clone());
+        }
+        Ok(Response::from(
+            builder
+                .body(body.clone())
+                .map_err(|err| Error::InvalidArguments(Box::new(err)))?,
+        ))
+    };
+
+    Ok((build()?, build()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::OAUTH2_STATE_KEY;
+
+    #[tokio::test]
+    async fn extract_keeps_the_oauth2_token() {
+        // a token endpoint that hands out a single token
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_url = format!("http://{}/token", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = r#"{"access_token":"fresh","expires_in":3600,"refresh_token":"r2"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let path = std::env::temp_dir().join(format!("kla-template-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conf = Config::builder()
+            .set_default("default.state", path.to_str().unwrap())
+            .unwrap()
+            .add_source(File::from_str(
+                &format!(
+                    "[environment.prod]\nurl = \"http://prod\"\n[environment.prod.oauth2]\ntoken_url = \"{}\"\nclient_id = \"kla\"\n",
+                    token_url
+                ),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let env = Environment::new(Some(&"prod".into()), &conf).unwrap();
+        let tmpl = Config::builder()
+            .add_source(File::from_str(
+                "[[extract]]\nname = \"user_id\"\npointer = \"/id\"\nstate = true\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        // the state is loaded before the request goes out, as `kla run` does
+        let client = Client::new();
+        let template = TemplateBuilder::new()
+            .client(client.clone())
+            .config(ConfigCommand::with_name("test", tmpl).unwrap())
+            .state(State::load(&path).unwrap())
+            .build()
+            .unwrap();
+
+        // preparing the request fetches the token and stores it in the state file
+        env.prepare(Some(&client), client.get("http://prod/").build().unwrap())
+            .await
+            .unwrap();
+
+        let response = Response::from(http::Response::new(r#"{"id": 42}"#));
+        template.extract(&env, response).await.unwrap();
+
+        let state = State::load(&path).unwrap();
+        assert_eq!(state.get("prod", "user_id"), Some(&42.into()));
+        let token = state.get("prod", OAUTH2_STATE_KEY).unwrap();
+        assert_eq!(token["access_token"], "fresh");
+        assert_eq!(token["refresh_token"], "r2");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn extracts_are_validated_when_built() {
+        let tmpl = Config::builder()
+            .add_source(File::from_str(
+                "[[extract]]\nname = \"token\"\nvalue = \"{{ resp_body }}\"\npointer = \"/token\"\nstate = true\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let err = TemplateBuilder::new()
+            .client(Client::new())
+            .config(ConfigCommand::with_name("login", tmpl).unwrap())
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("extract `token`"), "{}", err);
+    }
+
+    #[test]
+    fn repeated_form_entries_are_kept() {
+        let tmpl = Config::builder()
+            .add_source(File::from_str(
+                "[[form]]\nname = \"tag\"\nvalue = \"a\"\n[[form]]\nname = \"tag\"\nvalue = \"{{ b }}\"\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let template = TemplateBuilder::new()
+            .client(Client::new())
+            .config(ConfigCommand::with_name("test", tmpl).unwrap())
+            .build()
+            .unwrap();
+        let mut context = Context::new();
+        context.insert("b", "b");
+
+        let group = template
+            .render_group("form", &template.config.form, &context)
+            .unwrap();
+        let request = Client::new()
+            .post("http://localhost/")
+            .opt_form(Some(group.into_iter()))
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.body().and_then(|body| body.as_bytes()),
+            Some("tag=a&tag=b".as_bytes())
+        );
+    }
+
+    #[test]
+    fn arguments_override_vars() {
+        let conf = Config::builder()
+            .add_source(File::from_str(
+                "[environment.prod]\nurl = \"http://prod\"\n[environment.prod.vars]\nindex = \"logs\"\ntenant = \"acme\"\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let env = Environment::new(Some(&"prod".into()), &conf).unwrap();
+        let tmpl = Config::builder()
+            .add_source(File::from_str(
+                "uri = \"/{{ tenant }}/{{ index }}/_search\"\n[[arg]]\nname = \"index\"\nlong = \"index\"\n",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let config = ConfigCommand::with_name("search", tmpl).unwrap();
+        let command = clap::Command::try_from(config.clone()).unwrap();
+        let template = TemplateBuilder::new()
+            .client(Client::new())
+            .config(config)
+            .build()
+            .unwrap();
+
+        let render = |args: &[&str]| {
+            let args = command.clone().try_get_matches_from(args).unwrap();
+            let context = template.context(&env, &args).unwrap();
+            template.tmpl.render("uri", &context).unwrap()
+        };
+        assert_eq!(render(&["search"]), "/acme/logs/_search");
+        assert_eq!(
+            render(&["search", "--index", "metrics"]),
+            "/acme/metrics/_search"
+        );
+    }
 }
@@ -8,6 +8,12 @@ pub struct ContextBuilder {
     data: Context,
 }
 
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ContextBuilder {
     pub fn new() -> Self {
         ContextBuilder {
@@ -26,7 +32,7 @@ impl ContextBuilder {
         let headers = response.headers();
         for (name, value) in headers.iter() {
             self.data
-                .insert(&format!("resp_headers_{}", name), &value.to_str()?);
+                .insert(format!("resp_headers_{}", name), &value.to_str()?);
         }
 
         let version = response.version();
@@ -40,12 +46,10 @@ impl ContextBuilder {
         }
 
         let content = response.text().await?;
-        match serde_json::from_str::<serde_json::Value>(&content) {
-            Ok(v) => match Context::from_value(v) {
-                Ok(v) => self.data.extend(v),
-                _ => (),
-            },
-            _ => (),
+        if let Ok(v) = serde_json::from_str::<serde_json::Value>(&content) {
+            if let Ok(v) = Context::from_value(v) {
+                self.data.extend(v)
+            }
         }
 
         self.data.insert("resp_body", &content);
@@ -15,8 +15,7 @@ pub trait FetchMany {
 impl FetchMany for Tera {
     fn has<'a>(&self, name: &str) -> bool {
         self.get_template_names()
-            .filter(move |tmpl| *tmpl == name)
-            .next()
+            .find(move |tmpl| *tmpl == name)
             .is_some()
     }
 
@@ -32,7 +31,7 @@ impl FetchMany for Tera {
                 name: f.strip_prefix(prefix).unwrap_or(f).into(),
                 tmpl_name: f.into(),
                 tmpl: self,
-                context: context,
+                context,
             })
     }
 }
@@ -54,7 +53,7 @@ pub struct RenderGroup<'a> {
 impl<'a> RenderGroup<'a> {
     /// render will output the value of the evaluated template
     pub fn render(&self) -> std::result::Result<String, tera::Error> {
-        self.tmpl.render(self.tmpl_name.as_str(), &self.context)
+        self.tmpl.render(self.tmpl_name.as_str(), self.context)
     }
 
     /// return the name of the template which will be rendered
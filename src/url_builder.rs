@@ -24,10 +24,10 @@ impl PrefixURLBuilder {
 
         // normalize the prefix
         if !prefix.ends_with("/") {
-            prefix.push_str("/");
+            prefix.push('/');
         };
 
-        Self { prefix: prefix }
+        Self { prefix }
     }
 }
 
@@ -85,7 +85,7 @@ impl URLBuilder for AssumingURLBuilder {
     /// the path is literal, and return that. If not we use a prefix builder
     fn build(&self, path: &str) -> Result<String> {
         if path.starts_with("http://") || path.starts_with("https://") {
-            return LiteralURLBuilder::default().build(path);
+            LiteralURLBuilder::default().build(path)
         } else {
             self.prefixed.build(path)
         }